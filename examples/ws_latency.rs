@@ -0,0 +1,174 @@
+//! Measures request / response round trip latency against
+//! running wsss instances, and how much CPU they use while
+//! clients sit idle
+//!
+//! Start the servers, then run
+//!
+//! `cargo run --release --example ws_latency -- [OPTIONS] [URL[=PID]]...`
+//!
+//! URL defaults to `ws://127.0.0.1:10081`. Requests alternate
+//! between the servers, so run two builds side by side on
+//! different ports to compare them. Options are
+//!
+//! * `--count COUNT` requests per server, 1000 by default
+//! * `--token TOKEN` only needed if api keys are configured
+//! * `--idle SECS` hold IDLE_CLIENTS idle connections to each
+//!   server for SECS seconds, 10 by default, and report the CPU
+//!   time each server with a PID used meanwhile. Linux only
+
+use std::env;
+use std::fs;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use websocket::sync::Client;
+use websocket::{ClientBuilder, Message, OwnedMessage};
+
+use lib::binary_frame::JSON_PROTOCOL;
+
+const DEFAULT_URL: &str = "ws://127.0.0.1:10081";
+const DEFAULT_COUNT: usize = 1000;
+const DEFAULT_IDLE_SECS: u64 = 10;
+/// Idle connections held open to each server
+const IDLE_CLIENTS: usize = 20;
+
+/// A server under test
+struct Target {
+  url: String,
+  pid: Option<u32>,
+}
+
+fn main() {
+  let mut targets = Vec::new();
+  let mut count = DEFAULT_COUNT;
+  let mut token = None;
+  let mut idle_secs = DEFAULT_IDLE_SECS;
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    let mut value = |name: &str| args.next().unwrap_or_else(|| panic!("{} needs a value", name));
+    match arg.as_str() {
+      "--count" => count = value("--count").parse().expect("COUNT must be a number"),
+      "--token" => token = Some(value("--token")),
+      "--idle" => idle_secs = value("--idle").parse().expect("SECS must be a number"),
+      _ => targets.push(parse_target(&arg)),
+    }
+  }
+  if targets.is_empty() {
+    targets.push(parse_target(DEFAULT_URL));
+  }
+  assert!(count > 0, "COUNT must be at least 1");
+  let open = |target: &Target| connect(&target.url, token.as_ref());
+
+  let mut clients: Vec<_> = targets.iter().map(open).collect();
+  let mut times = vec![Vec::with_capacity(count); targets.len()];
+  for i in 0..count {
+    for (client, times) in clients.iter_mut().zip(times.iter_mut()) {
+      // Releasing write locks we don't hold is cheap and
+      // always answered, so it measures the round trip
+      let id = i.to_string();
+      let request = format!(r#"{{"id":"{}","ReleaseWriteLock":{{}}}}"#, id);
+      let start = Instant::now();
+      client
+        .send_message(&Message::text(request))
+        .expect("Could not send request");
+      wait_for_reply(client, &id);
+      times.push(start.elapsed());
+    }
+  }
+
+  // Every server gets the same idle clients at the same time
+  let _idle: Vec<_> = targets
+    .iter()
+    .flat_map(|target| (0..IDLE_CLIENTS).map(move |_| target))
+    .map(open)
+    .collect();
+  // Let the servers settle after the connections
+  thread::sleep(Duration::from_secs(1));
+  let cpu_before: Vec<_> = targets.iter().map(|t| t.pid.map(cpu_time)).collect();
+  let idle_start = Instant::now();
+  if targets.iter().any(|target| target.pid.is_some()) {
+    thread::sleep(Duration::from_secs(idle_secs));
+  }
+  let idle_time = idle_start.elapsed();
+
+  println!("requests per server: {}", count);
+  for (i, (target, times)) in targets.iter().zip(times.iter_mut()).enumerate() {
+    times.sort();
+    println!();
+    println!("{}", target.url);
+    println!("  min:      {}", millis(times[0]));
+    println!("  median:   {}", millis(times[count / 2]));
+    println!("  p99:      {}", millis(times[count * 99 / 100]));
+    println!("  max:      {}", millis(times[count - 1]));
+    if let (Some(pid), Some(before)) = (target.pid, cpu_before[i]) {
+      let used = cpu_time(pid).checked_sub(before).unwrap_or_default();
+      println!(
+        "  idle cpu: {} over {} with {} idle clients ({:.2}%)",
+        millis(used),
+        millis(idle_time),
+        IDLE_CLIENTS,
+        secs(used) / secs(idle_time) * 100.0
+      );
+    }
+  }
+}
+
+/// Parse a `URL` or `URL=PID` argument
+fn parse_target(arg: &str) -> Target {
+  let mut parts = arg.splitn(2, '=');
+  Target {
+    url: parts.next().unwrap_or_default().to_string(),
+    pid: parts.next().map(|pid| pid.parse().expect("PID must be a number")),
+  }
+}
+
+/// Connect to a server with the json protocol
+fn connect(url: &str, token: Option<&String>) -> Client<TcpStream> {
+  let url = match token {
+    Some(token) => format!("{}/?token={}", url.trim_end_matches('/'), token),
+    None => url.to_string(),
+  };
+  ClientBuilder::new(&url)
+    .expect("Invalid url")
+    .add_protocol(JSON_PROTOCOL)
+    .connect_insecure()
+    .unwrap_or_else(|e| panic!("Could not connect to {}, cause '{}'", url, e))
+}
+
+/// Read messages till the reply with the given id arrives,
+/// skipping notices such as port changes
+fn wait_for_reply(client: &mut Client<TcpStream>, id: &str) {
+  loop {
+    let text = match client.recv_message().expect("Could not read reply") {
+      OwnedMessage::Text(text) => text,
+      _ => continue,
+    };
+    let reply: serde_json::Value = serde_json::from_str(&text).expect("Reply was not json");
+    if reply.get("id").and_then(|v| v.as_str()) == Some(id) {
+      return;
+    }
+  }
+}
+
+/// CPU time used so far by the live threads of a process
+fn cpu_time(pid: u32) -> Duration {
+  let tasks = fs::read_dir(format!("/proc/{}/task", pid))
+    .unwrap_or_else(|e| panic!("Could not read threads of {}, cause '{}'", pid, e));
+  let nanos: u64 = tasks
+    .filter_map(|task| task.ok())
+    .filter_map(|task| fs::read_to_string(task.path().join("schedstat")).ok())
+    .filter_map(|stat| stat.split_whitespace().next().and_then(|ns| ns.parse::<u64>().ok()))
+    .sum();
+  Duration::from_nanos(nanos)
+}
+
+/// A duration in seconds
+fn secs(d: Duration) -> f64 {
+  d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+/// Format a duration as milliseconds
+fn millis(d: Duration) -> String {
+  format!("{:.3} ms", secs(d) * 1000.0)
+}
@@ -18,33 +18,49 @@
 #[macro_use]
 extern crate log;
 
+use std::io;
 use std::io::Write;
-use std::net::TcpStream;
-use std::sync::mpsc::{channel, Sender};
+use std::net::{SocketAddr, TcpListener};
+use std::str;
+use std::sync::mpsc::{channel, Sender, TryRecvError};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use hyper::net::Fresh;
 use hyper::server::request::Request;
 use hyper::server::response::Response;
+use hyper::uri::RequestUri;
 use hyper::Server as HttpServer;
+use hyper_native_tls::NativeTlsServer;
 use rand::{thread_rng, Rng};
-use websocket::client::Writer;
-use websocket::message::Type;
-use websocket::result::WebSocketError;
-use websocket::server::upgrade::WsUpgrade;
-use websocket::{Message, Server};
-
+use websocket::server::InvalidConnection;
+use websocket::stream::sync::{AsTcpStream, Stream};
+use websocket::sync::server::upgrade::Upgrade;
+use websocket::{Message, OwnedMessage};
+
+use lib::acl::AccessControl;
+use lib::auth::*;
+use lib::binary_frame::*;
 use lib::cfg::*;
-use lib::dynamic_sleep::DynamicSleep;
 use lib::errors as e;
+use lib::listener::*;
 use lib::manager::Manager;
 use lib::messages::*;
+use lib::origin::OriginPolicy;
+use lib::sub_queue::*;
+use lib::tls::load_tls_acceptor;
+use lib::ws_connection::*;
 
 /// Max number of failures we allow when trying to send
 /// data to client before exiting
 /// TODO: Make configurable
 pub const MAX_SEND_ERROR_COUNT: u32 = 5;
 
+/// Path websockets are served on when sharing
+/// the HTTP port, see WsssConfig::single_port
+pub const WS_PATH: &str = "/ws";
+
 /// Launches wsss
 pub fn main() {
   // Init logger
@@ -53,198 +69,433 @@ pub fn main() {
   // Grab config
   let cfg = WsssConfig::load();
 
-  // html file for landing page
-  let websocket_html = include_str!("websockets.html").replace(
+  // html file for landing page, in single port mode
+  // it finds the websocket at WS_PATH on its own port
+  let page_ws_port = match cfg.single_port {
+    true => "null".to_string(),
+    false => cfg.ws_port.to_string(),
+  };
+  let websocket_html = Arc::new(include_str!("websockets.html").replace(
     "__WS_PORT__ = 8081",
-    &format!("__WS_PORT__ = {}", cfg.ws_port),
+    &format!("__WS_PORT__ = {}", page_ws_port),
+  ));
+
+  match cfg.single_port {
+    true => info!("Using port {} with websockets at {}", cfg.http_port, WS_PATH),
+    false => info!("Using ports {} {}", cfg.http_port, cfg.ws_port),
+  }
+
+  // Set up channels and Manager
+  let (manager_tx, manager_rx) = channel::<ManagerEvent>();
+  Manager::spawn(
+    manager_rx,
+    manager_tx.clone(),
+    AccessControl::new(cfg.acl.clone()),
   );
 
-  // The HTTP server handler
-  let http_handler = move |_: Request, response: Response<Fresh>| {
-    let mut response = response.start().expect(&"Could not start response");
-    // Send a client webpage
-    response
-      .write_all(websocket_html.as_bytes())
-      .expect(&"Could not get template as bytes");
-    response.end().expect(&"Send response failed");
+  // Clients must present one of these keys, if any are configured
+  let authenticator = Arc::new(Authenticator::new(cfg.api_keys.clone()));
+  if authenticator.is_required() {
+    info!("Authentication required, {} api keys configured", cfg.api_keys.len());
+  }
+
+  // Web pages allowed to connect, besides our own
+  let origin_policy = Arc::new(OriginPolicy::new(
+    cfg.allowed_origins.clone(),
+    &cfg.bind_address,
+    cfg.http_port,
+    cfg.use_tls(),
+    cfg.allow_no_origin,
+  ));
+
+  // Clients may ask for a shorter queue in their url
+  let queue_options = QueueOptions {
+    length: cfg.queue_length,
+    policy: cfg.overflow,
   };
 
-  info!("Using ports {} {}", cfg.http_port, cfg.ws_port);
+  if let (&Some(ref cert), &Some(ref key)) = (&cfg.tls_cert, &cfg.tls_key) {
+    info!("Using TLS certificate {} and key {}", cert, key);
+  }
 
-  // Set up channels and Manager
-  let (sub_tx, sub_rx) = channel::<SubscriptionRequest>();
-  let (sreq_tx, sreq_rx) = channel::<(String, SerialRequest)>();
-  Manager::spawn(sreq_rx, sub_rx);
+  // Listen on every bind address, with an accept loop per address
+  let mut ws_listeners = Vec::new();
+  for ip_addr in cfg.bind_address.iter() {
+    let http_addr = SocketAddr::new(*ip_addr, cfg.http_port as u16);
+    // In single port mode the websocket listener serves the page too
+    let (ws_addr, page) = match cfg.single_port {
+      true => (http_addr, Some(websocket_html.clone())),
+      false => (SocketAddr::new(*ip_addr, cfg.ws_port as u16), None),
+    };
+    let ws_server = TcpListener::bind(ws_addr)
+      .unwrap_or_else(|_| panic!("Failed bind on websocket address {}", ws_addr));
+    let authenticator = authenticator.clone();
+    let origin_policy = origin_policy.clone();
+    let manager_tx = manager_tx.clone();
+
+    let ws_listener = match (&cfg.tls_cert, &cfg.tls_key) {
+      (&Some(ref cert), &Some(ref key)) => {
+        let load_acceptor = || {
+          load_tls_acceptor(cert, key).unwrap_or_else(|e| panic!("Failed to load TLS config: {}", e))
+        };
 
-  // Start listening for http connections
-  let http_server = HttpServer::http(format!("{}:{}", cfg.bind_address, cfg.http_port)).expect(
-    &format!("Failed to create http server on port {}", cfg.http_port),
-  );
+        // Start listening for https connections
+        if !cfg.single_port {
+          let http_handler = page_handler(websocket_html.clone());
+          let http_server = HttpServer::https(http_addr, NativeTlsServer::from(load_acceptor()))
+            .unwrap_or_else(|_| panic!("Failed to create https server on {}", http_addr));
+          thread::spawn(move || {
+            http_server.handle(http_handler).expect("Failed to listen");
+          });
+        }
 
-  thread::spawn(move || {
-    http_server.handle(http_handler).expect(&"Failed to listen");
-  });
+        // Start listening for secure WebSocket connections,
+        // each client does its TLS handshake on its own thread
+        let acceptor = load_acceptor();
+        info!("Listening on https://{} and wss://{}", http_addr, ws_addr);
+        thread::spawn(move || {
+          accept_connections(
+            ws_server,
+            move |stream| {
+              acceptor
+                .accept(stream)
+                .map_err(|e| io::Error::other(e.to_string()))
+            },
+            connection_handler(page, authenticator, origin_policy, queue_options, manager_tx),
+          )
+        })
+      }
+      _ => {
+        // Start listening for http connections
+        if !cfg.single_port {
+          let http_handler = page_handler(websocket_html.clone());
+          let http_server = HttpServer::http(http_addr)
+            .unwrap_or_else(|_| panic!("Failed to create http server on {}", http_addr));
+          thread::spawn(move || {
+            http_server.handle(http_handler).expect("Failed to listen");
+          });
+        }
 
-  // Start listening for WebSocket connections
-  let ws_server = Server::bind(format!("{}:{}", cfg.bind_address, cfg.ws_port))
-    .expect(&format!("Failed bind on websocket port {}", cfg.ws_port));
+        // Start listening for WebSocket connections
+        info!("Listening on http://{} and ws://{}", http_addr, ws_addr);
+        thread::spawn(move || {
+          accept_connections(
+            ws_server,
+            Ok,
+            connection_handler(page, authenticator, origin_policy, queue_options, manager_tx),
+          )
+        })
+      }
+    };
+    ws_listeners.push(ws_listener);
+  }
+
+  // Run until every listener stops
+  for ws_listener in ws_listeners {
+    ws_listener
+      .join()
+      .unwrap_or_else(|_| warn!("Websocket listener exited with a panic"));
+  }
+}
+
+/// The HTTP server handler, sends the client webpage
+fn page_handler(page: Arc<String>) -> impl Fn(Request, Response<Fresh>) + Send + Sync + 'static {
+  move |_: Request, response: Response<Fresh>| {
+    let mut response = response.start().expect("Could not start response");
+    // Send a client webpage
+    response
+      .write_all(page.as_bytes())
+      .expect("Could not get template as bytes");
+    response.end().expect("Send response failed");
+  }
+}
+
+/// Handles connections accepted on a websocket
+/// listener, running on each connection's thread
+///
+/// If a page is given, the listener is shared with the
+/// HTTP server. Websocket upgrades are only accepted on WS_PATH,
+/// and plain HTTP requests are answered with the page
+fn connection_handler<S>(
+  page: Option<Arc<String>>,
+  authenticator: Arc<Authenticator>,
+  origin_policy: Arc<OriginPolicy>,
+  queue_options: QueueOptions,
+  manager_tx: Sender<ManagerEvent>,
+) -> impl Fn(Connection<S>) + Send + Sync + 'static
+where
+  S: Stream + AsTcpStream + BufferedStream + Send + 'static,
+{
+  move |connection: Connection<S>| {
+    let connection = match (connection, &page) {
+      (Ok(connection), &None) => connection,
+      (Ok(connection), &Some(_)) => {
+        if request_path(&connection.request.subject.1) != WS_PATH {
+          if connection.reject().is_err() {
+            debug!("Connection rejection failed.");
+          }
+          return;
+        }
+        connection
+      }
+      (
+        Err(InvalidConnection {
+          stream: Some(stream),
+          parsed: Some(request),
+          ..
+        }),
+        &Some(ref page),
+      ) => {
+        // A plain HTTP request
+        serve_page(stream, &request, page);
+        return;
+      }
+      (Err(_), _) => {
+        debug!("Dropping bad connection");
+        return;
+      }
+    };
 
-  // Continuously iterate over connections,
-  // spawning handlers
-  for connection in ws_server.filter_map(Result::ok) {
     // Set up subscription id
     // let ts = SystemTime::now() - UNIX_EPOCH
     let prefix: String = thread_rng().gen_ascii_chars().take(8).collect();
     let sub_id = format!("thread-{}-{}", prefix, rand::random::<u16>());
     debug!("{}: spawned.", sub_id);
 
-    // Spawn a new thread for each connection.
-    let sub_tx_clone = sub_tx.clone();
-    let sreq_tx_clone = sreq_tx.clone();
-    spawn_ws_handler(sub_id, sub_tx_clone, sreq_tx_clone, connection);
+    ws_handler(
+      sub_id,
+      &authenticator,
+      &origin_policy,
+      queue_options,
+      &manager_tx,
+      connection,
+    );
   }
 }
 
-/// Spawn a websocket handler into its own thread
-fn spawn_ws_handler(
-  sub_id: String,
-  sub_tx_clone: Sender<SubscriptionRequest>,
-  sreq_tx_clone: Sender<(String, SerialRequest)>,
-  connection: WsUpgrade<TcpStream>,
-) {
-  thread::spawn(move || ws_handler(sub_id, &sub_tx_clone, &sreq_tx_clone, connection));
-}
-
 /// Websocket handler
-fn ws_handler(
+fn ws_handler<S>(
   sub_id: String,
-  sub_tx: &Sender<SubscriptionRequest>,
-  sreq_tx: &Sender<(String, SerialRequest)>,
-  connection: WsUpgrade<TcpStream>,
-) {
-  if !connection
-    .protocols()
-    .contains(&"websocket-serial-json".to_string())
-  {
-    connection.reject().expect(&"Connection rejection failed.");
+  authenticator: &Authenticator,
+  origin_policy: &OriginPolicy,
+  queue_options: QueueOptions,
+  manager_tx: &Sender<ManagerEvent>,
+  connection: Upgrade<S>,
+) where
+  S: Stream + AsTcpStream + BufferedStream,
+{
+  // Prefer binary data frames if the client supports them
+  let protocols = connection.protocols();
+  let binary = if protocols.contains(&BINARY_PROTOCOL.to_string()) {
+    true
+  } else if protocols.contains(&JSON_PROTOCOL.to_string()) {
+    false
+  } else {
+    if connection.reject().is_err() {
+      debug!("{}: Connection rejection failed.", sub_id);
+    }
     return;
-  }
+  };
+  let protocol = if binary { BINARY_PROTOCOL } else { JSON_PROTOCOL };
 
-  connection
-    .tcp_stream()
-    .set_nonblocking(true)
-    .expect(&"Setting stream non-blocking failed.");
+  // Queue length and overflow policy can be set in the url
+  let queue_options = match connection.request.subject.1 {
+    RequestUri::AbsolutePath(ref path) => queue_options.with_query(path),
+    _ => queue_options,
+  };
 
-  // Create response channel
-  let (sub_resp_tx, sub_resp_rx) = channel::<SerialResponse>();
+  // Don't let other websites use our ports
+  let origin = request_header(&connection, "Origin");
+  if !origin_policy.is_allowed(origin.as_ref().map(|o| &o[..])) {
+    warn!(
+      "{}: Rejecting connection from origin '{}'",
+      sub_id,
+      origin.unwrap_or_default()
+    );
+    if connection.reject().is_err() {
+      debug!("{}: Connection rejection failed.", sub_id);
+    }
+    return;
+  }
 
-  // Register sub_id with manager
-  sub_tx
-    .send(SubscriptionRequest {
-      sub_id: sub_id.clone(),
-      subscriber: sub_resp_tx,
-    })
-    .expect(&format!("{}: Registering with manager failed.", sub_id));
+  // Check a key given in the url or Authorization header,
+  // rejecting the upgrade if it is wrong
+  let mut identity = None;
+  if authenticator.is_required() {
+    if let Some(token) = request_token(&connection) {
+      match authenticator.authenticate(&token) {
+        Ok(id) => identity = Some(id),
+        Err(err) => {
+          warn!("{}: Rejecting connection, cause '{}'", sub_id, err);
+          if connection.reject().is_err() {
+            debug!("{}: Connection rejection failed.", sub_id);
+          }
+          return;
+        }
+      }
+    }
+  }
 
-  let client = connection
-    .use_protocol(format!("websocket-serial-json"))
-    .accept()
-    .expect(&format!("{}: Accept protocol failed.", sub_id));
+  let client = match connection.use_protocol(protocol.to_string()).accept() {
+    Ok(client) => client,
+    Err((_, e)) => {
+      warn!("{}: Accept protocol failed, cause '{}'", sub_id, e);
+      return;
+    }
+  };
 
   let ip = client
     .peer_addr()
-    .expect(&format!("{}: Could not get peer address", sub_id));
+    .unwrap_or_else(|_| panic!("{}: Could not get peer address", sub_id));
 
   info!("{}: Connection from {}", sub_id, ip);
 
-  let (mut receiver, mut sender) = client
-    .split()
-    .expect(&format!("{}: WS client error", sub_id));
+  // Sleeps until the client sends something,
+  // or the manager queues something for it
+  let mut connection = match WsConnection::new(client) {
+    Ok(connection) => connection,
+    Err(e) => {
+      warn!("{}: Setting up connection failed, cause '{}'", sub_id, e);
+      return;
+    }
+  };
 
-  let mut send_error_count = 0;
+  // Otherwise the first message has to be an Auth request
+  if authenticator.is_required() && identity.is_none() {
+    identity = wait_for_auth(&sub_id, &mut connection, authenticator);
+    if identity.is_none() {
+      connection
+        .send_message(&Message::close())
+        .unwrap_or_else(|e| debug!("{}: Could not send close, cause '{}'", sub_id, e));
+      info!("{}: Client {} failed to authenticate", sub_id, ip);
+      return;
+    }
+  }
+  if let Some(ref id) = identity {
+    info!("{}: Client {} authenticated as '{}'", sub_id, ip, id.name);
+  }
 
-  let mut dynamic_sleep = DynamicSleep::new("main");
+  // Create response queue
+  let (sub_resp_tx, sub_resp_rx) = sub_queue(queue_options.length, queue_options.policy);
+
+  // Register sub_id with manager
+  manager_tx
+    .send(ManagerEvent::Subscribe(SubscriptionRequest {
+      sub_id: sub_id.clone(),
+      subscriber: sub_resp_tx,
+      binary: binary,
+      identity: identity,
+    }))
+    .unwrap_or_else(|_| panic!("{}: Registering with manager failed.", sub_id));
+
+  // Wake up for queued messages too
+  connection.watch_queue(&sub_resp_rx);
+
+  let mut send_error_count = 0;
+  // Only check on the client between batches
+  // until the queue is empty
+  let mut more_queued = false;
 
   'msg_loop: loop {
-    dynamic_sleep.sleep();
-
-    // Try and read a WS message
-    match receiver.recv_message::<Message, _, _>() {
-      Ok(message) => {
-        match message.opcode {
-          Type::Close => {
-            let message = Message::close();
-            sender
-              .send_message(&message)
-              .unwrap_or(info!("{}: Client {} hung up!", sub_id, ip));
-            // Send close request to cleanup resources
-            sreq_tx
-              .send((sub_id.clone(), SerialRequest::Close { port: None }))
-              .unwrap_or_else(|e| {
-                warn!(
-                  "Client exit cleanup failed for sub_id '{}', cause '{}'",
-                  sub_id, e
-                )
-              });
-            info!("{}: Client {} disconnected", sub_id, ip);
-            break 'msg_loop;
-          }
+    let timeout = match more_queued {
+      true => Some(Duration::from_millis(0)),
+      false => None,
+    };
+    match connection.wait(timeout) {
+      // Queued messages are sent below
+      Ok(WsEvent::Queued) | Ok(WsEvent::Timeout) => {}
+
+      Ok(WsEvent::Message(OwnedMessage::Close(_))) => {
+        let _: () = info!("{}: Client {} hung up!", sub_id, ip);
+        connection
+        .send_message(&Message::close())
+        .unwrap_or(());
+        // Send close request to cleanup resources
+        unsubscribe(&sub_id, manager_tx);
+        info!("{}: Client {} disconnected", sub_id, ip);
+        break 'msg_loop;
+      }
 
-          Type::Ping => {
-            let message = Message::pong(message.payload);
-            sender
-              .send_message(&message)
-              .unwrap_or(info!("{}:  Could not ping client {}!", sub_id, ip));
+      Ok(WsEvent::Message(OwnedMessage::Ping(payload))) => {
+        let _: () = info!("{}:  Could not ping client {}!", sub_id, ip);
+        connection
+        .send_message(&Message::pong(payload))
+        .unwrap_or(());
+      }
+
+      Ok(WsEvent::Message(OwnedMessage::Pong(_))) => {}
+
+      Ok(WsEvent::Message(OwnedMessage::Binary(ref payload))) if binary => {
+        // Raw data to write to a port
+        match decode_write_frame(payload) {
+          Ok(req) => {
+            let id = req.id.clone();
+            if let Err(err) = manager_tx.send(ManagerEvent::Request(sub_id.clone(), req)) {
+              let error = e::ErrorKind::SendRequest(err).into();
+              send_serial_response_error(&sub_id, &mut connection, id, error);
+            }
           }
+          Err(error) => send_serial_response_error(&sub_id, &mut connection, None, error),
+        }
+      }
 
-          _ => {
-            // Get the payload, in a lossy manner
-            let msg = String::from_utf8_lossy(&message.payload);
-
-            // So we will get a result <SerialRequest::*,SerialResponse::Error> back
-            match serde_json::from_str(&msg) {
-              Ok(req) => {
-                match sreq_tx.send((sub_id.clone(), req)) {
-                  Err(err) => {
-                    let error = e::ErrorKind::SendRequest(err).into();
-                    send_serial_response_error(&sub_id, &mut sender, error);
-                  }
-                  _ => {}
-                };
-              }
-              Err(err) => {
-                let error = e::ErrorKind::Json(err).into();
-                send_serial_response_error(&sub_id, &mut sender, error);
-              }
-            };
+      Ok(WsEvent::Message(message)) => {
+        // Get the payload, in a lossy manner
+        let msg = match message {
+          OwnedMessage::Text(text) => text,
+          OwnedMessage::Binary(payload) => String::from_utf8_lossy(&payload).into_owned(),
+          _ => String::new(),
+        };
+
+        // So we will get a result <RequestEnvelope,SerialResponse::Error> back
+        match serde_json::from_str::<RequestEnvelope>(&msg) {
+          Ok(req) => {
+            let id = req.id.clone();
+            if let Err(err) = manager_tx.send(ManagerEvent::Request(sub_id.clone(), req)) {
+              let error = e::ErrorKind::SendRequest(err).into();
+              send_serial_response_error(&sub_id, &mut connection, id, error);
+            }
+          }
+          Err(err) => {
+            let id = request_id_from_json(&msg);
+            let error = e::ErrorKind::Json(err).into();
+            send_serial_response_error(&sub_id, &mut connection, id, error);
           }
         };
       }
-      Err(e) => {
-        match e {
-          WebSocketError::NoDataAvailable => { /*Logging?*/ }
-          _ => { /*Logging?*/ }
-        };
+
+      Err(err) => {
+        info!("{}: Lost client {}, cause '{}'", sub_id, ip, err);
+        unsubscribe(&sub_id, manager_tx);
+        break 'msg_loop;
       }
     }
 
-    // Send on any serial responses
-    match sub_resp_rx.try_recv() {
-      Ok(resp) => match serde_json::to_string(&resp) {
-        Ok(json) => {
-          let reply = Message::text(json.clone());
-          sender.send_message(&reply).unwrap_or_else(|e| {
-            send_error_count += 1;
-            info!(
-              "{}: Could not send message '{}' to client '{}', cause '{}'",
-              sub_id, json, ip, e
-            )
-          });
+    // Send a batch of what the manager queued, with reads
+    // merged. Client messages and batches take turns, so
+    // neither a busy client nor a busy port holds up the other
+    match connection.send_batch(&sub_id, &sub_resp_rx) {
+      Ok(errors) => {
+        more_queued = true;
+        for e in errors {
+          send_error_count += 1;
+          info!(
+            "{}: Could not send message to client '{}', cause '{}'",
+            sub_id, ip, e
+          );
         }
-        Err(_) => {}
-      },
-      _ => { /*Logging*/ }
-    };
+      }
+      Err(TryRecvError::Empty) => more_queued = false,
+      Err(TryRecvError::Disconnected) => {
+        // The manager ended the subscription, for example
+        // because the queue overflowed with the Disconnect policy
+        warn!("{}: Subscription ended, closing client {}", sub_id, ip);
+        connection
+          .send_message(&Message::close())
+          .unwrap_or_else(|e| debug!("{}: Could not send close, cause '{}'", sub_id, e));
+        break 'msg_loop;
+      }
+    }
 
     if send_error_count > MAX_SEND_ERROR_COUNT {
       warn!(
@@ -258,23 +509,154 @@ fn ws_handler(
   info!("{}: Shutting down!", sub_id);
 }
 
+/// Ask the manager to close the client's ports
+/// and forget its subscription
+fn unsubscribe(sub_id: &String, manager_tx: &Sender<ManagerEvent>) {
+  manager_tx
+    .send(ManagerEvent::Request(
+      sub_id.clone(),
+      SerialRequest::Close { port: None }.into(),
+    ))
+    .unwrap_or_else(|e| {
+      warn!(
+        "Client exit cleanup failed for sub_id '{}', cause '{}'",
+        sub_id, e
+      )
+    });
+}
+
+/// Get a key from the url query string or the Authorization header
+fn request_token<S>(connection: &Upgrade<S>) -> Option<String>
+where
+  S: Stream,
+{
+  let from_path = match connection.request.subject.1 {
+    RequestUri::AbsolutePath(ref path) => token_from_path(path),
+    _ => None,
+  };
+  from_path.or_else(|| {
+    request_header(connection, "Authorization").and_then(|value| token_from_auth_header(&value))
+  })
+}
+
+/// Get the first value of a request header as a string
+fn request_header<S>(connection: &Upgrade<S>, name: &str) -> Option<String>
+where
+  S: Stream,
+{
+  connection
+    .request
+    .headers
+    .get_raw(name)
+    .and_then(|values| values.first())
+    .and_then(|value| str::from_utf8(value).ok())
+    .map(|value| value.to_string())
+}
+
+/// Wait for the client to send an Auth request
+///
+/// Returns the identity of the matching key, or None if the
+/// client sent something else, a bad key, or nothing
+/// at all within AUTH_TIMEOUT_MS
+fn wait_for_auth<S>(
+  sub_id: &String,
+  connection: &mut WsConnection<S>,
+  authenticator: &Authenticator,
+) -> Option<Identity>
+where
+  S: Stream + AsTcpStream + BufferedStream,
+{
+  let deadline = Instant::now() + Duration::from_millis(AUTH_TIMEOUT_MS);
+
+  loop {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let msg = match connection.wait(Some(remaining)) {
+      Ok(WsEvent::Message(OwnedMessage::Close(_))) => return None,
+      Ok(WsEvent::Message(OwnedMessage::Ping(payload))) => {
+        connection
+          .send_message(&Message::pong(payload))
+          .unwrap_or_else(|e| debug!("{}: Could not ping client, cause '{}'", sub_id, e));
+        continue;
+      }
+      Ok(WsEvent::Message(OwnedMessage::Pong(_))) => continue,
+      Ok(WsEvent::Message(OwnedMessage::Text(text))) => text,
+      Ok(WsEvent::Message(OwnedMessage::Binary(payload))) => {
+        String::from_utf8_lossy(&payload).into_owned()
+      }
+      // Nothing is queued before authenticating
+      Ok(WsEvent::Queued) => continue,
+      Ok(WsEvent::Timeout) => break,
+      Err(e) => {
+        debug!("{}: Lost client while authenticating, cause '{}'", sub_id, e);
+        return None;
+      }
+    };
+
+    let (id, result) = match serde_json::from_str::<RequestEnvelope>(&msg) {
+      Ok(RequestEnvelope {
+        id,
+        request: SerialRequest::Auth { token },
+      }) => (id, authenticator.authenticate(&token)),
+      Ok(req) => (req.id, Err(e::ErrorKind::NotAuthenticated.into())),
+      Err(_) => (request_id_from_json(&msg), Err(e::ErrorKind::NotAuthenticated.into())),
+    };
+
+    return match result {
+      Ok(identity) => {
+        let resp = ResponseEnvelope {
+          id: id,
+          response: SerialResponse::Authenticated {
+            name: identity.name.clone(),
+          },
+        };
+        if let Some(reply) = to_ws_message(sub_id, SubscriberMessage::Response(resp)) {
+          connection
+            .send_message(&reply)
+            .unwrap_or_else(|e| debug!("{}: Could not send reply, cause '{}'", sub_id, e));
+        }
+        Some(identity)
+      }
+      Err(error) => {
+        warn!("{}: Authentication failed, cause '{}'", sub_id, error);
+        send_serial_response_error(sub_id, connection, id, error);
+        None
+      }
+    };
+  }
+
+  send_serial_response_error(sub_id, connection, None, e::ErrorKind::NotAuthenticated.into());
+  None
+}
+
 /// Send an error to the given subscriber
 /// Log a warning if the message can't be sent
 /// This is usually ok as it means the client
 /// has simply disconnected
-fn send_serial_response_error(sub_id: &String, sender: &mut Writer<TcpStream>, error: e::Error) {
-  let error = e::to_serial_response_error(error);
-  serde_json::to_string(&error)
-    .map_err(|err| e::ErrorKind::Json(err))
-    .map(|json| Message::text(json))
-    .map(|msg| {
-      sender
-        .send_message::<Message, _>(&msg)
-        .map_err::<e::Error, _>(|err| e::ErrorKind::SendWsMessage(err).into())
-    })
-    .unwrap_or_else(|_| {
-      warn!("{}: Problem sending bad json error response", sub_id);
-      Ok(())
-    })
-    .is_ok(); // This shouldn't be needed?
+fn send_serial_response_error<S>(
+  sub_id: &String,
+  connection: &mut WsConnection<S>,
+  id: Option<String>,
+  error: e::Error,
+) where
+  S: Stream + AsTcpStream + BufferedStream,
+{
+  let error = ResponseEnvelope {
+    id: id,
+    response: e::to_serial_response_error(error),
+  };
+  match serde_json::to_string(&error) {
+    Ok(json) => connection
+      .send_message(&Message::text(json))
+      .unwrap_or_else(|e| warn!("{}: Could not send error response, cause '{}'", sub_id, e)),
+    Err(_) => warn!("{}: Problem sending bad json error response", sub_id),
+  }
+}
+
+/// Try and pull the request id out of a message
+/// that could not be parsed as a request, so the
+/// error response can still be matched up
+fn request_id_from_json(msg: &str) -> Option<String> {
+  serde_json::from_str::<serde_json::Value>(msg)
+    .ok()
+    .and_then(|v| v.get("id").and_then(|id| id.as_str()).map(|id| id.to_string()))
 }
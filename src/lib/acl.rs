@@ -0,0 +1,189 @@
+//! Per port access control
+//!
+//! Rules from the config map glob patterns on port names
+//! to the key names or roles allowed to read (Open),
+//! write (WriteLock) and administer (ForceWriteLock)
+//! matching ports. The first rule matching
+//! a port applies. If any rules are configured, ports not
+//! matched by a rule can not be used by anyone.
+
+use crate::auth::Identity;
+use crate::cfg::AclRule;
+use crate::errors::*;
+
+/// Matches any client, including ones that
+/// did not authenticate
+pub const ANYONE: &str = "*";
+
+/// Checks which clients may use which ports
+#[derive(Clone, Debug, Default)]
+pub struct AccessControl {
+  /// Rules, in order of priority
+  rules: Vec<AclRule>,
+}
+
+impl AccessControl {
+  /// Constructor
+  pub fn new(rules: Vec<AclRule>) -> AccessControl {
+    AccessControl { rules: rules }
+  }
+
+  /// Check if a client may open a port
+  pub fn check_read(&self, port_name: &String, identity: Option<&Identity>) -> Result<()> {
+    self.check(port_name, |rule| {
+      allows(&rule.read, identity) || allows(&rule.write, identity) || allows(&rule.admin, identity)
+    })
+  }
+
+  /// Check if a client may write lock a port
+  pub fn check_write(&self, port_name: &String, identity: Option<&Identity>) -> Result<()> {
+    self.check(port_name, |rule| {
+      allows(&rule.write, identity) || allows(&rule.admin, identity)
+    })
+  }
+
+  /// Check if a client may take the write lock on
+  /// a port from another client
+  pub fn check_admin(&self, port_name: &String, identity: Option<&Identity>) -> Result<()> {
+    self.check(port_name, |rule| allows(&rule.admin, identity))
+  }
+
+  /// Check the first rule matching the port
+  fn check<F>(&self, port_name: &String, allowed: F) -> Result<()>
+  where
+    F: Fn(&AclRule) -> bool,
+  {
+    if self.rules.is_empty() {
+      return Ok(());
+    }
+    match self
+      .rules
+      .iter()
+      .find(|rule| glob_match(&rule.ports, port_name))
+    {
+      Some(rule) if allowed(rule) => Ok(()),
+      _ => Err(ErrorKind::PermissionDenied(port_name.to_string()).into()),
+    }
+  }
+}
+
+/// Does a list of key names / roles include the client
+fn allows(entries: &[String], identity: Option<&Identity>) -> bool {
+  entries.iter().any(|entry| {
+    entry == ANYONE || identity.is_some_and(|id| id.is(entry))
+  })
+}
+
+/// Match a name against a glob pattern where `*` matches
+/// any number of characters and `?` matches one character
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+  let pattern: Vec<char> = pattern.chars().collect();
+  let name: Vec<char> = name.chars().collect();
+  let (mut p, mut n) = (0, 0);
+  // Position of the last * seen, and where in the name it
+  // started matching, so we can backtrack
+  let mut star: Option<(usize, usize)> = None;
+
+  while n < name.len() {
+    if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+      p += 1;
+      n += 1;
+    } else if p < pattern.len() && pattern[p] == '*' {
+      star = Some((p, n));
+      p += 1;
+    } else if let Some((star_p, star_n)) = star {
+      // Let the last * swallow one more character
+      p = star_p + 1;
+      n = star_n + 1;
+      star = Some((star_p, star_n + 1));
+    } else {
+      return false;
+    }
+  }
+  pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  fn rule(ports: &str, read: &[&str], write: &[&str]) -> AclRule {
+    AclRule {
+      ports: ports.to_string(),
+      read: read.iter().map(|s| s.to_string()).collect(),
+      write: write.iter().map(|s| s.to_string()).collect(),
+      admin: Vec::new(),
+    }
+  }
+
+  fn identity(name: &str, roles: &[&str]) -> Identity {
+    Identity {
+      name: name.to_string(),
+      roles: roles.iter().map(|s| s.to_string()).collect(),
+    }
+  }
+
+  #[test]
+  fn test_glob_match() {
+    assert!(glob_match("/dev/ttyUSB*", "/dev/ttyUSB0"));
+    assert!(glob_match("/dev/ttyUSB*", "/dev/ttyUSB"));
+    assert!(glob_match("/dev/tty???0", "/dev/ttyUSB0"));
+    assert!(glob_match("*", "COM3"));
+    assert!(glob_match("/dev/*USB*", "/dev/ttyUSB12"));
+    assert!(glob_match("*B1", "/dev/ttyUSB1B1"));
+    assert!(!glob_match("/dev/ttyUSB?", "/dev/ttyUSB10"));
+    assert!(!glob_match("/dev/ttyACM*", "/dev/ttyUSB0"));
+    assert!(!glob_match("", "/dev/ttyUSB0"));
+  }
+
+  #[test]
+  fn test_access_control() {
+    let port = "/dev/ttyUSB0".to_string();
+    let printer = "/dev/ttyACM0".to_string();
+    let other = "/dev/ttyS0".to_string();
+    let operator = identity("operator", &["viewer"]);
+    let service = identity("print-service", &[]);
+
+    // No rules, anything goes
+    let acl = AccessControl::new(Vec::new());
+    assert!(acl.check_write(&port, None).is_ok());
+
+    let acl = AccessControl::new(vec![
+      rule("/dev/ttyACM*", &["viewer"], &["print-service"]),
+      rule("/dev/ttyUSB*", &["*"], &["*"]),
+    ]);
+
+    assert!(acl.check_read(&printer, Some(&operator)).is_ok());
+    assert!(acl.check_write(&printer, Some(&operator)).is_err());
+    assert!(acl.check_read(&printer, Some(&service)).is_ok());
+    assert!(acl.check_write(&printer, Some(&service)).is_ok());
+    assert!(acl.check_read(&printer, None).is_err());
+
+    assert!(acl.check_write(&port, None).is_ok());
+    assert!(acl.check_write(&port, Some(&operator)).is_ok());
+
+    // Ports without a rule are off limits
+    assert!(acl.check_read(&other, Some(&service)).is_err());
+  }
+
+  #[test]
+  fn test_admin() {
+    let printer = "/dev/ttyACM0".to_string();
+    let operator = identity("operator", &["viewer"]);
+    let service = identity("print-service", &[]);
+    let admin = identity("admin", &["admins"]);
+
+    let mut printer_rule = rule("/dev/ttyACM*", &["viewer"], &["print-service"]);
+    printer_rule.admin = vec!["admins".to_string()];
+    let acl = AccessControl::new(vec![printer_rule]);
+
+    assert!(acl.check_admin(&printer, Some(&admin)).is_ok());
+    assert!(acl.check_admin(&printer, Some(&service)).is_err());
+    assert!(acl.check_admin(&printer, Some(&operator)).is_err());
+    assert!(acl.check_admin(&printer, None).is_err());
+    // Admins may also read and write
+    assert!(acl.check_read(&printer, Some(&admin)).is_ok());
+    assert!(acl.check_write(&printer, Some(&admin)).is_ok());
+  }
+}
@@ -0,0 +1,150 @@
+//! Authenticates websocket clients against the
+//! api keys given in the config
+//!
+//! Clients can pass their key in the query string of the
+//! websocket url (`ws://host:port/?token=...`), in an
+//! `Authorization: Bearer ...` header, or in a
+//! SerialRequest::Auth message sent first thing after connecting
+
+use crate::cfg::ApiKey;
+use crate::common::query_param;
+use crate::errors::*;
+
+/// Query string parameter holding the key
+pub const TOKEN_QUERY_PARAM: &str = "token";
+/// Authorization header scheme for the key
+pub const BEARER_SCHEME: &str = "Bearer ";
+/// How long a client has to send an Auth message
+/// after connecting, before it is disconnected
+pub const AUTH_TIMEOUT_MS: u64 = 10000;
+
+/// Who a client authenticated as
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+  /// Name of the matched key
+  pub name: String,
+  /// Roles given to the key
+  pub roles: Vec<String>,
+}
+
+impl Identity {
+  /// Does the identity have the given name or role
+  pub fn is(&self, name_or_role: &str) -> bool {
+    self.name == name_or_role || self.roles.iter().any(|r| r == name_or_role)
+  }
+}
+
+/// Checks keys presented by clients
+#[derive(Clone, Debug)]
+pub struct Authenticator {
+  /// Accepted keys
+  api_keys: Vec<ApiKey>,
+}
+
+impl Authenticator {
+  /// Constructor
+  pub fn new(api_keys: Vec<ApiKey>) -> Authenticator {
+    Authenticator { api_keys: api_keys }
+  }
+
+  /// Authentication is only required if keys are configured
+  pub fn is_required(&self) -> bool {
+    !self.api_keys.is_empty()
+  }
+
+  /// Check a token, returning the identity of the matching key
+  pub fn authenticate(&self, token: &str) -> Result<Identity> {
+    self
+      .api_keys
+      .iter()
+      .find(|api_key| constant_time_eq(api_key.key.as_bytes(), token.as_bytes()))
+      .map(|api_key| Identity {
+        name: api_key.name.clone(),
+        roles: api_key.roles.clone(),
+      })
+      .ok_or_else(|| ErrorKind::AuthenticationFailed.into())
+  }
+}
+
+/// Get the token from the query string of a request path,
+/// for example `/?token=abc`
+pub fn token_from_path(path: &str) -> Option<String> {
+  query_param(path, TOKEN_QUERY_PARAM)
+}
+
+/// Get the token from an Authorization header value,
+/// for example `Bearer abc`
+pub fn token_from_auth_header(value: &str) -> Option<String> {
+  let value = value.trim();
+  match value.get(..BEARER_SCHEME.len()) {
+    Some(scheme) if scheme.eq_ignore_ascii_case(BEARER_SCHEME) => {
+      Some(value[BEARER_SCHEME.len()..].trim().to_string())
+    }
+    _ => None,
+  }
+}
+
+/// Compare two byte strings without bailing out
+/// at the first difference, so the time taken
+/// doesn't leak how much of a key matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_authenticate() {
+    let auth = Authenticator::new(vec![
+      ApiKey {
+        name: "operator".to_string(),
+        key: "key-1".to_string(),
+        roles: vec!["viewer".to_string()],
+      },
+      ApiKey {
+        name: "print-service".to_string(),
+        key: "key-2".to_string(),
+        roles: Vec::new(),
+      },
+    ]);
+    assert!(auth.is_required());
+    assert_eq!(auth.authenticate("key-2").unwrap().name, "print-service");
+    let operator = auth.authenticate("key-1").unwrap();
+    assert_eq!(operator.name, "operator");
+    assert!(operator.is("operator"));
+    assert!(operator.is("viewer"));
+    assert!(!operator.is("print-service"));
+    assert!(auth.authenticate("key-").is_err());
+    assert!(auth.authenticate("").is_err());
+
+    assert!(!Authenticator::new(Vec::new()).is_required());
+  }
+
+  #[test]
+  fn test_token_parsing() {
+    assert_eq!(token_from_path("/?token=abc"), Some("abc".to_string()));
+    assert_eq!(
+      token_from_path("/ws?x=1&token=a%2Bb"),
+      Some("a+b".to_string())
+    );
+    assert_eq!(token_from_path("/"), None);
+    assert_eq!(token_from_path("/?tokens=abc"), None);
+
+    assert_eq!(
+      token_from_auth_header("Bearer abc"),
+      Some("abc".to_string())
+    );
+    assert_eq!(
+      token_from_auth_header("bearer  abc "),
+      Some("abc".to_string())
+    );
+    assert_eq!(token_from_auth_header("Basic YWJj"), None);
+    assert_eq!(token_from_auth_header("Bearer"), None);
+  }
+}
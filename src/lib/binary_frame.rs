@@ -0,0 +1,168 @@
+//! Encoding and decoding of binary websocket frames
+//!
+//! Clients using the `websocket-serial-binary` subprotocol
+//! send and receive serial data as binary frames instead of
+//! json, while all other requests and responses stay json.
+//!
+//! Frame layout:
+//!
+//! ``` text
+//! +------+----------+-----------------+--------------+
+//! | kind | name len | port name       | data         |
+//! | u8   | u8       | name len bytes  | rest of frame|
+//! +------+----------+-----------------+--------------+
+//! ```
+//!
+//! `kind` is [READ_FRAME](constant.READ_FRAME.html) for data read
+//! from a port and sent to the client, or
+//! [WRITE_FRAME](constant.WRITE_FRAME.html) for data the
+//! client wants written to a port. The port name is utf8.
+//!
+//! Writes are answered with the usual json
+//! SerialResponse::Wrote or SerialResponse::Error. To match
+//! those up, clients can send a
+//! [WRITE_WITH_ID_FRAME](constant.WRITE_WITH_ID_FRAME.html)
+//! instead, whose data starts with a utf8 request id that is
+//! echoed on the reply, like the id of a json request:
+//!
+//! ``` text
+//! +------+----------+-----------+--------+--------+--------------+
+//! | kind | name len | port name | id len | id     | data         |
+//! | u8   | u8       |           | u8     |        | rest of frame|
+//! +------+----------+-----------+--------+--------+--------------+
+//! ```
+
+use crate::errors::*;
+use crate::messages::{RequestEnvelope, SerialRequest};
+
+/// Subprotocol for json only clients
+pub const JSON_PROTOCOL: &str = "websocket-serial-json";
+/// Subprotocol for clients using binary data frames
+pub const BINARY_PROTOCOL: &str = "websocket-serial-binary";
+
+/// Frame kind for data read from a port
+pub const READ_FRAME: u8 = 0x00;
+/// Frame kind for data to write to a port
+pub const WRITE_FRAME: u8 = 0x01;
+/// Frame kind for data to write to a port, with a request id
+pub const WRITE_WITH_ID_FRAME: u8 = 0x02;
+
+/// Length of the fixed part of the header
+const HEADER_LEN: usize = 2;
+
+/// Encode a frame of the given kind
+pub fn encode_frame(kind: u8, port_name: &str, data: &[u8]) -> Result<Vec<u8>> {
+  let mut frame = Vec::with_capacity(HEADER_LEN + port_name.len() + data.len());
+  frame.push(kind);
+  push_short_str(&mut frame, "port name", port_name)?;
+  frame.extend_from_slice(data);
+  Ok(frame)
+}
+
+/// Encode a write frame, with a request id if one is given
+pub fn encode_write_frame(id: Option<&str>, port_name: &str, data: &[u8]) -> Result<Vec<u8>> {
+  match id {
+    None => encode_frame(WRITE_FRAME, port_name, data),
+    Some(id) => {
+      let mut frame = encode_frame(WRITE_WITH_ID_FRAME, port_name, &[])?;
+      push_short_str(&mut frame, "request id", id)?;
+      frame.extend_from_slice(data);
+      Ok(frame)
+    }
+  }
+}
+
+/// Append a string prefixed with its length as a u8
+fn push_short_str(frame: &mut Vec<u8>, what: &str, s: &str) -> Result<()> {
+  if s.len() > u8::MAX as usize {
+    return Err(ErrorKind::InvalidBinaryFrame(format!("{} '{}' is too long", what, s)).into());
+  }
+  frame.push(s.len() as u8);
+  frame.extend_from_slice(s.as_bytes());
+  Ok(())
+}
+
+/// Decode a frame into its kind, port name and data
+pub fn decode_frame(frame: &[u8]) -> Result<(u8, String, Vec<u8>)> {
+  if frame.len() < HEADER_LEN {
+    return Err(ErrorKind::InvalidBinaryFrame("frame header is truncated".to_string()).into());
+  }
+  let kind = frame[0];
+  let name_end = HEADER_LEN + frame[1] as usize;
+  if frame.len() < name_end {
+    return Err(ErrorKind::InvalidBinaryFrame("port name is truncated".to_string()).into());
+  }
+  let port_name = String::from_utf8(frame[HEADER_LEN..name_end].to_vec())?;
+  Ok((kind, port_name, frame[name_end..].to_vec()))
+}
+
+/// Decode a write frame sent by a client into a
+/// SerialRequest::WriteBytes along with its request id
+pub fn decode_write_frame(frame: &[u8]) -> Result<RequestEnvelope> {
+  let (id, port_name, data) = match decode_frame(frame)? {
+    (WRITE_FRAME, port_name, data) => (None, port_name, data),
+    (WRITE_WITH_ID_FRAME, port_name, rest) => match rest.split_first() {
+      Some((&id_len, rest)) if rest.len() >= id_len as usize => {
+        let (id, data) = rest.split_at(id_len as usize);
+        (Some(String::from_utf8(id.to_vec())?), port_name, data.to_vec())
+      }
+      _ => {
+        return Err(ErrorKind::InvalidBinaryFrame("request id is truncated".to_string()).into())
+      }
+    },
+    (kind, _, _) => {
+      return Err(
+        ErrorKind::InvalidBinaryFrame(format!("expected write frame, got kind {}", kind)).into(),
+      )
+    }
+  };
+  Ok(RequestEnvelope {
+    id: id,
+    request: SerialRequest::WriteBytes {
+      port: port_name,
+      data: data,
+    },
+  })
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_frames() {
+    let data = vec![0u8, 159, 146, 150, 255];
+    let frame = encode_frame(WRITE_FRAME, "/dev/ttyUSB0", &data).expect("Encoding frame failed");
+    assert_eq!(frame.len(), 2 + 12 + data.len(), "Frame length is wrong");
+
+    let write = SerialRequest::WriteBytes {
+      port: "/dev/ttyUSB0".to_string(),
+      data: data.clone(),
+    };
+    let envelope = decode_write_frame(&frame).expect("Decoding frame failed");
+    assert_eq!(envelope, write.clone().into(), "Write should round trip");
+
+    let frame = encode_write_frame(Some("w1"), "/dev/ttyUSB0", &data).expect("Encoding failed");
+    assert_eq!(frame.len(), 2 + 12 + 1 + 2 + data.len(), "Frame length is wrong");
+    let envelope = decode_write_frame(&frame).expect("Decoding frame failed");
+    assert_eq!(envelope.id, Some("w1".to_string()), "Id should round trip");
+    assert_eq!(envelope.request, write, "Write should round trip");
+    assert!(
+      decode_write_frame(&frame[..2 + 12 + 2]).is_err(),
+      "Truncated id should fail"
+    );
+
+    let frame = encode_frame(READ_FRAME, "/dev/ttyUSB0", &data).expect("Encoding frame failed");
+    assert!(
+      decode_write_frame(&frame).is_err(),
+      "Read frame should not decode as a write frame"
+    );
+
+    assert!(decode_frame(&[WRITE_FRAME]).is_err(), "Truncated header should fail");
+    assert!(
+      decode_frame(&[WRITE_FRAME, 10, b'/']).is_err(),
+      "Truncated port name should fail"
+    );
+  }
+}
@@ -9,13 +9,14 @@ use std::default::Default;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::str::FromStr;
 
-use argparse::{ArgumentParser, StoreOption};
+use argparse::{ArgumentParser, StoreConst, StoreOption};
 use toml;
 
 use crate::errors::*;
+use crate::sub_queue::{OverflowPolicy, DEFAULT_QUEUE_LENGTH};
 
 /// Default HTTP port to bind to if none given
 pub const DEFAULT_HTTP_PORT: u32 = 10080;
@@ -37,6 +38,20 @@ pub const BIND_ADDRESS_ENV_KEY: &str = "WSSS_BIND_ADDRESS";
 pub const HTTP_PORT_ENV_KEY: &str = "WSSS_HTTP_PORT";
 /// Env variable name for specifying WS port
 pub const WS_PORT_ENV_KEY: &str = "WSSS_WS_PORT";
+/// Env variable name for serving websockets on the HTTP port
+pub const SINGLE_PORT_ENV_KEY: &str = "WSSS_SINGLE_PORT";
+/// Env variable name for specifying the TLS certificate file
+pub const TLS_CERT_ENV_KEY: &str = "WSSS_TLS_CERT";
+/// Env variable name for specifying the TLS private key file
+pub const TLS_KEY_ENV_KEY: &str = "WSSS_TLS_KEY";
+/// Env variable name for specifying allowed origins, comma separated
+pub const ALLOWED_ORIGINS_ENV_KEY: &str = "WSSS_ALLOWED_ORIGINS";
+/// Env variable name for allowing connections without an Origin header
+pub const ALLOW_NO_ORIGIN_ENV_KEY: &str = "WSSS_ALLOW_NO_ORIGIN";
+/// Env variable name for specifying the client queue length
+pub const QUEUE_LENGTH_ENV_KEY: &str = "WSSS_QUEUE_LENGTH";
+/// Env variable name for specifying the client queue overflow policy
+pub const OVERFLOW_ENV_KEY: &str = "WSSS_OVERFLOW";
 
 const HTTP_PORT_KEY: &str = "http_port";
 const WS_PORT_KEY: &str = "ws_port";
@@ -48,22 +63,38 @@ const BIND_ADDRESS_KEY: &str = "bind_address";
 struct TomlWsssConfig {
   pub http_port: Option<u32>,
   pub ws_port: Option<u32>,
-  pub bind_address: Option<String>,
+  pub single_port: Option<bool>,
+  pub bind_address: Option<BindAddress>,
+  pub tls_cert: Option<String>,
+  pub tls_key: Option<String>,
+  pub allowed_origins: Option<Vec<String>>,
+  pub allow_no_origin: Option<bool>,
+  pub queue_length: Option<usize>,
+  pub overflow: Option<OverflowPolicy>,
+  pub api_keys: Option<Vec<ApiKey>>,
+  pub acl: Option<Vec<AclRule>>,
 }
 
 impl TomlWsssConfig {
   /// Convert to a WsssConfig with default values
   /// substituted for missing values
+  #[allow(clippy::wrong_self_convention)]
   pub fn to_config(self) -> Result<WsssConfig> {
-    let addr_string: String = self.bind_address.unwrap_or(DEFAULT_BIND_ADDR.to_string());
-
-    let ip_addr = Ipv4Addr::from_str(&addr_string)?;
-    // .map_err(|e| ErrorKind::IpAddr(e).into())?;
+    let ip_addrs = parse_bind_addresses(self.bind_address)?;
 
     Ok(WsssConfig {
       http_port: self.http_port.unwrap_or(DEFAULT_HTTP_PORT),
       ws_port: self.ws_port.unwrap_or(DEFAULT_WS_PORT),
-      bind_address: ip_addr,
+      single_port: self.single_port.unwrap_or(false),
+      bind_address: ip_addrs,
+      tls_cert: self.tls_cert,
+      tls_key: self.tls_key,
+      allowed_origins: self.allowed_origins.unwrap_or_default(),
+      allow_no_origin: self.allow_no_origin.unwrap_or(true),
+      queue_length: self.queue_length.unwrap_or(DEFAULT_QUEUE_LENGTH),
+      overflow: self.overflow.unwrap_or_default(),
+      api_keys: self.api_keys.unwrap_or_default(),
+      acl: self.acl.unwrap_or_default(),
     })
   }
 
@@ -73,7 +104,16 @@ impl TomlWsssConfig {
     TomlWsssConfig {
       http_port: merge_options(self.http_port, o.http_port),
       ws_port: merge_options(self.ws_port, o.ws_port),
+      single_port: merge_options(self.single_port, o.single_port),
       bind_address: merge_options(self.bind_address, o.bind_address),
+      tls_cert: merge_options(self.tls_cert, o.tls_cert),
+      tls_key: merge_options(self.tls_key, o.tls_key),
+      allowed_origins: merge_options(self.allowed_origins, o.allowed_origins),
+      allow_no_origin: merge_options(self.allow_no_origin, o.allow_no_origin),
+      queue_length: merge_options(self.queue_length, o.queue_length),
+      overflow: merge_options(self.overflow, o.overflow),
+      api_keys: merge_options(self.api_keys, o.api_keys),
+      acl: merge_options(self.acl, o.acl),
     }
   }
 
@@ -82,7 +122,11 @@ impl TomlWsssConfig {
   pub fn parse_cmdline() -> TomlWsssConfig {
     let mut port: Option<u32> = None;
     let mut ws_port: Option<u32> = None;
+    let mut single_port: Option<bool> = None;
     let mut bind_address: Option<String> = None;
+    let mut tls_cert: Option<String> = None;
+    let mut tls_key: Option<String> = None;
+    let mut allowed_origins: Option<String> = None;
 
     {
       let mut ap = ArgumentParser::new();
@@ -91,10 +135,30 @@ impl TomlWsssConfig {
         .add_option(&["-p", "--http_port"], StoreOption, "Http Port");
       ap.refer(&mut ws_port)
         .add_option(&["-w", "--ws_port"], StoreOption, "Websocket Port");
+      ap.refer(&mut single_port).add_option(
+        &["-s", "--single_port"],
+        StoreConst(Some(true)),
+        "Serve websockets on the Http Port",
+      );
       ap.refer(&mut bind_address).add_option(
         &["-a", "--bind_address"],
         StoreOption,
-        "Bind Address",
+        "Bind Addresses, comma separated",
+      );
+      ap.refer(&mut tls_cert).add_option(
+        &["-c", "--tls_cert"],
+        StoreOption,
+        "TLS certificate file (PEM)",
+      );
+      ap.refer(&mut tls_key).add_option(
+        &["-k", "--tls_key"],
+        StoreOption,
+        "TLS private key file (PEM)",
+      );
+      ap.refer(&mut allowed_origins).add_option(
+        &["-o", "--allowed_origins"],
+        StoreOption,
+        "Allowed websocket origins, comma separated",
       );
       ap.parse_args_or_exit();
     }
@@ -102,7 +166,16 @@ impl TomlWsssConfig {
     TomlWsssConfig {
       http_port: port,
       ws_port: ws_port,
-      bind_address: bind_address,
+      single_port: single_port,
+      bind_address: bind_address.map(|a| BindAddress::Many(split_list(&a))),
+      tls_cert: tls_cert,
+      tls_key: tls_key,
+      allowed_origins: allowed_origins.map(|o| split_list(&o)),
+      allow_no_origin: None,
+      queue_length: None,
+      overflow: None,
+      api_keys: None,
+      acl: None,
     }
   }
 
@@ -134,7 +207,28 @@ impl TomlWsssConfig {
       ws_port: env::var(WS_PORT_ENV_KEY)
         .ok()
         .and_then(|v| v.parse::<u32>().ok()),
-      bind_address: env::var(BIND_ADDRESS_ENV_KEY).ok(),
+      single_port: env::var(SINGLE_PORT_ENV_KEY)
+        .ok()
+        .and_then(|v| v.parse::<bool>().ok()),
+      bind_address: env::var(BIND_ADDRESS_ENV_KEY)
+        .ok()
+        .map(|v| BindAddress::Many(split_list(&v))),
+      tls_cert: env::var(TLS_CERT_ENV_KEY).ok(),
+      tls_key: env::var(TLS_KEY_ENV_KEY).ok(),
+      allowed_origins: env::var(ALLOWED_ORIGINS_ENV_KEY)
+        .ok()
+        .map(|v| split_list(&v)),
+      allow_no_origin: env::var(ALLOW_NO_ORIGIN_ENV_KEY)
+        .ok()
+        .and_then(|v| v.parse::<bool>().ok()),
+      queue_length: env::var(QUEUE_LENGTH_ENV_KEY)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok()),
+      overflow: env::var(OVERFLOW_ENV_KEY)
+        .ok()
+        .and_then(|v| v.parse::<OverflowPolicy>().ok()),
+      api_keys: None,
+      acl: None,
     }
   }
 }
@@ -145,11 +239,66 @@ impl From<WsssConfig> for TomlWsssConfig {
     TomlWsssConfig {
       http_port: Some(wsss_cfg.http_port),
       ws_port: Some(wsss_cfg.ws_port),
-      bind_address: Some(wsss_cfg.bind_address.to_string()),
+      single_port: Some(wsss_cfg.single_port),
+      bind_address: Some(BindAddress::Many(
+        wsss_cfg
+          .bind_address
+          .iter()
+          .map(|a| a.to_string())
+          .collect(),
+      )),
+      tls_cert: wsss_cfg.tls_cert,
+      tls_key: wsss_cfg.tls_key,
+      allowed_origins: Some(wsss_cfg.allowed_origins),
+      allow_no_origin: Some(wsss_cfg.allow_no_origin),
+      queue_length: Some(wsss_cfg.queue_length),
+      overflow: Some(wsss_cfg.overflow),
+      api_keys: Some(wsss_cfg.api_keys),
+      acl: Some(wsss_cfg.acl),
     }
   }
 }
 
+/// One or more addresses to bind to
+///
+/// A single address string is still accepted
+/// for older config files
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum BindAddress {
+  /// A single address
+  One(String),
+  /// A list of addresses
+  Many(Vec<String>),
+}
+
+/// Parse bind addresses, using the default
+/// if none are given
+fn parse_bind_addresses(bind_address: Option<BindAddress>) -> Result<Vec<IpAddr>> {
+  let addrs = match bind_address {
+    Some(BindAddress::One(addr)) => vec![addr],
+    Some(BindAddress::Many(addrs)) => addrs,
+    None => Vec::new(),
+  };
+  if addrs.is_empty() {
+    return Ok(vec![IpAddr::from_str(DEFAULT_BIND_ADDR)?]);
+  }
+  addrs
+    .iter()
+    .map(|addr| IpAddr::from_str(addr.trim()).map_err(|e| e.into()))
+    .collect()
+}
+
+/// Split a comma separated list, dropping empty items
+fn split_list(list: &str) -> Vec<String> {
+  list
+    .split(',')
+    .map(|item| item.trim())
+    .filter(|item| !item.is_empty())
+    .map(|item| item.to_string())
+    .collect()
+}
+
 /// Merge o2 into o1, only if o2 is missing a value for a particular key
 fn merge_options<T>(o1: Option<T>, o2: Option<T>) -> Option<T> {
   match (o1, o2) {
@@ -169,7 +318,24 @@ fn merge_options<T>(o1: Option<T>, o2: Option<T>) -> Option<T> {
 /// ``` toml
 ///   http_port = 8080
 ///   ws_port = 8082
-///   bind_address = "10.1.100.12"
+///   single_port = false
+///   bind_address = ["10.1.100.12", "::1"]
+///   tls_cert = "/etc/wsss/cert.pem"
+///   tls_key = "/etc/wsss/key.pem"
+///   allowed_origins = ["https://lab.example.com"]
+///   allow_no_origin = true
+///   queue_length = 1000
+///   overflow = "DropOldest"
+///
+///   [[api_keys]]
+///   name = "print-service"
+///   key = "c2VjcmV0LWtleQ"
+///   roles = ["printing"]
+///
+///   [[acl]]
+///   ports = "/dev/ttyUSB*"
+///   read = ["*"]
+///   write = ["printing"]
 /// ```
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct WsssConfig {
@@ -191,14 +357,139 @@ pub struct WsssConfig {
   /// cmdline switch -w or --ws_port
   pub ws_port: u32,
 
-  /// Address to bind to.
+  /// Serve websockets on the http_port, at the
+  /// path /ws, instead of on a separate ws_port
+  ///
+  /// Defaults to false
+  ///
+  /// env var WSSS_SINGLE_PORT
+  ///
+  /// cmdline switch -s or --single_port
+  pub single_port: bool,
+
+  /// Addresses to bind to, IPv4 or IPv6.
+  /// The HTTP and websocket ports are
+  /// listened on at every address
   ///
   /// Defaults to 127.0.0.1 (localhost)
   ///
-  /// env var WSSS_BIND_ADDR
+  /// env var WSSS_BIND_ADDRESS, comma separated
+  ///
+  /// cmdline -a or --bind_address, comma separated
+  pub bind_address: Vec<IpAddr>,
+
+  /// PEM certificate file, including any intermediate
+  /// certificates. If both tls_cert and tls_key are given,
+  /// the HTTP page and websockets are served over TLS
+  /// (https:// and wss://)
+  ///
+  /// Defaults to None
+  ///
+  /// env var WSSS_TLS_CERT
+  ///
+  /// cmdline -c or --tls_cert
+  pub tls_cert: Option<String>,
+
+  /// PEM private key file for tls_cert
+  ///
+  /// Defaults to None
+  ///
+  /// env var WSSS_TLS_KEY
+  ///
+  /// cmdline -k or --tls_key
+  pub tls_key: Option<String>,
+
+  /// Origins of web pages allowed to open websockets,
+  /// besides the built in page. `"*"` allows any origin.
+  ///
+  /// Defaults to empty, only the built in page is allowed
+  ///
+  /// env var WSSS_ALLOWED_ORIGINS, comma separated
   ///
-  /// cmdline -a or --bind_address
-  pub bind_address: Ipv4Addr,
+  /// cmdline -o or --allowed_origins, comma separated
+  pub allowed_origins: Vec<String>,
+
+  /// Allow websocket connections without an Origin header.
+  /// Browsers always send one, so these come from scripts
+  /// and other programs
+  ///
+  /// Defaults to true
+  ///
+  /// env var WSSS_ALLOW_NO_ORIGIN
+  pub allow_no_origin: bool,
+
+  /// Max number of messages queued for a client.
+  /// Clients can ask for a different length with
+  /// `?queue_length=...` in the websocket url
+  ///
+  /// Defaults to 1000
+  ///
+  /// env var WSSS_QUEUE_LENGTH
+  pub queue_length: usize,
+
+  /// What to do with data read from ports when a client's
+  /// queue is full, `DropOldest`, `DropNewest` or `Disconnect`.
+  /// Clients can ask for a different policy with
+  /// `?overflow=...` in the websocket url
+  ///
+  /// Defaults to DropOldest
+  ///
+  /// env var WSSS_OVERFLOW
+  pub overflow: OverflowPolicy,
+
+  /// Keys clients must present to connect. If empty,
+  /// no authentication is required.
+  ///
+  /// Only read from the config file, so keys don't
+  /// show up in process listings
+  ///
+  /// Defaults to empty
+  pub api_keys: Vec<ApiKey>,
+
+  /// Rules restricting which clients may open and
+  /// write lock which ports. If empty, any client
+  /// may use any port.
+  ///
+  /// Only read from the config file
+  ///
+  /// Defaults to empty
+  pub acl: Vec<AclRule>,
+}
+
+/// A named key a client can use to authenticate
+///
+/// The name identifies the client in logs
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ApiKey {
+  /// Name of the client or user owning the key
+  pub name: String,
+  /// The secret key
+  pub key: String,
+  /// Roles the key has, used in acl rules
+  #[serde(default)]
+  pub roles: Vec<String>,
+}
+
+/// An access control rule for ports
+///
+/// Entries in read, write and admin are key names or roles,
+/// `"*"` matches any client. Clients allowed to write
+/// may also read, admins may also write.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AclRule {
+  /// Glob pattern for port names, `*` matches any
+  /// number of characters and `?` a single character
+  pub ports: String,
+  /// Who may open matching ports
+  #[serde(default)]
+  pub read: Vec<String>,
+  /// Who may write lock matching ports
+  #[serde(default)]
+  pub write: Vec<String>,
+  /// Who may take write locks from other
+  /// clients on matching ports
+  #[serde(default)]
+  pub admin: Vec<String>,
 }
 
 impl WsssConfig {
@@ -221,22 +512,27 @@ impl WsssConfig {
   /// parameters
   pub fn load() -> WsssConfig {
     let file_cfg = load_env_file()
-      .or_else(|| load_etc())
-      .or_else(|| load_local_file())
-      .unwrap_or(TomlWsssConfig::default());
+      .or_else(load_etc)
+      .or_else(load_local_file)
+      .unwrap_or_default();
 
     let env_cfg = TomlWsssConfig::parse_env();
 
     let cmdline_cfg = TomlWsssConfig::parse_cmdline();
 
-    return TomlWsssConfig::merge(
+    TomlWsssConfig::merge(
       cmdline_cfg,
       TomlWsssConfig::merge(
         env_cfg,
         TomlWsssConfig::merge(file_cfg, WsssConfig::default()),
       ),
     )
-    .into();
+    .into()
+  }
+
+  /// Is TLS configured
+  pub fn use_tls(&self) -> bool {
+    self.tls_cert.is_some() && self.tls_key.is_some()
   }
 
   /// Save the configuration to a file
@@ -258,7 +554,16 @@ impl Default for WsssConfig {
     WsssConfig {
       http_port: DEFAULT_HTTP_PORT,
       ws_port: DEFAULT_WS_PORT,
-      bind_address: Ipv4Addr::from_str(DEFAULT_BIND_ADDR).unwrap(),
+      single_port: false,
+      bind_address: vec![IpAddr::from_str(DEFAULT_BIND_ADDR).unwrap()],
+      tls_cert: None,
+      tls_key: None,
+      allowed_origins: Vec::new(),
+      allow_no_origin: true,
+      queue_length: DEFAULT_QUEUE_LENGTH,
+      overflow: OverflowPolicy::default(),
+      api_keys: Vec::new(),
+      acl: Vec::new(),
     }
   }
 }
@@ -267,16 +572,21 @@ impl Default for WsssConfig {
 /// WsssConfig replacing None with default values
 impl From<TomlWsssConfig> for WsssConfig {
   fn from(toml_wsss_cfg: TomlWsssConfig) -> WsssConfig {
-    let addr_string: String = toml_wsss_cfg
-      .bind_address
-      .unwrap_or(DEFAULT_BIND_ADDR.to_string());
-
-    let ip_addr = Ipv4Addr::from_str(&addr_string).unwrap();
+    let ip_addrs = parse_bind_addresses(toml_wsss_cfg.bind_address).unwrap();
 
     WsssConfig {
       http_port: toml_wsss_cfg.http_port.unwrap_or(DEFAULT_HTTP_PORT),
       ws_port: toml_wsss_cfg.ws_port.unwrap_or(DEFAULT_WS_PORT),
-      bind_address: ip_addr,
+      single_port: toml_wsss_cfg.single_port.unwrap_or(false),
+      bind_address: ip_addrs,
+      tls_cert: toml_wsss_cfg.tls_cert,
+      tls_key: toml_wsss_cfg.tls_key,
+      allowed_origins: toml_wsss_cfg.allowed_origins.unwrap_or_default(),
+      allow_no_origin: toml_wsss_cfg.allow_no_origin.unwrap_or(true),
+      queue_length: toml_wsss_cfg.queue_length.unwrap_or(DEFAULT_QUEUE_LENGTH),
+      overflow: toml_wsss_cfg.overflow.unwrap_or_default(),
+      api_keys: toml_wsss_cfg.api_keys.unwrap_or_default(),
+      acl: toml_wsss_cfg.acl.unwrap_or_default(),
     }
   }
 }
@@ -305,9 +615,9 @@ fn load_env_file() -> Option<TomlWsssConfig> {
 fn load_local_file() -> Option<TomlWsssConfig> {
   env::current_exe()
     .ok()
-    .and_then(|mut dir| {
+    .map(|mut dir| {
       dir.pop();
-      Some(dir)
+      dir
     })
     .and_then(|file| TomlWsssConfig::parse_file(&file.to_string_lossy()).ok())
 }
@@ -327,13 +637,22 @@ mod tests {
     let cfg = TomlWsssConfig::default();
     assert_eq!(cfg.http_port, None, "Http port should be None");
     assert_eq!(cfg.ws_port, None, "WS port should be None");
+    assert_eq!(cfg.single_port, None, "single port should be None");
     assert_eq!(cfg.bind_address, None, "bind address should be None");
+    assert_eq!(cfg.tls_cert, None, "tls cert should be None");
+    assert_eq!(cfg.tls_key, None, "tls key should be None");
+    assert_eq!(cfg.allowed_origins, None, "allowed origins should be None");
+    assert_eq!(cfg.allow_no_origin, None, "allow no origin should be None");
+    assert_eq!(cfg.queue_length, None, "queue length should be None");
+    assert_eq!(cfg.overflow, None, "overflow should be None");
+    assert_eq!(cfg.api_keys, None, "api keys should be None");
+    assert_eq!(cfg.acl, None, "acl should be None");
   }
 
   #[test]
   fn wsss_config_default() {
     let cfg = WsssConfig::default();
-    let def_bind_addr = Ipv4Addr::from_str(DEFAULT_BIND_ADDR).unwrap();
+    let def_bind_addr = vec![IpAddr::from_str(DEFAULT_BIND_ADDR).unwrap()];
     assert_eq!(
       cfg.http_port, DEFAULT_HTTP_PORT,
       "Http port should be '{}'",
@@ -351,13 +670,65 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_bind_addresses() {
+    let one: TomlWsssConfig = toml::from_str("bind_address = \"10.1.100.10\"").unwrap();
+    assert_eq!(
+      one.to_config().unwrap().bind_address,
+      vec![IpAddr::from_str("10.1.100.10").unwrap()]
+    );
+
+    let many: TomlWsssConfig =
+      toml::from_str("bind_address = [\"127.0.0.1\", \"::1\"]").unwrap();
+    assert_eq!(
+      many.to_config().unwrap().bind_address,
+      vec![
+        IpAddr::from_str("127.0.0.1").unwrap(),
+        IpAddr::from_str("::1").unwrap()
+      ]
+    );
+
+    let bad: TomlWsssConfig = toml::from_str("bind_address = [\"localhost\"]").unwrap();
+    assert!(bad.to_config().is_err());
+  }
+
+  #[test]
+  fn test_split_list() {
+    assert_eq!(
+      split_list(" https://a.example.com, ,http://b.example.com:8080,"),
+      vec!["https://a.example.com", "http://b.example.com:8080"]
+    );
+    assert!(split_list("").is_empty());
+  }
+
   #[test]
   fn test_file_config() {
     let mut tmp_cfg_file = tempfile().expect("Creating temp file failed");
     let cfg = WsssConfig {
       http_port: 12345,
       ws_port: 12346,
-      bind_address: Ipv4Addr::from_str("10.1.100.10").expect("Create config obj failed"),
+      single_port: true,
+      bind_address: vec![
+        IpAddr::from_str("10.1.100.10").expect("Create config obj failed"),
+        IpAddr::from_str("::1").expect("Create config obj failed"),
+      ],
+      tls_cert: Some("/etc/wsss/cert.pem".to_string()),
+      tls_key: Some("/etc/wsss/key.pem".to_string()),
+      allowed_origins: vec!["https://lab.example.com".to_string()],
+      allow_no_origin: false,
+      queue_length: 50,
+      overflow: OverflowPolicy::Disconnect,
+      api_keys: vec![ApiKey {
+        name: "print-service".to_string(),
+        key: "c2VjcmV0LWtleQ".to_string(),
+        roles: vec!["printing".to_string()],
+      }],
+      acl: vec![AclRule {
+        ports: "/dev/ttyUSB*".to_string(),
+        read: vec!["*".to_string()],
+        write: vec!["printing".to_string()],
+        admin: vec!["operator".to_string()],
+      }],
     };
     let cfg_str = toml::to_string(&cfg).expect("Serializing to toml failed");
     tmp_cfg_file.write_all(cfg_str.as_bytes()).unwrap();
@@ -0,0 +1,171 @@
+//! Batches messages queued for a websocket client, merging
+//! consecutive reads from the same port, so a busy port is
+//! sent as a few large messages instead of many small ones
+
+use std::sync::mpsc::TryRecvError;
+
+use base64;
+
+use crate::messages::*;
+use crate::sub_queue::QueueReceiver;
+
+/// Max messages taken from the queue in one batch
+pub const MAX_BATCH_SIZE: usize = 1000;
+/// Reads are not merged past this many bytes of data
+pub const MAX_MERGED_BYTES: usize = 64 * 1024;
+
+/// Take everything already queued, up to MAX_BATCH_SIZE
+/// messages, with consecutive reads merged
+pub fn try_recv_batch(receiver: &QueueReceiver) -> Result<Vec<SubscriberMessage>, TryRecvError> {
+  let first = receiver.try_recv()?;
+  let mut batch = vec![first];
+  batch.extend(receiver.drain(MAX_BATCH_SIZE - 1));
+  Ok(coalesce_reads(batch))
+}
+
+/// Merge consecutive reads from the same port
+///
+/// Framed reads, reads answering a request and reads
+/// with different encodings are left alone
+pub fn coalesce_reads(messages: Vec<SubscriberMessage>) -> Vec<SubscriberMessage> {
+  let mut merged: Vec<SubscriberMessage> = Vec::with_capacity(messages.len());
+  for msg in messages {
+    let appended = match merged.last_mut() {
+      Some(last) => append_read(last, &msg),
+      None => false,
+    };
+    if !appended {
+      merged.push(msg);
+    }
+  }
+  merged
+}
+
+/// Append the data of a read to the previous read,
+/// returning false if they can't be merged
+fn append_read(prev: &mut SubscriberMessage, next: &SubscriberMessage) -> bool {
+  match (prev, next) {
+    (
+      &mut SubscriberMessage::Data {
+        port: ref prev_port,
+        data: ref mut prev_data,
+      },
+      &SubscriberMessage::Data {
+        ref port,
+        ref data,
+      },
+    ) => {
+      if prev_port != port || prev_data.len() + data.len() > MAX_MERGED_BYTES {
+        return false;
+      }
+      prev_data.extend_from_slice(data);
+      true
+    }
+    (&mut SubscriberMessage::Response(ref mut prev), &SubscriberMessage::Response(ref next)) => {
+      if prev.id.is_some() || next.id.is_some() {
+        return false;
+      }
+      match (&mut prev.response, &next.response) {
+        (
+          &mut SerialResponse::Read {
+            port: ref prev_port,
+            data: ref mut prev_data,
+            encoding: ref prev_encoding,
+            ..
+          },
+          &SerialResponse::Read {
+            ref port,
+            ref data,
+            ref encoding,
+            ..
+          },
+        ) => {
+          if prev_port != port
+            || prev_encoding != encoding
+            || prev_data.len() + data.len() > MAX_MERGED_BYTES
+          {
+            return false;
+          }
+          append_encoded(prev_data, data, *encoding)
+        }
+        _ => false,
+      }
+    }
+    _ => false,
+  }
+}
+
+/// Append encoded data. Base64 can't simply be concatenated
+/// because of padding, so it is decoded and encoded again
+fn append_encoded(prev_data: &mut String, data: &str, encoding: Option<Encoding>) -> bool {
+  match encoding {
+    Some(Encoding::Base64) => match (base64::decode(prev_data.as_str()), base64::decode(data)) {
+      (Ok(mut prev_bytes), Ok(bytes)) => {
+        prev_bytes.extend_from_slice(&bytes);
+        *prev_data = base64::encode(&prev_bytes);
+        true
+      }
+      _ => false,
+    },
+    _ => {
+      prev_data.push_str(data);
+      true
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  fn read(port: &str, data: &str, encoding: Encoding) -> SubscriberMessage {
+    SubscriberMessage::Response(
+      SerialResponse::Read {
+        port: port.to_string(),
+        data: data.to_string(),
+        base64: Some(encoding == Encoding::Base64),
+        encoding: Some(encoding),
+      }
+      .into(),
+    )
+  }
+
+  #[test]
+  fn test_coalesce_reads() {
+    let ok = SubscriberMessage::Response(
+      SerialResponse::Ok {
+        msg: "ok".to_string(),
+      }
+      .into(),
+    );
+    let merged = coalesce_reads(vec![
+      read("/dev/ttyUSB0", "Hello ", Encoding::Utf8),
+      read("/dev/ttyUSB0", "World", Encoding::Utf8),
+      read("/dev/ttyUSB1", "abc", Encoding::Utf8),
+      ok.clone(),
+      read("/dev/ttyUSB1", "def", Encoding::Utf8),
+      read("/dev/ttyUSB1", &base64::encode(b"a"), Encoding::Base64),
+      read("/dev/ttyUSB1", &base64::encode(b"bc"), Encoding::Base64),
+    ]);
+    assert_eq!(
+      merged,
+      vec![
+        read("/dev/ttyUSB0", "Hello World", Encoding::Utf8),
+        read("/dev/ttyUSB1", "abc", Encoding::Utf8),
+        ok,
+        read("/dev/ttyUSB1", "def", Encoding::Utf8),
+        read("/dev/ttyUSB1", &base64::encode(b"abc"), Encoding::Base64),
+      ]
+    );
+
+    // Raw data is merged, frames are not
+    let data = |d: &[u8]| SubscriberMessage::Data {
+      port: "/dev/ttyUSB0".to_string(),
+      data: d.to_vec(),
+    };
+    let frame = |d: &[u8]| SubscriberMessage::Frame(Box::new(data(d)));
+    let merged = coalesce_reads(vec![data(b"a"), data(b"b"), frame(b"c\n"), frame(b"d\n")]);
+    assert_eq!(merged, vec![data(b"ab"), frame(b"c\n"), frame(b"d\n")]);
+  }
+}
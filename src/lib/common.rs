@@ -1,9 +1,24 @@
 use std::sync::mpsc::Receiver;
 
-use crate::messages::SubscriptionRequest;
+use hyper::Url;
 
-/// Convenience type for a listener
-/// that accepts weak refs of Senders of Serial Reponses
-/// This is how the manager will communicate
-/// results back to the websockets
-pub type SubscReceiver = Receiver<SubscriptionRequest>;
+use crate::messages::ManagerEvent;
+
+/// Convenience type for the receiver of events sent
+/// to the manager by the websockets, new subscriptions
+/// and serial requests
+pub type EventReceiver = Receiver<ManagerEvent>;
+
+/// Get a parameter from the query string of
+/// a request path, for example `/?token=abc`
+pub fn query_param(path: &str, name: &str) -> Option<String> {
+  Url::parse("http://localhost")
+    .and_then(|base| base.join(path))
+    .ok()
+    .and_then(|url| {
+      url
+        .query_pairs()
+        .find(|&(ref param, _)| param == name)
+        .map(|(_, value)| value.into_owned())
+    })
+}
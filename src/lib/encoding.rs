@@ -0,0 +1,167 @@
+//! Encodes data read from serial ports into strings
+//! for SerialResponse::Read messages, according to the
+//! encoding a subscriber chose when opening a port
+
+use std::str;
+
+use base64;
+
+use crate::messages::Encoding;
+
+/// Largest number of bytes a partial utf8 code point can have
+const MAX_UTF8_CARRY: usize = 3;
+
+/// Encodes chunks of port data for one subscriber and port
+///
+/// For utf8, bytes of a code point split across two reads
+/// are carried over and prepended to the next read.
+#[derive(Clone, Debug)]
+pub struct ReadEncoder {
+  /// The encoding to use
+  encoding: Encoding,
+  /// Trailing bytes of an incomplete utf8 code point
+  carry: Vec<u8>,
+}
+
+impl ReadEncoder {
+  /// Create a new encoder
+  pub fn new(encoding: Encoding) -> ReadEncoder {
+    ReadEncoder {
+      encoding: encoding,
+      carry: Vec::new(),
+    }
+  }
+
+  /// The encoding used
+  pub fn encoding(&self) -> Encoding {
+    self.encoding
+  }
+
+  /// Encode a chunk of data, returning the encoded string
+  /// and the encoding actually used.
+  ///
+  /// Only Encoding::Auto can result in a different encoding,
+  /// it yields utf8 if the chunk is valid utf8, otherwise base64
+  pub fn encode(&mut self, data: &[u8]) -> (String, Encoding) {
+    match self.encoding {
+      Encoding::Auto => match String::from_utf8(data.to_vec()) {
+        Ok(s) => (s, Encoding::Utf8),
+        Err(e) => (base64::encode(&e.into_bytes()), Encoding::Base64),
+      },
+      Encoding::Utf8 => (self.encode_utf8(data), Encoding::Utf8),
+      Encoding::Latin1 => (data.iter().map(|&b| b as char).collect(), Encoding::Latin1),
+      Encoding::Hex => (
+        data.iter().map(|b| format!("{:02x}", b)).collect(),
+        Encoding::Hex,
+      ),
+      Encoding::Base64 => (base64::encode(data), Encoding::Base64),
+    }
+  }
+
+  /// Encode data that stands on its own, such as a write
+  /// echoed to other clients, so nothing is carried over.
+  /// An incomplete trailing utf8 code point is replaced
+  /// with U+FFFD
+  pub fn encode_whole(encoding: Encoding, data: &[u8]) -> (String, Encoding) {
+    let mut encoder = ReadEncoder::new(encoding);
+    let (mut encoded, encoding) = encoder.encode(data);
+    if !encoder.carry.is_empty() {
+      encoded.push('\u{FFFD}');
+    }
+    (encoded, encoding)
+  }
+
+  /// Decode utf8, carrying over an incomplete trailing
+  /// code point and replacing invalid bytes with U+FFFD
+  fn encode_utf8(&mut self, data: &[u8]) -> String {
+    let mut bytes = std::mem::take(&mut self.carry);
+    bytes.extend_from_slice(data);
+    let mut out = String::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+      match str::from_utf8(&bytes[pos..]) {
+        Ok(s) => {
+          out.push_str(s);
+          pos = bytes.len();
+        }
+        Err(e) => {
+          let valid_end = pos + e.valid_up_to();
+          // Safe to unwrap, validated above
+          out.push_str(str::from_utf8(&bytes[pos..valid_end]).unwrap());
+          match e.error_len() {
+            Some(len) => {
+              out.push('\u{FFFD}');
+              pos = valid_end + len;
+            }
+            None => {
+              // Incomplete code point at the end, wait for more data
+              if bytes.len() - valid_end <= MAX_UTF8_CARRY {
+                self.carry = bytes[valid_end..].to_vec();
+              } else {
+                out.push('\u{FFFD}');
+              }
+              pos = bytes.len();
+            }
+          }
+        }
+      }
+    }
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_utf8_carry_over() {
+    let mut encoder = ReadEncoder::new(Encoding::Utf8);
+    // "aé€" split in the middle of both multi byte characters
+    let bytes = "aé€".as_bytes();
+    let (s1, _) = encoder.encode(&bytes[0..2]);
+    let (s2, _) = encoder.encode(&bytes[2..4]);
+    let (s3, enc) = encoder.encode(&bytes[4..]);
+    assert_eq!(s1, "a", "Partial code point should be held back");
+    assert_eq!(s2, "é", "Carried over code point should be completed");
+    assert_eq!(s3, "€", "Carried over code point should be completed");
+    assert_eq!(enc, Encoding::Utf8, "Encoding should stay utf8");
+
+    // Invalid bytes are replaced, not switched to base64
+    let (s, enc) = encoder.encode(&[b'a', 0xff, b'b']);
+    assert_eq!(s, "a\u{FFFD}b", "Invalid byte should be replaced");
+    assert_eq!(enc, Encoding::Utf8, "Encoding should stay utf8");
+
+    // Nothing is held back from data that stands on its own
+    assert_eq!(
+      ReadEncoder::encode_whole(Encoding::Utf8, &bytes[0..2]),
+      ("a\u{FFFD}".to_string(), Encoding::Utf8)
+    );
+  }
+
+  #[test]
+  fn test_other_encodings() {
+    let data = [b'H', b'i', 0xe9, 0x00];
+    assert_eq!(
+      ReadEncoder::new(Encoding::Latin1).encode(&data),
+      ("Hi\u{e9}\u{0}".to_string(), Encoding::Latin1)
+    );
+    assert_eq!(
+      ReadEncoder::new(Encoding::Hex).encode(&data),
+      ("4869e900".to_string(), Encoding::Hex)
+    );
+    assert_eq!(
+      ReadEncoder::new(Encoding::Base64).encode(b"Hello World"),
+      ("SGVsbG8gV29ybGQ=".to_string(), Encoding::Base64)
+    );
+    assert_eq!(
+      ReadEncoder::new(Encoding::Auto).encode(&data),
+      (base64::encode(&data), Encoding::Base64)
+    );
+    assert_eq!(
+      ReadEncoder::new(Encoding::Auto).encode(b"Hi"),
+      ("Hi".to_string(), Encoding::Utf8)
+    );
+  }
+}
@@ -1,4 +1,4 @@
-use crate::messages::{SerialRequest, SerialResponse};
+use crate::messages::{ManagerEvent, SerialResponse};
 
 error_chain! {
 
@@ -17,16 +17,18 @@ error_chain! {
     TomlDeserialize(::toml::de::Error);
     // Wrapped toml serialization error
     TomlSerialize(::toml::ser::Error);
-    // Wrapped sync send response error
-    SendResponse(::std::sync::mpsc::SendError<SerialResponse>);
     // Wrapped Base64 decode error
     Base64(::base64::DecodeError);
     // Wrapped sync send request error
-    SendRequest(::std::sync::mpsc::SendError<(String,SerialRequest)>);
+    SendRequest(::std::sync::mpsc::SendError<ManagerEvent>);
     // wrapped send websocket error.
     SendWsMessage(::websocket::result::WebSocketError);
-    // Wrapped ipv4 parse error
+    // Wrapped ip address parse error
     IpAddr(::std::net::AddrParseError);
+    // Wrapped openssl error
+    Openssl(::openssl::error::ErrorStack);
+    // Wrapped native tls error
+    NativeTls(::native_tls::Error);
   }
 
   errors{
@@ -70,11 +72,86 @@ error_chain! {
       description("Error writing serial port")
       display("Writing to port '{}' failed", port)
     }
+    /// Invalid serial port settings
+    InvalidPortSettings(reason:String){
+      description("Invalid serial port settings")
+      display("Invalid serial port settings, {}", reason)
+    }
+    /// Invalid framing options
+    InvalidFraming(reason:String){
+      description("Invalid framing")
+      display("Invalid framing, {}", reason)
+    }
+    /// Port already open with different settings
+    PortSettingsConflict(port:String){
+      description("Port already open with different settings")
+      display("Serial port '{}' is already open with different settings", port)
+    }
+    /// Port already open with a different write policy
+    WritePolicyConflict(port:String){
+      description("Port already open with a different write policy")
+      display("Serial port '{}' is already open with a different write policy", port)
+    }
+    /// Port already has a transaction in progress
+    TransactionInProgress(port:String){
+      description("Transaction already in progress")
+      display("A transaction is already in progress on port '{}'", port)
+    }
+    /// Bad transaction options
+    InvalidTransaction(reason:String){
+      description("Invalid transaction")
+      display("Invalid transaction, {}", reason)
+    }
+    /// Transaction reply did not end within the max length
+    TransactionReplyTooLong(port:String){
+      description("Transaction reply too long")
+      display("Transaction reply on port '{}' reached the max length without ending", port)
+    }
+    /// Bad TLS configuration
+    TlsConfig(reason:String){
+      description("Bad TLS configuration")
+      display("Bad TLS configuration, {}", reason)
+    }
+    /// Client presented a bad key
+    AuthenticationFailed{
+      description("Authentication failed")
+      display("Authentication failed, unknown key")
+    }
+    /// Client did not authenticate before sending requests
+    NotAuthenticated{
+      description("Not authenticated")
+      display("Not authenticated, send an Auth request with a valid key first")
+    }
+    /// Client is not allowed to use a port
+    PermissionDenied(port:String){
+      description("Permission denied")
+      display("Permission denied for port '{}'", port)
+    }
+    /// Malformed binary websocket frame
+    InvalidBinaryFrame(reason:String){
+      description("Invalid binary frame")
+      display("Invalid binary frame, {}", reason)
+    }
     /// Send to subscriber error
     SubscriberSendError(sub_id:String){
       description("Error sending message to subscriber")
       display("Send to subscriber '{}' failed", sub_id)
     }
+    /// Subscriber's websocket handler went away
+    SubscriberGone{
+      description("Subscriber gone")
+      display("Subscriber is no longer receiving messages")
+    }
+    /// Subscriber's queue is full and its policy is to disconnect
+    SubscriberQueueFull{
+      description("Subscriber queue full")
+      display("Subscriber queue is full, disconnecting")
+    }
+    /// Unknown queue overflow policy
+    InvalidOverflowPolicy(policy:String){
+      description("Invalid overflow policy")
+      display("Invalid overflow policy '{}', use DropOldest, DropNewest or Disconnect", policy)
+    }
   }
 }
 
@@ -0,0 +1,191 @@
+//! Splits data read from a port into frames, such
+//! as lines, so subscribers only receive complete frames
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::messages::{FrameDelimiter, Framing};
+
+/// Frames are split at this length even without a max_length,
+/// so a missing delimiter can't grow the buffer without bound
+pub const MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+/// Check framing options sent by a client
+pub fn check_framing(framing: &Framing) -> Result<()> {
+  if framing.max_length == Some(0) {
+    return Err(ErrorKind::InvalidFraming("max_length must be greater than 0".to_string()).into());
+  }
+  Ok(())
+}
+
+/// Buffers port data for one subscriber and port
+/// and splits it into frames
+#[derive(Clone, Debug)]
+pub struct Framer {
+  /// How to split frames
+  framing: Framing,
+  /// Data not yet part of a complete frame
+  buffer: Vec<u8>,
+  /// When data was last added to the buffer
+  last_data: Option<Instant>,
+}
+
+impl Framer {
+  /// Create a new framer
+  pub fn new(framing: Framing) -> Framer {
+    Framer {
+      framing: framing,
+      buffer: Vec::new(),
+      last_data: None,
+    }
+  }
+
+  /// Add data, returning any complete frames.
+  ///
+  /// Frames include their delimiter
+  pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+    self.buffer.extend_from_slice(data);
+    self.last_data = Some(Instant::now());
+    let mut frames = Vec::new();
+    while let Some(end) = self.frame_end() {
+      let rest = self.buffer.split_off(end);
+      frames.push(mem::replace(&mut self.buffer, rest));
+    }
+    frames
+  }
+
+  /// If the flush timeout has passed since data was last
+  /// received, return the buffered partial frame
+  pub fn flush_if_due(&mut self) -> Option<Vec<u8>> {
+    let timeout = match self.framing.flush_timeout_ms {
+      Some(ms) => Duration::from_millis(ms),
+      None => return None,
+    };
+    match self.last_data {
+      Some(last) if !self.buffer.is_empty() && last.elapsed() >= timeout => {
+        Some(mem::take(&mut self.buffer))
+      }
+      _ => None,
+    }
+  }
+
+  /// Is a partial frame waiting to be flushed
+  pub fn has_pending_flush(&self) -> bool {
+    self.framing.flush_timeout_ms.is_some() && !self.buffer.is_empty()
+  }
+
+  /// Longest frame to buffer, never 0
+  fn max_length(&self) -> usize {
+    self
+      .framing
+      .max_length
+      .unwrap_or(MAX_FRAME_LENGTH)
+      .clamp(1, MAX_FRAME_LENGTH)
+  }
+
+  /// Find the end of the first complete frame in the buffer
+  fn frame_end(&self) -> Option<usize> {
+    let delimited = match self.framing.delimiter {
+      FrameDelimiter::None => None,
+      FrameDelimiter::Newline => self.buffer.iter().position(|&b| b == b'\n').map(|i| i + 1),
+      FrameDelimiter::CrLf => self
+        .buffer
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|i| i + 2),
+      FrameDelimiter::Byte(d) => self.buffer.iter().position(|&b| b == d).map(|i| i + 1),
+    };
+    let max = self.max_length();
+    match delimited {
+      Some(end) => Some(end.min(max)),
+      None if self.buffer.len() >= max => Some(max),
+      None => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_delimiters() {
+    let mut framer = Framer::new(Framing {
+      delimiter: FrameDelimiter::Newline,
+      ..Framing::default()
+    });
+    assert_eq!(framer.push(b"G1 X1"), Vec::<Vec<u8>>::new());
+    assert_eq!(
+      framer.push(b"0\nG1 Y2\nG1"),
+      vec![b"G1 X10\n".to_vec(), b"G1 Y2\n".to_vec()]
+    );
+
+    let mut framer = Framer::new(Framing {
+      delimiter: FrameDelimiter::CrLf,
+      ..Framing::default()
+    });
+    assert_eq!(framer.push(b"OK\r"), Vec::<Vec<u8>>::new());
+    assert_eq!(framer.push(b"\nOK\n"), vec![b"OK\r\n".to_vec()]);
+
+    let mut framer = Framer::new(Framing {
+      delimiter: FrameDelimiter::Byte(b';'),
+      ..Framing::default()
+    });
+    assert_eq!(framer.push(b"a;b;c"), vec![b"a;".to_vec(), b"b;".to_vec()]);
+  }
+
+  #[test]
+  fn test_max_length_and_flush() {
+    let mut framer = Framer::new(Framing {
+      delimiter: FrameDelimiter::None,
+      max_length: Some(4),
+      flush_timeout_ms: Some(0),
+    });
+    assert_eq!(
+      framer.push(b"abcdefghij"),
+      vec![b"abcd".to_vec(), b"efgh".to_vec()]
+    );
+    assert!(framer.has_pending_flush());
+    assert_eq!(framer.flush_if_due(), Some(b"ij".to_vec()));
+    assert_eq!(framer.flush_if_due(), None, "Nothing left to flush");
+    assert!(!framer.has_pending_flush());
+
+    // Long lines are split at max length
+    let mut framer = Framer::new(Framing {
+      delimiter: FrameDelimiter::Newline,
+      max_length: Some(3),
+      flush_timeout_ms: None,
+    });
+    assert_eq!(framer.push(b"abcde\n"), vec![b"abc".to_vec(), b"de\n".to_vec()]);
+    framer.push(b"xy");
+    assert!(!framer.has_pending_flush());
+    assert_eq!(framer.flush_if_due(), None, "No flush without timeout");
+  }
+
+  #[test]
+  fn test_length_limits() {
+    let zero = Framing {
+      delimiter: FrameDelimiter::None,
+      max_length: Some(0),
+      flush_timeout_ms: None,
+    };
+    assert!(check_framing(&zero).is_err(), "max_length 0 should be rejected");
+    assert!(check_framing(&Framing::default()).is_ok());
+
+    // Even if it gets past the check, no empty frames are made
+    let mut framer = Framer::new(zero);
+    assert_eq!(framer.push(b"ab"), vec![b"a".to_vec(), b"b".to_vec()]);
+
+    // Without a delimiter or max_length the buffer is still capped
+    let mut framer = Framer::new(Framing {
+      delimiter: FrameDelimiter::None,
+      max_length: None,
+      flush_timeout_ms: None,
+    });
+    let frames = framer.push(&vec![0; MAX_FRAME_LENGTH * 2 + 1]);
+    assert_eq!(frames, vec![vec![0; MAX_FRAME_LENGTH]; 2]);
+    assert_eq!(framer.buffer.len(), 1);
+  }
+}
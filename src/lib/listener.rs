@@ -0,0 +1,173 @@
+//! Accepts websocket connections, doing the TLS handshake
+//! and reading the upgrade request in a thread per connection,
+//! so a client that stalls partway can't hold up the listener
+
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use hyper::method::Method;
+use hyper::uri::RequestUri;
+use websocket::server::InvalidConnection;
+use websocket::stream::sync::Stream;
+use websocket::sync::server::upgrade::{Buffer, IntoWs, Upgrade};
+use websocket::sync::server::Request;
+
+use crate::ws_connection::MESSAGE_TIMEOUT_MS;
+
+/// A websocket upgrade, or the stream and request
+/// of a connection that didn't ask for one
+pub type Connection<S> = ::std::result::Result<Upgrade<S>, InvalidConnection<S, Buffer>>;
+
+/// Accept connections till the listener fails, handing each
+/// to `handle` on its own thread once `handshake` has set up
+/// the stream and the upgrade request has been read
+///
+/// Clients get MESSAGE_TIMEOUT_MS to finish the handshake
+/// and send their request
+pub fn accept_connections<S, H, F>(listener: TcpListener, handshake: H, handle: F)
+where
+  S: Stream + Send + 'static,
+  H: Fn(TcpStream) -> io::Result<S> + Send + Sync + 'static,
+  F: Fn(Connection<S>) + Send + Sync + 'static,
+{
+  let handshake = Arc::new(handshake);
+  let handle = Arc::new(handle);
+  for stream in listener.incoming() {
+    let stream = match stream {
+      Ok(stream) => stream,
+      Err(e) => {
+        debug!("Accepting connection failed, cause '{}'", e);
+        continue;
+      }
+    };
+    let handshake = handshake.clone();
+    let handle = handle.clone();
+    thread::spawn(move || {
+      let timeout = Some(Duration::from_millis(MESSAGE_TIMEOUT_MS));
+      if let Err(e) = stream.set_read_timeout(timeout) {
+        debug!("Setting handshake timeout failed, cause '{}'", e);
+        return;
+      }
+      match handshake(stream) {
+        Ok(stream) => handle(upgrade(stream)),
+        Err(e) => debug!("Dropping connection, handshake failed, cause '{}'", e),
+      }
+    });
+  }
+}
+
+/// Read the upgrade request from a stream
+fn upgrade<S>(stream: S) -> Connection<S>
+where
+  S: Stream,
+{
+  stream
+    .into_ws()
+    .map_err(|(stream, parsed, buffer, error)| InvalidConnection {
+      stream: Some(stream),
+      parsed: parsed,
+      buffer: buffer,
+      error: error,
+    })
+}
+
+/// Answer a plain HTTP request on a shared port,
+/// sending the page for `/` and a 404 for anything else
+pub fn serve_page<S>(mut stream: S, request: &Request, page: &str)
+where
+  S: Stream,
+{
+  let path = request_path(&request.subject.1);
+  let (status, content_type, body) = match (&request.subject.0, path) {
+    (&Method::Get, "/") | (&Method::Get, "/index.html") => {
+      ("200 OK", "text/html; charset=utf-8", page)
+    }
+    _ => ("404 Not Found", "text/plain", "Not Found"),
+  };
+  let response = format!(
+    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    content_type,
+    body.len(),
+    body
+  );
+  stream
+    .write_all(response.as_bytes())
+    .and_then(|_| stream.flush())
+    .unwrap_or_else(|e| debug!("Sending page failed, cause '{}'", e));
+}
+
+/// The path of a request, without the query string
+pub fn request_path(uri: &RequestUri) -> &str {
+  match *uri {
+    RequestUri::AbsolutePath(ref path) => path.split('?').next().unwrap_or(""),
+    _ => "",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+
+  use websocket::{ClientBuilder, Message, OwnedMessage};
+
+  use super::*;
+  use crate::tls::tests::self_signed_acceptor;
+
+  #[test]
+  fn test_tls_connections() {
+    let (acceptor, connector) = self_signed_acceptor();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+      accept_connections(
+        listener,
+        move |stream| {
+          acceptor
+            .accept(stream)
+            .map_err(|e| io::Error::other(e.to_string()))
+        },
+        |connection| match connection {
+          Ok(upgrade) => {
+            let mut client = upgrade.accept().expect("Accepting upgrade failed");
+            client.send_message(&Message::text("hello")).unwrap();
+          }
+          Err(InvalidConnection {
+            stream: Some(stream),
+            parsed: Some(request),
+            ..
+          }) => serve_page(stream, &request, "page"),
+          Err(_) => {}
+        },
+      )
+    });
+
+    // A client that never starts its handshake
+    // doesn't hold up the others
+    let _stalled = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    let mut client = ClientBuilder::new(&format!("wss://localhost:{}", port))
+      .unwrap()
+      .connect_secure(Some(connector.clone()))
+      .expect("Websocket upgrade failed");
+    match client.recv_message().expect("Reading message failed") {
+      OwnedMessage::Text(text) => assert_eq!(text, "hello"),
+      message => panic!("Unexpected message {:?}", message),
+    }
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stream = connector
+      .connect("localhost", stream)
+      .expect("Client handshake failed");
+    stream
+      .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+      .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("\r\n\r\npage"), "{}", response);
+  }
+}
@@ -1,20 +1,33 @@
 //! Manages serial port state and communication with clients,
 //! and handling requests / responses
 
-use std::collections::HashSet;
-use std::sync::mpsc::{Receiver, TryRecvError};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc::{RecvTimeoutError, Sender};
 use std::thread;
+use std::time::Duration;
 
 use base64;
 
+use crate::acl::AccessControl;
 use crate::common::*;
-use crate::dynamic_sleep::DynamicSleep;
+use crate::encoding::ReadEncoder;
 use crate::errors::*;
+use crate::framing::check_framing;
 use crate::messages::*;
 use crate::port_manager::*;
+use crate::port_watcher::*;
+use crate::reconnect_manager::*;
 use crate::sub_manager::*;
+use crate::transaction::*;
 use crate::writelock_manager::*;
 
+/// How often to check for timeouts while
+/// transactions or partial frames are pending
+pub const TIMEOUT_CHECK_INTERVAL_MS: u64 = 5;
+/// Max events handled before doing timed work
+pub const MAX_EVENTS_PER_WAKEUP: usize = 100;
+
 /// Serial port management module supporting one
 /// writer and multiple readers
 ///
@@ -24,147 +37,270 @@ use crate::writelock_manager::*;
 /// The Manager takes actions in response to
 /// [SerialRequest::*](../messages/index.html) messages sent on
 /// on its receiver
+///
+/// It sleeps until an event arrives, or until it's time
+/// to do timed work such as rescanning ports, so requests
+/// and data read by port readers are handled as soon as
+/// they arrive
 pub struct Manager {
+  /// Which clients may use which ports
+  acl: AccessControl,
   /// Manage write lock status
   writelock_manager: WriteLockManager,
   /// Manage ports
   port_manager: PortManager,
   /// Manage subscriptions
   sub_manager: SubscriptionManager,
-  /// Receiver for serial requests
-  receiver: Receiver<(String, SerialRequest)>,
-  /// Receiver for response subscription requests
-  subsc_receiver: SubscReceiver,
+  /// Watch for ports being added or removed
+  port_watcher: PortWatcher,
+  /// Track lost sticky ports to reconnect
+  reconnect_manager: ReconnectManager,
+  /// Pending transactions by port name
+  transactions: HashMap<String, Transaction>,
+  /// Writes to SharedAtomic ports held back till the
+  /// transaction in progress finishes, by port name
+  held_requests: HashMap<String, VecDeque<(String, RequestEnvelope)>>,
+  /// Receiver for subscriptions, serial requests and port data
+  receiver: EventReceiver,
+  /// Id of the request currently being handled,
+  /// attached to any replies
+  request_id: Option<String>,
 }
 
 impl Manager {
   ///Constructor
+  ///
+  /// Port readers send their data to events, which
+  /// should be a sender for receiver
   pub fn new(
-    receiver: Receiver<(String, SerialRequest)>,
-    subsc_receiver: SubscReceiver,
+    receiver: EventReceiver,
+    events: Sender<ManagerEvent>,
+    acl: AccessControl,
   ) -> Manager {
     Manager {
+      acl: acl,
       writelock_manager: WriteLockManager::new(),
-      port_manager: PortManager::new(),
+      port_manager: PortManager::new(events),
       sub_manager: SubscriptionManager::new(),
+      port_watcher: PortWatcher::new(),
+      reconnect_manager: ReconnectManager::new(),
+      transactions: HashMap::new(),
+      held_requests: HashMap::new(),
       receiver: receiver,
-      subsc_receiver: subsc_receiver,
+      request_id: None,
     }
   }
 
   ///Spawn an instance in a new thread.
   pub fn spawn(
-    receiver: Receiver<(String, SerialRequest)>,
-    subsc_receiver: SubscReceiver,
+    receiver: EventReceiver,
+    events: Sender<ManagerEvent>,
+    acl: AccessControl,
   ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-      Manager::new(receiver, subsc_receiver).run();
+      Manager::new(receiver, events, acl).run();
     })
   }
 
   /// Main loop
   fn run(&mut self) {
-    // Bad ports we couldn't read from
-    // A set of SerialResponse::Errors built from
-    // from the serial port read/write error responses
-    let mut bad_ports = HashSet::<String>::new();
-
-    // Check about 30 times a second
-    let mut dynamic_sleep = DynamicSleep::new("manager");
-
     loop {
-      // Sleep for a little bit to avoid pegging cpu
-      dynamic_sleep.sleep();
-
-      // Handle serial operation requests
-      match self.receiver.try_recv() {
-        Err(e) => {
-          match e {
-            TryRecvError::Empty => {
-              // nothing to do
-            }
-            TryRecvError::Disconnected => {
-              // Remote end hung up, time to shutdown
-              info!("Shutting down SerialPortManager");
-              break;
+      // Sleep until an event arrives or there is timed work to do
+      match self.receiver.recv_timeout(self.next_wakeup()) {
+        Ok(event) => {
+          self.handle_event(event);
+          // Handle anything else that queued up meanwhile
+          for _ in 1..MAX_EVENTS_PER_WAKEUP {
+            match self.receiver.try_recv() {
+              Ok(event) => self.handle_event(event),
+              Err(_) => break,
             }
           }
         }
-        Ok(req) => self.handle_serial_request(&req.0, req.1),
-      }
-
-      // Check for new data on each port
-      for (port_name, result) in self.port_manager.read_all_ports() {
-        match result {
-          Ok(data) => {
-            let response = match String::from_utf8(data) {
-              // We need to send as binary
-              Err(e) => SerialResponse::Read {
-                port: port_name.to_string(),
-                data: base64::encode(&e.into_bytes()),
-                base64: Some(true),
-              },
-              Ok(s) => SerialResponse::Read {
-                port: port_name.to_string(),
-                data: s,
-                base64: Some(false),
-              },
-            };
-            self.broadcast_message_for_port(&port_name, response);
-          }
-          // Send data reads
-          Err(e) => {
-            warn!("Error reading port!");
-            warn!("{}", e);
-            bad_ports.insert(port_name);
-          }
+        Err(RecvTimeoutError::Timeout) => {}
+        Err(RecvTimeoutError::Disconnected) => {
+          // Remote end hung up, time to shutdown
+          info!("Shutting down SerialPortManager");
+          break;
         }
       }
 
-      //Handle write requests
-      let mut recv_count = 0;
-      while recv_count < 50 {
-        recv_count += 1;
-        match self.subsc_receiver.try_recv() {
-          Ok(sub_request) => self.sub_manager.add_subscription(sub_request),
-          Err(e) => {
-            match e {
-              TryRecvError::Disconnected => {
-                // Does this mean all senders have disconnected?
-                // Or just one?
-                debug!("Got disconnected when trying to get serial request");
-              }
-              TryRecvError::Empty => break,
-            }
-          }
-        }
+      // Finish transactions that timed out
+      self.expire_transactions();
+
+      // Release write locks whose lease ran out
+      self.expire_write_locks();
+
+      // Send partial frames that timed out
+      let bad_subs = self.sub_manager.flush_partial_frames();
+      self.cleanup_bad_subs(bad_subs);
+
+      // Let everyone know about ports coming and going
+      self.check_for_port_changes();
+
+      // Try and bring back lost sticky ports
+      self.reconnect_lost_ports();
+
+      // Hand over locks released by timed work
+      self.grant_waiting_locks();
+      self.release_held_requests();
+      self.broadcast_lock_changes();
+    }
+  }
+
+  /// How long to wait for events before there is timed work to do
+  fn next_wakeup(&self) -> Duration {
+    if !self.transactions.is_empty() || self.sub_manager.has_pending_flush() {
+      return Duration::from_millis(TIMEOUT_CHECK_INTERVAL_MS);
+    }
+    let until_scan = self.port_watcher.time_until_scan();
+    [
+      self.reconnect_manager.time_until_due(),
+      self.writelock_manager.time_until_expiry(),
+    ]
+    .iter()
+    .filter_map(|until| *until)
+    .fold(until_scan, cmp::min)
+  }
+
+  /// Handles an event sent to the manager
+  fn handle_event(&mut self, event: ManagerEvent) {
+    match event {
+      ManagerEvent::Subscribe(sub_request) => self.sub_manager.add_subscription(sub_request),
+      ManagerEvent::Request(sub_id, envelope) => self.handle_serial_request(&sub_id, envelope),
+      ManagerEvent::PortData(port_name, result) => self.handle_port_read(port_name, result),
+    }
+    // Locks released while handling the event go
+    // straight to whoever is waiting
+    self.grant_waiting_locks();
+    self.release_held_requests();
+    self.broadcast_lock_changes();
+  }
+
+  /// Handles what a port reader read, cleaning up
+  /// the port if reading failed
+  fn handle_port_read(&mut self, port_name: String, result: PortReadResult) {
+    // Data may still be queued from a reader
+    // whose port was closed since
+    if !self.port_manager.is_port_open(&port_name) {
+      return;
+    }
+    match result {
+      Ok(data) => self.handle_port_data(&port_name, data),
+      Err(e) => {
+        warn!("Error reading port {}, cause '{}'", port_name, e);
+        let mut bad_ports = HashSet::new();
+        bad_ports.insert(port_name);
+        self.cleanup_bad_ports(&bad_ports);
       }
+    }
+  }
 
-      // Cleanup bad serial ports that failed read or write
-      // We remove them from everything before
-      self.cleanup_bad_ports(&bad_ports);
-      bad_ports.clear();
+  /// Handles SerialRequest sent by the channel, holding
+  /// back writes that have to wait for a transaction
+  fn handle_serial_request(&mut self, sub_id: &String, envelope: RequestEnvelope) {
+    match self.port_to_wait_for(sub_id, &envelope.request) {
+      Some(port_name) => self
+        .held_requests
+        .entry(port_name)
+        .or_default()
+        .push_back((sub_id.to_string(), envelope)),
+      None => self.dispatch_request(sub_id, envelope),
+    }
+  }
+
+  /// The port a request has to wait for, if it writes to a
+  /// SharedAtomic port while another client's transaction is
+  /// in progress, or earlier writes are still waiting
+  fn port_to_wait_for(&self, sub_id: &String, request: &SerialRequest) -> Option<String> {
+    let port_name = match *request {
+      SerialRequest::Write { ref port, .. }
+      | SerialRequest::WriteBytes { ref port, .. }
+      | SerialRequest::Transact { ref port, .. } => port,
+      _ => return None,
+    };
+    if self.writelock_manager.policy(port_name) != WritePolicy::SharedAtomic {
+      return None;
+    }
+    let busy = self
+      .transactions
+      .get(port_name)
+      .map(|t| t.sub_id != *sub_id)
+      .unwrap_or(false);
+    match busy || self.held_requests.contains_key(port_name) {
+      true => Some(port_name.to_string()),
+      false => None,
     }
   }
 
-  /// Handles and dispatches SerialRequest sent by
-  /// the channel
-  fn handle_serial_request(&mut self, sub_id: &String, msg: SerialRequest) {
-    let response = match msg {
-      SerialRequest::Open { port } => self.handle_open_port(sub_id, port),
-      SerialRequest::WriteLock { port } => self.handle_write_lock(sub_id, port),
+  /// Dispatches a SerialRequest to its handler
+  fn dispatch_request(&mut self, sub_id: &String, envelope: RequestEnvelope) {
+    self.request_id = envelope.id;
+    let response = match envelope.request {
+      // Auth is handled by the connection before
+      // any requests are passed on to the manager
+      SerialRequest::Auth { .. } => Err(ErrorKind::UnknownRequest.into()),
+      SerialRequest::Open {
+        port,
+        settings,
+        sticky,
+        encoding,
+        framing,
+        echo,
+        write_policy,
+      } => {
+        let options = PortOptions {
+          sticky: sticky.unwrap_or(false),
+          encoding: encoding.unwrap_or_default(),
+          framing: framing,
+          echo: echo.unwrap_or(false),
+        };
+        self.handle_open_port(sub_id, port, settings, options, write_policy)
+      }
+      SerialRequest::WriteLock {
+        port,
+        lease_ms,
+        wait,
+      } => self.handle_write_lock(sub_id, port, lease_ms, wait.unwrap_or(false)),
+      SerialRequest::ForceWriteLock { port, lease_ms } => {
+        self.handle_force_write_lock(sub_id, port, lease_ms)
+      }
       SerialRequest::ReleaseWriteLock { port } => self.handle_release_write_lock(sub_id, port),
       SerialRequest::Write { port, data, base64 } => {
         self.handle_write_port(sub_id, port, data, base64.unwrap_or(false))
       }
+      SerialRequest::WriteBytes { port, data } => self.handle_write_bytes(sub_id, port, data),
+      SerialRequest::Transact {
+        port,
+        data,
+        base64,
+        terminator,
+        max_bytes,
+        timeout_ms,
+      } => self.handle_transact(
+        sub_id,
+        port,
+        data,
+        base64.unwrap_or(false),
+        terminator,
+        max_bytes,
+        timeout_ms,
+      ),
+      SerialRequest::Configure { port, settings } => {
+        self.handle_configure_port(sub_id, port, settings)
+      }
       SerialRequest::Close { port } => self.handle_close_port(sub_id, port),
-      SerialRequest::List {} => self.handle_list_ports(sub_id),
+      SerialRequest::List { names_only } => {
+        self.handle_list_ports(sub_id, names_only.unwrap_or(false))
+      }
+      SerialRequest::Status { port } => self.handle_status(sub_id, port),
     };
     if let Err(e) = response {
       warn!("Error '{}' occured handling serial request message", e);
       // Send error?
-      self.send_message(&sub_id, to_serial_response_error(e));
+      self.reply(sub_id, to_serial_response_error(e));
     }
+    self.request_id = None;
   }
 
   /// Handle write port requests
@@ -175,27 +311,123 @@ impl Manager {
     data: String,
     base_64: bool,
   ) -> Result<()> {
-    self.check_sub_id(&sub_id)?;
-    self.check_owns_writelock(&port_name, &sub_id)?;
-    match base_64 {
-      true => base64::decode(&data)
-        .map_err(|e| ErrorKind::Base64(e).into())
-        .and_then(|d| self.port_manager.write_port(&port_name, &d))
-        .map(|_| self.send_message(&sub_id, SerialResponse::Wrote { port: port_name })),
-      false => self
-        .port_manager
-        .write_port(&port_name, data.as_bytes())
-        .map(|_| self.send_message(&sub_id, SerialResponse::Wrote { port: port_name })),
+    let data = decode_data(data, base_64)?;
+    self.handle_write_bytes(sub_id, port_name, data)
+  }
+
+  /// Handle writes of raw bytes, from binary write frames
+  fn handle_write_bytes(&mut self, sub_id: &String, port_name: String, data: Vec<u8>) -> Result<()> {
+    self.check_sub_id(sub_id)?;
+    self.check_can_write(&port_name, sub_id)?;
+    self.port_manager.write_port(&port_name, &data)?;
+    self.announce_write(&port_name, sub_id, &data);
+    self.reply(sub_id, SerialResponse::Wrote { port: port_name });
+    Ok(())
+  }
+
+  /// Handle transact requests
+  #[allow(clippy::too_many_arguments)]
+  fn handle_transact(
+    &mut self,
+    sub_id: &String,
+    port_name: String,
+    data: String,
+    base_64: bool,
+    terminator: Option<String>,
+    max_bytes: Option<usize>,
+    timeout_ms: Option<u64>,
+  ) -> Result<()> {
+    self.check_sub_id(sub_id)?;
+    self.check_can_write(&port_name, sub_id)?;
+    if self.transactions.contains_key(&port_name) {
+      return Err(ErrorKind::TransactionInProgress(port_name).into());
     }
+    check_transaction(max_bytes)?;
+    let data = decode_data(data, base_64)?;
+    self.port_manager.write_port(&port_name, &data)?;
+    self.announce_write(&port_name, sub_id, &data);
+    let transaction = Transaction::new(
+      sub_id,
+      self.request_id.clone(),
+      terminator.map(|t| t.into_bytes()),
+      max_bytes,
+      timeout_ms,
+    );
+    self.transactions.insert(port_name, transaction);
+    Ok(())
+  }
+
+  /// Handle write lock requests, which also renew
+  /// the lease on a lock the sub already holds
+  ///
+  /// If wait is set and someone else holds the lock,
+  /// the sub is put in line for it instead of failing
+  fn handle_write_lock(
+    &mut self,
+    sub_id: &String,
+    port_name: String,
+    lease_ms: Option<u64>,
+    wait: bool,
+  ) -> Result<()> {
+    self.check_sub_id(sub_id)?;
+    self
+      .acl
+      .check_write(&port_name, self.sub_manager.identity(sub_id)?)?;
+    let lease = lease_ms.map(Duration::from_millis);
+    if wait
+      && self
+        .writelock_manager
+        .is_port_locked_by_someone_else(&port_name, sub_id)
+    {
+      let waiter = LockWaiter {
+        sub_id: sub_id.to_string(),
+        lease: lease,
+        request_id: self.request_id.clone(),
+      };
+      let position = self.writelock_manager.wait_for_lock(&port_name, waiter);
+      let resp = SerialResponse::WriteLockQueued {
+        port: port_name,
+        position: position,
+      };
+      self.reply(sub_id, resp);
+      return Ok(());
+    }
+    self
+      .writelock_manager
+      .lock_port(&port_name, sub_id, lease)
+      .map(|_| self.reply(sub_id, SerialResponse::WriteLocked { port: port_name }))
   }
 
-  /// Handle write lock requests
-  fn handle_write_lock(&mut self, sub_id: &String, port_name: String) -> Result<()> {
-    self.check_sub_id(&sub_id)?;
+  /// Handle force write lock requests, taking the
+  /// lock from whoever holds it
+  fn handle_force_write_lock(
+    &mut self,
+    sub_id: &String,
+    port_name: String,
+    lease_ms: Option<u64>,
+  ) -> Result<()> {
+    self.check_sub_id(sub_id)?;
     self
+      .acl
+      .check_admin(&port_name, self.sub_manager.identity(sub_id)?)?;
+    let lease = lease_ms.map(Duration::from_millis);
+    // No need to wait any more
+    self.writelock_manager.stop_waiting(&port_name, sub_id);
+    let previous = self
       .writelock_manager
-      .lock_port(&port_name, &sub_id)
-      .map(|_| self.send_message(&sub_id, SerialResponse::WriteLocked { port: port_name }))
+      .force_lock_port(&port_name, sub_id, lease);
+    if let Some(previous) = previous {
+      info!(
+        "Write lock on '{}' taken from '{}' by '{}'",
+        port_name, previous, sub_id
+      );
+      let resp = SerialResponse::WriteLockTaken {
+        port: port_name.clone(),
+      };
+      self.send_message(&previous, resp);
+    }
+    self.reply(sub_id, SerialResponse::WriteLocked { port: port_name });
+    Ok(())
   }
 
   /// Handle write requests
@@ -207,18 +439,19 @@ impl Manager {
     self.check_sub_id(sub_id)?;
     match port_name {
       None => {
-        self.writelock_manager.unlock_all_ports_for_sub(&sub_id);
-        Ok(self.send_message(
-          &sub_id,
+        self.writelock_manager.unlock_all_ports_for_sub(sub_id);
+        self.reply(
+          sub_id,
           SerialResponse::WriteLockReleased { port: port_name },
-        ))
+        );
+        Ok(())
       }
       Some(port_name) => self
         .writelock_manager
-        .unlock_port(&port_name, &sub_id)
+        .unlock_port(&port_name, sub_id)
         .map(|_| {
-          self.send_message(
-            &sub_id,
+          self.reply(
+            sub_id,
             SerialResponse::WriteLockReleased {
               port: Some(port_name),
             },
@@ -228,33 +461,138 @@ impl Manager {
   }
 
   /// Handle open port requests
-  fn handle_open_port(&mut self, sub_id: &String, port_name: String) -> Result<()> {
-    self.check_sub_id(&sub_id)?;
-    self.port_manager.open_port(&port_name)?;
+  fn handle_open_port(
+    &mut self,
+    sub_id: &String,
+    port_name: String,
+    settings: Option<PortSettings>,
+    options: PortOptions,
+    write_policy: Option<WritePolicy>,
+  ) -> Result<()> {
+    self.check_sub_id(sub_id)?;
     self
-      .sub_manager
-      .add_port(&sub_id, &port_name)
-      .map(|_| self.send_message(&sub_id, SerialResponse::Opened { port: port_name }))
+      .acl
+      .check_read(&port_name, self.sub_manager.identity(sub_id)?)?;
+    if let Some(ref framing) = options.framing {
+      check_framing(framing)?;
+    }
+    // The first client to open a port sets its write policy
+    let was_open = self.port_manager.is_port_open(&port_name);
+    if let Some(policy) = write_policy {
+      if was_open && policy != self.writelock_manager.policy(&port_name) {
+        return Err(ErrorKind::WritePolicyConflict(port_name).into());
+      }
+    }
+    let settings = self
+      .port_manager
+      .open_port(&port_name, settings.as_ref())?;
+    if !was_open {
+      self
+        .writelock_manager
+        .set_policy(&port_name, write_policy.unwrap_or_default());
+    }
+    self.sub_manager.add_port(sub_id, &port_name, options).map(|_| {
+      self.reply(
+        sub_id,
+        SerialResponse::Opened {
+          port: port_name,
+          settings: settings,
+        },
+      )
+    })
+  }
+
+  /// Handle configure port requests
+  fn handle_configure_port(
+    &mut self,
+    sub_id: &String,
+    port_name: String,
+    settings: PortSettings,
+  ) -> Result<()> {
+    self.check_sub_id(sub_id)?;
+    self.check_owns_writelock(&port_name, sub_id)?;
+    let settings = self.port_manager.configure_port(&port_name, &settings)?;
+    let resp = SerialResponse::Configured {
+      port: port_name.clone(),
+      settings: settings,
+    };
+    self.broadcast_message_for_port_except(&port_name, Some(sub_id), resp.clone());
+    self.reply(sub_id, resp);
+    Ok(())
   }
 
   /// Handle list ports request
-  fn handle_list_ports(&mut self, sub_id: &String) -> Result<()> {
-    self.check_sub_id(&sub_id)?;
-    let port_names: Result<Vec<String>> = self
-      .port_manager
-      .list_ports()
-      .map(|v| v.iter().map(|v| v.port_name.clone()).collect());
-    let resp = port_names.map(|pns| SerialResponse::List { ports: pns });
-    self.send_message(
-      &sub_id,
-      resp.unwrap_or(SerialResponse::Error {
-        display: "".to_string(),
-        description: "".to_string(),
-      }),
+  fn handle_list_ports(&mut self, sub_id: &String, names_only: bool) -> Result<()> {
+    self.check_sub_id(sub_id)?;
+    let port_infos = self.port_manager.list_ports()?;
+    let port_names = port_infos.iter().map(|i| i.port_name.clone()).collect();
+    let info = match names_only {
+      true => None,
+      false => Some(port_infos.into_iter().map(PortInfo::from).collect()),
+    };
+    self.reply(
+      sub_id,
+      SerialResponse::List {
+        ports: port_names,
+        info: info,
+      },
     );
     Ok(())
   }
 
+  /// Handle status requests
+  fn handle_status(&mut self, sub_id: &String, port_name: Option<String>) -> Result<()> {
+    self.check_sub_id(sub_id)?;
+    if let Some(ref port_name) = port_name {
+      if !self.port_manager.is_port_open(port_name) {
+        return Err(ErrorKind::OpenPortNotFound(port_name.to_string()).into());
+      }
+    }
+    let identity = self.sub_manager.identity(sub_id)?;
+    let mut port_names: Vec<String> = self
+      .port_manager
+      .open_ports()
+      .into_iter()
+      .filter(|p| port_name.as_ref().map(|pn| pn == p).unwrap_or(true))
+      .filter(|p| self.acl.check_read(p, identity).is_ok())
+      .collect();
+    port_names.sort();
+    let ports = port_names
+      .into_iter()
+      .filter_map(|p| self.port_status(sub_id, p).ok())
+      .collect();
+    self.reply(sub_id, SerialResponse::Status { ports: ports });
+    Ok(())
+  }
+
+  /// State of an open port, as seen by sub_id
+  fn port_status(&self, sub_id: &String, port_name: String) -> Result<PortStatus> {
+    let settings = self.port_manager.port_settings(&port_name)?;
+    let holder = self.writelock_manager.lock_holder(&port_name);
+    Ok(PortStatus {
+      settings: settings,
+      write_policy: self.writelock_manager.policy(&port_name),
+      subscribers: self.sub_manager.subscribers_for_port(&port_name).len(),
+      locked_by_you: holder.as_ref() == Some(sub_id),
+      lock_holder: holder.map(|h| self.client_label(&h)),
+      lock_age_ms: self
+        .writelock_manager
+        .lock_age(&port_name)
+        .map(|age| age.as_secs() * 1000 + u64::from(age.subsec_millis())),
+      lock_waiters: self.writelock_manager.waiters(&port_name).len(),
+      port: port_name,
+    })
+  }
+
+  /// Name other clients know a client by, the name of its
+  /// api key if it authenticated, otherwise its sub id
+  fn client_label(&self, sub_id: &String) -> String {
+    match self.sub_manager.identity(sub_id) {
+      Ok(Some(identity)) => identity.name.clone(),
+      _ => sub_id.to_string(),
+    }
+  }
+
   /// Handle close port requests
   fn handle_close_port(&mut self, sub_id: &String, port_name: Option<String>) -> Result<()> {
     match port_name {
@@ -265,71 +603,377 @@ impl Manager {
 
   /// Handle closing a signle port for a sub
   fn handle_close_port_for_sub(&mut self, sub_id: &String, port_name: String) -> Result<()> {
-    self.sub_manager.remove_port(&sub_id, &port_name)?;
+    self.sub_manager.remove_port(sub_id, &port_name)?;
     self
       .writelock_manager
-      .unlock_port_if_locked_by(&port_name, &sub_id);
-    // self.cleanup_ports_with_no_subs();
+      .unlock_port_if_locked_by(&port_name, sub_id);
+    self.writelock_manager.stop_waiting(&port_name, sub_id);
+    self.reconnect_manager.remove_sub(&port_name, sub_id);
+    self.drop_transactions_for_sub(sub_id, Some(&port_name));
+    self.close_ports_with_no_subs();
     let close_resp = SerialResponse::Closed {
       port: port_name.clone(),
     };
-    self.send_message(&sub_id, close_resp);
+    self.reply(sub_id, close_resp);
     Ok(())
   }
 
   /// Handle closing all ports for sub
   fn handle_close_all_ports_for_sub(&mut self, sub_id: &String) -> Result<()> {
-    self.sub_manager.clear_ports(Some(&sub_id));
+    self.sub_manager.clear_ports(Some(sub_id));
     self.writelock_manager.unlock_all_ports_for_sub(sub_id);
+    self.reconnect_manager.remove_sub_from_all(sub_id);
+    self.drop_transactions_for_sub(sub_id, None);
 
-    // Close ports with no subscribers
+    // Close ports with no subscribers, and let them know
+    for port_name in self.close_ports_with_no_subs() {
+      let close_resp = SerialResponse::Closed { port: port_name };
+      self.reply(sub_id, close_resp);
+    }
+    Ok(())
+  }
+
+  /// Close open ports nobody subscribes to anymore,
+  /// returning the names of the ports closed
+  fn close_ports_with_no_subs(&mut self) -> Vec<String> {
     let open_ports = self.port_manager.open_ports();
     let subscribed_ports = self.sub_manager.subscribed_ports();
-    let ports_with_no_subs = open_ports.difference(&subscribed_ports);
-
-    // For each open port that isn't subscribed,
-    for port_to_close in ports_with_no_subs {
-      // close it, REDUNDANT?
-      self.port_manager.close_port(&port_to_close);
-      // remove the write lock, REDUNDANT?
-      self.writelock_manager.clear_lock(&port_to_close);
-      // Let them know its closed
-      let close_resp = SerialResponse::Closed {
-        port: port_to_close.clone(),
+    let mut ports_with_no_subs: Vec<String> =
+      open_ports.difference(&subscribed_ports).cloned().collect();
+    ports_with_no_subs.sort();
+    for port_name in ports_with_no_subs.iter() {
+      self.port_manager.close_port(port_name);
+      self.forget_port(port_name);
+    }
+    ports_with_no_subs
+  }
+
+  /// Forget the write lock, write policy, transaction and held
+  /// back writes of a port that was closed or lost, so whoever
+  /// opens it next starts afresh
+  fn forget_port(&mut self, port_name: &String) {
+    self.writelock_manager.clear_lock(port_name);
+    self
+      .writelock_manager
+      .set_policy(port_name, WritePolicy::Exclusive);
+    self.transactions.remove(port_name);
+    // Writes held back for the port will never happen
+    let held = self.held_requests.remove(port_name).unwrap_or_default();
+    for (sub_id, envelope) in held {
+      let err = ErrorKind::OpenPortNotFound(port_name.to_string()).into();
+      self.send_reply(&sub_id, envelope.id, to_serial_response_error(err));
+    }
+  }
+
+  /// Periodically rescan available ports and broadcast
+  /// any ports that were added or removed
+  fn check_for_port_changes(&mut self) {
+    if !self.port_watcher.is_scan_due() {
+      return;
+    }
+    match self.port_manager.list_ports() {
+      Ok(port_infos) => {
+        let ports = port_infos.into_iter().map(PortInfo::from).collect();
+        self.broadcast_port_changes(ports);
+      }
+      Err(e) => warn!("Error scanning serial ports, cause '{}'", e),
+    }
+  }
+
+  /// Broadcast the ports added or removed since the last scan
+  fn broadcast_port_changes(&mut self, ports: Vec<PortInfo>) {
+    for resp in self.port_watcher.update(ports) {
+      self.broadcast_message(resp);
+    }
+  }
+
+  /// Handle data read from a port, feeding it to a pending
+  /// transaction if there is one, otherwise broadcasting it
+  fn handle_port_data(&mut self, port_name: &String, data: Vec<u8>) {
+    let leftover = match self.transactions.get_mut(port_name) {
+      None => Some(data),
+      Some(transaction) => transaction.push(&data),
+    };
+    let leftover = match leftover {
+      // Transaction still collecting
+      None => return,
+      Some(leftover) => leftover,
+    };
+    if let Some(transaction) = self.transactions.remove(port_name) {
+      self.finish_transaction(port_name, transaction, false);
+    }
+    if !leftover.is_empty() {
+      self.broadcast_data_for_port(port_name, &leftover);
+    }
+  }
+
+  /// Finish any transactions that timed out
+  fn expire_transactions(&mut self) {
+    let expired: Vec<String> = self
+      .transactions
+      .iter()
+      .filter(|&(_, t)| t.is_expired())
+      .map(|(port_name, _)| port_name.to_string())
+      .collect();
+    for port_name in expired {
+      if let Some(transaction) = self.transactions.remove(&port_name) {
+        self.finish_transaction(&port_name, transaction, true);
+      }
+    }
+  }
+
+  /// Hand released write locks to the first client waiting
+  /// for them, and let the rest know their new place in line
+  fn grant_waiting_locks(&mut self) {
+    for (port_name, waiter) in self.writelock_manager.grant_waiting_locks() {
+      info!(
+        "Write lock on '{}' handed to '{}'",
+        port_name, waiter.sub_id
+      );
+      let resp = SerialResponse::WriteLocked {
+        port: port_name.clone(),
       };
-      self.send_message(&sub_id, close_resp);
+      self.send_reply(&waiter.sub_id, waiter.request_id, resp);
+      let waiting = self.writelock_manager.waiters(&port_name);
+      for (index, sub_id) in waiting.iter().enumerate() {
+        let resp = SerialResponse::WriteLockQueued {
+          port: port_name.clone(),
+          position: index + 1,
+        };
+        self.send_message(sub_id, resp);
+      }
     }
-    Ok(())
+  }
+
+  /// Let the subscribers of ports whose write lock
+  /// changed hands know who holds it now
+  fn broadcast_lock_changes(&mut self) {
+    for (port_name, holder) in self.writelock_manager.take_changes() {
+      let resp = SerialResponse::WriteLockChanged {
+        port: port_name.clone(),
+        holder: holder.map(|h| self.client_label(&h)),
+      };
+      self.broadcast_message_for_port(&port_name, resp);
+    }
+  }
+
+  /// Release write locks whose lease ran out, letting
+  /// the port's subscribers and the holder know
+  fn expire_write_locks(&mut self) {
+    for (port_name, sub_id) in self.writelock_manager.expire_locks() {
+      info!("Write lock on '{}' held by '{}' expired", port_name, sub_id);
+      let resp = SerialResponse::WriteLockExpired {
+        port: port_name.clone(),
+      };
+      self.broadcast_message_for_port_except(&port_name, Some(&sub_id), resp.clone());
+      self.send_message(&sub_id, resp);
+    }
+  }
+
+  /// Send the result of a transaction to the client that started
+  /// it, or an error if the reply grew too long without ending
+  fn finish_transaction(&mut self, port_name: &String, transaction: Transaction, timed_out: bool) {
+    let sub_id = transaction.sub_id.clone();
+    let request_id = transaction.request_id.clone();
+    if transaction.is_too_long() {
+      let err = ErrorKind::TransactionReplyTooLong(port_name.to_string()).into();
+      self.send_reply(&sub_id, request_id, to_serial_response_error(err));
+      return;
+    }
+    let (data, encoding) = ReadEncoder::new(Encoding::Auto).encode(&transaction.into_reply());
+    let resp = SerialResponse::TransactResult {
+      port: port_name.to_string(),
+      data: data,
+      base64: Some(encoding == Encoding::Base64),
+      timed_out: timed_out,
+    };
+    self.send_reply(&sub_id, request_id, resp);
+  }
+
+  /// Drop pending transactions and held back writes of a
+  /// subscriber, for a single port, or all ports if port_name is None
+  fn drop_transactions_for_sub(&mut self, sub_id: &String, port_name: Option<&String>) {
+    self.transactions.retain(|p, t| {
+      t.sub_id != *sub_id || port_name.map(|pn| pn != p).unwrap_or(false)
+    });
+    for (p, held) in self.held_requests.iter_mut() {
+      if port_name.map(|pn| pn == p).unwrap_or(true) {
+        held.retain(|&(ref sid, _)| sid != sub_id);
+      }
+    }
+    self.held_requests.retain(|_, held| !held.is_empty());
+  }
+
+  /// Handle writes held back on SharedAtomic ports in the
+  /// order they arrived, till one starts another transaction
+  fn release_held_requests(&mut self) {
+    let ready: Vec<String> = self
+      .held_requests
+      .keys()
+      .filter(|port_name| !self.transactions.contains_key(*port_name))
+      .cloned()
+      .collect();
+    for port_name in ready {
+      while !self.transactions.contains_key(&port_name) {
+        let next = self
+          .held_requests
+          .get_mut(&port_name)
+          .and_then(|held| held.pop_front());
+        match next {
+          Some((sub_id, envelope)) => self.dispatch_request(&sub_id, envelope),
+          None => break,
+        }
+      }
+      let empty = self
+        .held_requests
+        .get(&port_name)
+        .map(|held| held.is_empty())
+        .unwrap_or(false);
+      if empty {
+        self.held_requests.remove(&port_name);
+      }
+    }
+  }
+
+  /// Tell the other subscribers of a port about a write, who
+  /// wrote if the port is shared, and what was written to
+  /// those that asked for echoes
+  fn announce_write(&mut self, port_name: &String, sub_id: &String, data: &[u8]) {
+    let by = self.client_label(sub_id);
+    if self.writelock_manager.policy(port_name) != WritePolicy::Exclusive {
+      let resp = SerialResponse::Written {
+        port: port_name.clone(),
+        by: by.clone(),
+        bytes: data.len(),
+      };
+      self.broadcast_message_for_port_except(port_name, Some(sub_id), resp);
+    }
+    let bad_subs = self
+      .sub_manager
+      .broadcast_echo_for_port(port_name, sub_id, &by, data);
+    self.cleanup_bad_subs(bad_subs);
   }
 
   /// Cleanup any bad ports
+  ///
+  /// Sticky subscribers and their write locks are remembered
+  /// so they can be restored if the port comes back
   fn cleanup_bad_ports(&mut self, bad_ports: &HashSet<String>) {
     for port_name in bad_ports.iter() {
       // Tell everyone port is sick
       let err_resp =
         to_serial_response_error(ErrorKind::PortReadError(port_name.to_owned()).into());
       self.broadcast_message_for_port(port_name, err_resp);
-      // Tell everyone the sick ports were closed
-      let close_resp = SerialResponse::Closed {
-        port: port_name.clone(),
-      };
-      self.broadcast_message_for_port(port_name, close_resp);
+      // Split subscribers into sticky ones we keep, and the rest
+      let (sticky_subs, other_subs): (Vec<_>, Vec<_>) = self
+        .sub_manager
+        .subscribers_for_port(port_name)
+        .into_iter()
+        .partition(|&(_, ref opts)| opts.sticky);
+      // Tell non sticky subscribers the sick port was closed
+      for (sub_id, _) in other_subs {
+        let close_resp = SerialResponse::Closed {
+          port: port_name.clone(),
+        };
+        self.send_message(&sub_id, close_resp);
+      }
+      // Remember sticky subscribers, and the lock if one of them held it
+      let lock_holder = self
+        .writelock_manager
+        .lock_holder(port_name)
+        .filter(|holder| sticky_subs.iter().any(|&(ref sid, _)| sid == holder));
+      let lock_lease = self.writelock_manager.lease(port_name);
+      let write_policy = self.writelock_manager.policy(port_name);
+      if let Ok(settings) = self.port_manager.port_settings(port_name) {
+        self.reconnect_manager.add_lost_port(
+          port_name,
+          settings,
+          sticky_subs,
+          lock_holder,
+          lock_lease,
+          write_policy,
+        );
+      }
       // Close bad ports
       self.port_manager.close_port(port_name);
-      // Remove write locks on bad ports
-      self.writelock_manager.clear_lock(port_name);
+      // Drop their transactions, write locks and policies
+      self.forget_port(port_name);
       // Remove bad ports from subscriptions
       self.sub_manager.remove_port_from_all(port_name);
     }
   }
 
+  /// Try to reopen lost sticky ports that are due for
+  /// another attempt, restoring subscriptions and write locks
+  /// for those that come back
+  fn reconnect_lost_ports(&mut self) {
+    for port_name in self.reconnect_manager.ports_due() {
+      // Someone may have reopened it in the meantime
+      let was_open = self.port_manager.is_port_open(&port_name);
+      let settings = match was_open {
+        true => None,
+        false => self.reconnect_manager.settings(&port_name),
+      };
+      let settings = match self.port_manager.open_port(&port_name, settings.as_ref()) {
+        Ok(settings) => settings,
+        Err(e) => {
+          debug!("Reconnecting port '{}' failed, cause '{}'", port_name, e);
+          self.reconnect_manager.attempt_failed(&port_name);
+          continue;
+        }
+      };
+      info!("Reconnected lost port '{}'", port_name);
+      if let Some(lost_port) = self.reconnect_manager.reconnected(&port_name) {
+        if !was_open {
+          self
+            .writelock_manager
+            .set_policy(&port_name, lost_port.write_policy);
+        }
+        for (sub_id, options) in lost_port.subscribers {
+          if self
+            .sub_manager
+            .add_port(&sub_id, &port_name, options)
+            .is_err()
+          {
+            // Subscription went away while port was lost
+            continue;
+          }
+          if lost_port.lock_holder.as_ref() == Some(&sub_id) {
+            let lease = lost_port.lock_lease;
+            if let Err(e) = self.writelock_manager.lock_port(&port_name, &sub_id, lease) {
+              debug!("Restoring write lock for '{}' failed, cause '{}'", sub_id, e);
+            }
+          }
+          let resp = SerialResponse::Reconnected {
+            port: port_name.clone(),
+            settings: settings.clone(),
+          };
+          self.send_message(&sub_id, resp);
+        }
+      }
+    }
+  }
+
+  /// Reply to the subscriber that sent the request
+  /// currently being handled, attaching the request id
+  fn reply(&mut self, sub_id: &String, msg: SerialResponse) {
+    let id = self.request_id.clone();
+    self.send_reply(sub_id, id, msg);
+  }
+
+  /// Send a reply to a request with the given id to a subscriber
+  fn send_reply(&mut self, sub_id: &String, id: Option<String>, msg: SerialResponse) {
+    if let Err(e) = self.sub_manager.send_reply(sub_id, id, msg) {
+      warn!("Error sending serial response to sub_id '{}'", sub_id);
+      self.cleanup_bad_subs(vec![e]);
+    }
+  }
+
   /// Send a message to a subscriber
   fn send_message(&mut self, sub_id: &String, msg: SerialResponse) {
     if let Err(e) = self.sub_manager.send_message(sub_id, msg) {
       warn!("Error sending serial response to sub_id '{}'", sub_id);
-      let mut bad_subs = Vec::new();
-      bad_subs.push(e);
-      self.cleanup_bad_subs(bad_subs);
+      self.cleanup_bad_subs(vec![e]);
     }
   }
 
@@ -347,6 +991,27 @@ impl Manager {
     self.cleanup_bad_subs(bad_subs);
   }
 
+  /// Send data read from a port to all subscribers interested
+  /// in it and then cleanup any subs that errored
+  fn broadcast_data_for_port(&mut self, port_name: &String, data: &[u8]) {
+    let bad_subs = self.sub_manager.broadcast_data_for_port(port_name, data);
+    self.cleanup_bad_subs(bad_subs);
+  }
+
+  /// Broadcast a message to all subscribers interested in the
+  /// given port except one, and then cleanup any subs that errored
+  fn broadcast_message_for_port_except(
+    &mut self,
+    port_name: &String,
+    except_sub_id: Option<&String>,
+    msg: SerialResponse,
+  ) {
+    let bad_subs = self
+      .sub_manager
+      .broadcast_message_for_port_except(port_name, except_sub_id, msg);
+    self.cleanup_bad_subs(bad_subs);
+  }
+
   /// Cleanup any bad subs where a message send failed
   fn cleanup_bad_subs(&mut self, bad_subs: Vec<Error>) {
     for e in bad_subs {
@@ -355,6 +1020,12 @@ impl Manager {
         self.sub_manager.end_subscription(&sub_id);
         // Remove all write locks held by dead subscription
         self.writelock_manager.unlock_all_ports_for_sub(&sub_id);
+        // Forget dead subscription for any lost ports
+        self.reconnect_manager.remove_sub_from_all(&sub_id);
+        // Drop transactions started by dead subscription
+        self.drop_transactions_for_sub(&sub_id, None);
+        // Close ports nobody else subscribes to
+        self.close_ports_with_no_subs();
       }
     }
   }
@@ -364,6 +1035,22 @@ impl Manager {
     self.sub_manager.check_subscription_exists(sub_id)
   }
 
+  /// Check if sub_id may write to port_name, because it holds
+  /// the write lock, or because the port is shared, nobody holds
+  /// the lock, and the access control rules allow it to write
+  fn check_can_write(&self, port_name: &String, sub_id: &String) -> Result<()> {
+    self.writelock_manager.check_can_write(port_name, sub_id)?;
+    match self
+      .writelock_manager
+      .is_port_write_locked_by(port_name, sub_id)
+    {
+      true => Ok(()),
+      false => self
+        .acl
+        .check_write(port_name, self.sub_manager.identity(sub_id)?),
+    }
+  }
+
   /// Check if port_name has a write lock for sub_id
   /// Errors if the port is not writelocked by sub_id
   fn check_owns_writelock(&self, port_name: &String, sub_id: &String) -> Result<()> {
@@ -372,3 +1059,366 @@ impl Manager {
       .check_owns_write_lock(port_name, sub_id)
   }
 }
+
+/// Decode data sent by a client, which is base64
+/// encoded if base_64 is true
+fn decode_data(data: String, base_64: bool) -> Result<Vec<u8>> {
+  match base_64 {
+    true => base64::decode(&data).map_err(|e| ErrorKind::Base64(e).into()),
+    false => Ok(data.into_bytes()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::io::Read;
+  use std::sync::mpsc::channel;
+
+  use serialport::posix::TTYPort;
+  use serialport::SerialPort;
+
+  use super::*;
+  use crate::sub_queue::*;
+
+  /// A manager driven by handing it events directly
+  fn manager() -> Manager {
+    let (events_tx, events_rx) = channel();
+    Manager::new(events_rx, events_tx, AccessControl::default())
+  }
+
+  /// Connect a client, returning the queue it reads from
+  fn subscribe(manager: &mut Manager, sub_id: &str) -> QueueReceiver {
+    let (tx, rx) = sub_queue(100, OverflowPolicy::DropOldest);
+    manager.handle_event(ManagerEvent::Subscribe(SubscriptionRequest {
+      sub_id: sub_id.to_string(),
+      subscriber: tx,
+      binary: false,
+      identity: None,
+    }));
+    rx
+  }
+
+  /// Send a request with the given id from a client
+  fn request(manager: &mut Manager, sub_id: &str, id: &str, request: SerialRequest) {
+    let envelope = RequestEnvelope {
+      id: Some(id.to_string()),
+      request: request,
+    };
+    manager.handle_event(ManagerEvent::Request(sub_id.to_string(), envelope));
+  }
+
+  /// The replies queued for a client, skipping notices
+  fn replies(rx: &QueueReceiver) -> Vec<(String, SerialResponse)> {
+    rx.drain(100)
+      .into_iter()
+      .filter_map(|msg| match msg {
+        SubscriberMessage::Response(ResponseEnvelope {
+          id: Some(id),
+          response,
+        }) => Some((id, response)),
+        _ => None,
+      })
+      .collect()
+  }
+
+  fn open(port: &str, write_policy: Option<WritePolicy>) -> SerialRequest {
+    SerialRequest::Open {
+      port: port.to_string(),
+      settings: None,
+      sticky: None,
+      encoding: None,
+      framing: None,
+      echo: None,
+      write_policy: write_policy,
+    }
+  }
+
+  fn write(port: &str, data: &str) -> SerialRequest {
+    SerialRequest::Write {
+      port: port.to_string(),
+      data: data.to_string(),
+      base64: None,
+    }
+  }
+
+  fn close(port: &str) -> SerialRequest {
+    SerialRequest::Close {
+      port: Some(port.to_string()),
+    }
+  }
+
+  fn assert_opened(rx: &QueueReceiver, id: &str) {
+    match replies(rx).as_slice() {
+      [(ref reply_id, SerialResponse::Opened { .. })] if reply_id == id => {}
+      other => panic!("Expected Opened reply to '{}', got {:?}", id, other),
+    }
+  }
+
+  fn assert_error(rx: &QueueReceiver, id: &str) {
+    match replies(rx).as_slice() {
+      [(ref reply_id, SerialResponse::Error { .. })] if reply_id == id => {}
+      other => panic!("Expected Error reply to '{}', got {:?}", id, other),
+    }
+  }
+
+  /// Read from the master end of a pty till len bytes arrived
+  fn read_master(master: &mut TTYPort, len: usize) -> String {
+    let mut read = Vec::new();
+    let mut buffer = [0; 64];
+    while read.len() < len {
+      let count = master.read(&mut buffer).expect("Read from master failed");
+      read.extend_from_slice(&buffer[..count]);
+    }
+    String::from_utf8_lossy(&read).to_string()
+  }
+
+  /// A pty pair whose slave end the manager can open
+  fn pty_pair() -> (TTYPort, TTYPort, String) {
+    let (master, mut slave) = TTYPort::pair().expect("Failed to create pseudoterminal pair!");
+    slave
+      .set_exclusive(false)
+      .expect("Failed to set exclusive false");
+    let port = slave.port_name().expect("Slave should have a name");
+    (master, slave, port)
+  }
+
+  /// The pseudoterminals currently available
+  #[cfg(target_os = "linux")]
+  fn pts_ports() -> Vec<PortInfo> {
+    std::fs::read_dir("/dev/pts")
+      .expect("Failed to list /dev/pts")
+      .filter_map(|entry| entry.ok())
+      .map(|entry| entry.path().to_string_lossy().to_string())
+      .filter(|path| path != "/dev/pts/ptmx")
+      .map(|path| PortInfo {
+        port_name: path,
+        port_type: PortType::Unknown,
+      })
+      .collect()
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_port_hotplug() {
+    let manager = &mut manager();
+    let sub1 = subscribe(manager, "SUB1");
+    let sub2 = subscribe(manager, "SUB2");
+    // The first scan only records the ports
+    manager.broadcast_port_changes(pts_ports());
+    assert!(sub1.drain(100).is_empty());
+
+    // Other tests create ptys too, so only look for ours
+    let (master, slave, port) = pty_pair();
+    manager.broadcast_port_changes(pts_ports());
+    for sub in [&sub1, &sub2].iter() {
+      let added = sub.drain(100).into_iter().any(|msg| match msg {
+        SubscriberMessage::Response(ResponseEnvelope {
+          id: None,
+          response: SerialResponse::PortAdded { port: ref p, .. },
+        }) => *p == port,
+        _ => false,
+      });
+      assert!(added, "Subscribers should be told {} was added", port);
+    }
+
+    drop(master);
+    drop(slave);
+    manager.broadcast_port_changes(pts_ports());
+    for sub in [&sub1, &sub2].iter() {
+      let removed = SubscriberMessage::Response(
+        SerialResponse::PortRemoved {
+          port: port.clone(),
+        }
+        .into(),
+      );
+      assert!(
+        sub.drain(100).contains(&removed),
+        "Subscribers should be told {} was removed",
+        port
+      );
+    }
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn test_shared_atomic_holds_writes() {
+    let (mut master, _slave, port) = pty_pair();
+    let manager = &mut manager();
+    let sub1 = subscribe(manager, "SUB1");
+    let sub2 = subscribe(manager, "SUB2");
+    request(manager, "SUB1", "open1", open(&port, Some(WritePolicy::SharedAtomic)));
+    assert_opened(&sub1, "open1");
+    request(manager, "SUB2", "open2", open(&port, None));
+    assert_opened(&sub2, "open2");
+
+    // Writes of others wait for a transaction to finish, the
+    // client running it can keep writing till someone waits
+    let transact = SerialRequest::Transact {
+      port: port.clone(),
+      data: "AT;".to_string(),
+      base64: None,
+      terminator: Some(";".to_string()),
+      max_bytes: None,
+      timeout_ms: Some(60_000),
+    };
+    request(manager, "SUB1", "transact", transact);
+    request(manager, "SUB1", "write", write(&port, "A1;"));
+    request(manager, "SUB2", "held", write(&port, "B1;"));
+    request(manager, "SUB1", "held", write(&port, "A2;"));
+    assert!(replies(&sub2).is_empty(), "Write should be held");
+    assert_eq!(
+      replies(&sub1),
+      vec![("write".to_string(), SerialResponse::Wrote { port: port.clone() })]
+    );
+
+    // The transaction's reply releases the held write
+    manager.handle_event(ManagerEvent::PortData(port.clone(), Ok(b"OK;".to_vec())));
+    let mut sub1_replies = replies(&sub1);
+    assert_eq!(
+      sub1_replies.pop(),
+      Some(("held".to_string(), SerialResponse::Wrote { port: port.clone() }))
+    );
+    match sub1_replies.as_slice() {
+      [(ref id, SerialResponse::TransactResult { ref data, timed_out, .. })] => {
+        assert_eq!(id, "transact");
+        assert_eq!(data, "OK;");
+        assert!(!timed_out, "Transaction should not time out");
+      }
+      other => panic!("Expected TransactResult, got {:?}", other),
+    }
+    assert_eq!(
+      replies(&sub2),
+      vec![("held".to_string(), SerialResponse::Wrote { port: port.clone() })]
+    );
+    // Held writes go out in the order they arrived
+    assert_eq!(read_master(&mut master, 12), "AT;A1;B1;A2;");
+
+    // Writes held for a lost port get an error
+    let transact = SerialRequest::Transact {
+      port: port.clone(),
+      data: "AT;".to_string(),
+      base64: None,
+      terminator: Some(";".to_string()),
+      max_bytes: None,
+      timeout_ms: Some(60_000),
+    };
+    request(manager, "SUB1", "transact", transact);
+    request(manager, "SUB2", "held", write(&port, "B2;"));
+    assert!(replies(&sub2).is_empty(), "Write should be held");
+    let lost = Box::new(ErrorKind::PortReadError(port.clone()).into());
+    manager.handle_event(ManagerEvent::PortData(port.clone(), Err(lost)));
+    assert_error(&sub2, "held");
+    assert!(manager.held_requests.is_empty());
+    assert_eq!(manager.writelock_manager.policy(&port), WritePolicy::Exclusive);
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn test_shared_writes_announced() {
+    let (mut master, _slave, port) = pty_pair();
+    let manager = &mut manager();
+    let sub1 = subscribe(manager, "SUB1");
+    let sub2 = subscribe(manager, "SUB2");
+    let sub3 = subscribe(manager, "SUB3");
+    request(manager, "SUB1", "open1", open(&port, Some(WritePolicy::Shared)));
+    assert_opened(&sub1, "open1");
+    request(manager, "SUB2", "open2", open(&port, None));
+    assert_opened(&sub2, "open2");
+    let open_echo = SerialRequest::Open {
+      port: port.clone(),
+      settings: None,
+      sticky: None,
+      encoding: Some(Encoding::Utf8),
+      framing: None,
+      echo: Some(true),
+      write_policy: None,
+    };
+    request(manager, "SUB3", "open3", open_echo);
+    assert_opened(&sub3, "open3");
+
+    // Each write goes out whole, in the order they arrived
+    request(manager, "SUB1", "write", write(&port, "A1;"));
+    request(manager, "SUB2", "write", write(&port, "B1;"));
+    request(manager, "SUB1", "write", write(&port, "A2;"));
+    assert_eq!(read_master(&mut master, 9), "A1;B1;A2;");
+
+    let notices = |rx: &QueueReceiver| -> Vec<SerialResponse> {
+      rx.drain(100)
+        .into_iter()
+        .filter_map(|msg| match msg {
+          SubscriberMessage::Response(ResponseEnvelope { id: None, response }) => Some(response),
+          _ => None,
+        })
+        .collect()
+    };
+    let written = |by: &str| SerialResponse::Written {
+      port: port.clone(),
+      by: by.to_string(),
+      bytes: 3,
+    };
+    let sent = |by: &str, data: &str| SerialResponse::Sent {
+      port: port.clone(),
+      data: data.to_string(),
+      base64: Some(false),
+      encoding: Some(Encoding::Utf8),
+      by: by.to_string(),
+    };
+
+    // Everyone else learns who wrote each frame,
+    // the frames themselves need echo
+    assert_eq!(notices(&sub1), vec![written("SUB2")]);
+    assert_eq!(notices(&sub2), vec![written("SUB1"), written("SUB1")]);
+    assert_eq!(
+      notices(&sub3),
+      vec![
+        written("SUB1"),
+        sent("SUB1", "A1;"),
+        written("SUB2"),
+        sent("SUB2", "B1;"),
+        written("SUB1"),
+        sent("SUB1", "A2;"),
+      ]
+    );
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn test_write_policy_reset() {
+    let (mut master, _slave, port) = pty_pair();
+    let manager = &mut manager();
+    let sub1 = subscribe(manager, "SUB1");
+    let sub2 = subscribe(manager, "SUB2");
+    request(manager, "SUB1", "open1", open(&port, Some(WritePolicy::Shared)));
+    assert_opened(&sub1, "open1");
+    request(manager, "SUB2", "open2", open(&port, Some(WritePolicy::Exclusive)));
+    assert_error(&sub2, "open2");
+    request(manager, "SUB2", "open2", open(&port, None));
+    assert_opened(&sub2, "open2");
+
+    // Anyone may write to a shared port without a lock
+    request(manager, "SUB1", "write1", write(&port, "A;"));
+    request(manager, "SUB2", "write2", write(&port, "B;"));
+    assert_eq!(read_master(&mut master, 4), "A;B;");
+    assert!(!replies(&sub1).is_empty());
+    assert!(!replies(&sub2).is_empty());
+
+    // The port is closed with its policy when the last subscriber leaves
+    request(manager, "SUB1", "close1", close(&port));
+    assert_eq!(manager.writelock_manager.policy(&port), WritePolicy::Shared);
+    request(manager, "SUB2", "close2", close(&port));
+    replies(&sub2);
+    assert!(!manager.port_manager.is_port_open(&port));
+    assert_eq!(manager.writelock_manager.policy(&port), WritePolicy::Exclusive);
+    request(manager, "SUB1", "open1", open(&port, Some(WritePolicy::SharedAtomic)));
+    replies(&sub1);
+    request(manager, "SUB2", "open2", open(&port, Some(WritePolicy::Exclusive)));
+    assert_error(&sub2, "open2");
+
+    // Also when the last subscriber goes away
+    drop(sub1);
+    manager.handle_event(ManagerEvent::PortData(port.clone(), Ok(b"Hi".to_vec())));
+    assert!(!manager.port_manager.is_port_open(&port));
+    assert_eq!(manager.writelock_manager.policy(&port), WritePolicy::Exclusive);
+  }
+}
@@ -5,20 +5,163 @@
 
 use serde_json;
 
-// TODO: use when new version drops
-//use serialport::{SerialPortInfo,UsbPortInfo,SerialPortType};
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
 
 use std::fmt;
-use std::sync::mpsc::Sender;
+
+use crate::auth::Identity;
+use crate::errors::Error;
+use crate::sub_queue::QueueSender;
 
 #[derive(Clone, Debug)]
 pub struct SubscriptionRequest {
   pub sub_id: String,
-  pub subscriber: Sender<SerialResponse>,
+  pub subscriber: QueueSender,
+  /// Send data read from ports as raw bytes
+  /// instead of SerialResponse::Read messages
+  pub binary: bool,
+  /// Who the client authenticated as, if
+  /// authentication is required
+  pub identity: Option<Identity>,
+}
+
+/// Events sent to the manager, any event
+/// wakes it up to handle it right away
+#[derive(Debug)]
+pub enum ManagerEvent {
+  /// A client connected
+  Subscribe(SubscriptionRequest),
+  /// A request from the client with the given sub_id
+  Request(String, RequestEnvelope),
+  /// Data read from a port by its reader, or
+  /// the error that stopped the reader
+  PortData(String, PortReadResult),
+}
+
+/// Data read from a port or why reading failed, the error
+/// is boxed since errors can hold a ManagerEvent themselves
+pub type PortReadResult = ::std::result::Result<Vec<u8>, Box<Error>>;
+
+/// Messages sent by the manager to a subscriber
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubscriberMessage {
+  /// A response to send to the client as json
+  Response(ResponseEnvelope),
+  /// Raw data read from a port, only sent to
+  /// binary subscriptions
+  Data { port: String, data: Vec<u8> },
+  /// A complete frame read from a port opened with
+  /// framing, which must not be merged with other reads
+  Frame(Box<SubscriberMessage>),
+}
+
+impl SubscriberMessage {
+  /// The port and number of bytes of port data the
+  /// message holds, None if it isn't port data.
+  /// Writes echoed to other clients count as port data
+  pub fn port_data_len(&self) -> Option<(String, usize)> {
+    match *self {
+      SubscriberMessage::Data { ref port, ref data } => Some((port.clone(), data.len())),
+      SubscriberMessage::Frame(ref msg) => msg.port_data_len(),
+      // Reads answering a request are replies, not port data
+      SubscriberMessage::Response(ref envelope) if envelope.id.is_some() => None,
+      SubscriberMessage::Response(ref envelope) => match envelope.response {
+        SerialResponse::Read {
+          ref port,
+          ref data,
+          encoding,
+          ..
+        } => Some((port.clone(), decoded_len(data, encoding))),
+        SerialResponse::Sent {
+          ref port,
+          ref data,
+          encoding,
+          ..
+        } => Some((port.clone(), decoded_len(data, encoding))),
+        _ => None,
+      },
+    }
+  }
+}
+
+/// Number of bytes encoded read data stands for
+fn decoded_len(data: &str, encoding: Option<Encoding>) -> usize {
+  match encoding {
+    Some(Encoding::Base64) => {
+      let padding = data.chars().rev().take_while(|&c| c == '=').count();
+      (data.len() / 4 * 3).saturating_sub(padding)
+    }
+    Some(Encoding::Hex) => data.len() / 2,
+    Some(Encoding::Latin1) => data.chars().count(),
+    _ => data.len(),
+  }
+}
+
+/// A SerialRequest along with an optional client supplied id
+///
+/// If an id is given, it is echoed back on the
+/// response to the request, including errors,
+/// so clients can match up responses with requests.
+///
+/// ``` json
+/// JSON:
+/// {"id":"write-3","Write":{"port":"/dev/ttyUSB","data":"Hello World"}}
+///
+/// {"List":{}}
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RequestEnvelope {
+  /// Client supplied request id
+  pub id: Option<String>,
+  /// The request
+  #[serde(flatten)]
+  pub request: SerialRequest,
+}
+
+impl From<SerialRequest> for RequestEnvelope {
+  fn from(request: SerialRequest) -> RequestEnvelope {
+    RequestEnvelope {
+      id: None,
+      request: request,
+    }
+  }
+}
+
+/// A SerialResponse along with the id of the
+/// request that caused it
+///
+/// The id is only present on direct replies to a request
+/// that had an id, broadcasts such as Read never carry one.
+///
+/// ``` json
+/// JSON:
+/// {"id":"write-3","Wrote":{"port":"/dev/ttyUSB"}}
+///
+/// {"Read":{"port":"/dev/ttyUSB","data":"Hello World","base64":false,"encoding":"Utf8"}}
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResponseEnvelope {
+  /// Id of the request this is a response to
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id: Option<String>,
+  /// The response
+  #[serde(flatten)]
+  pub response: SerialResponse,
+}
+
+impl From<SerialResponse> for ResponseEnvelope {
+  fn from(response: SerialResponse) -> ResponseEnvelope {
+    ResponseEnvelope {
+      id: None,
+      response: response,
+    }
+  }
 }
 
 /// Represents the valid json requests that can be made
 ///
+/// Requests may be wrapped in a [RequestEnvelope](struct.RequestEnvelope.html)
+/// to attach an id
 /// On the server side, every client is associated with
 /// a unique subscription id which is used
 /// to associate a given connection with their
@@ -28,23 +171,134 @@ pub struct SubscriptionRequest {
 /// in SerialResponse::Error responses
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum SerialRequest {
+  /// Authenticate with a key from the server config
+  ///
+  /// Only needed when the server has api keys configured and
+  /// the key was not given in the connection url (`?token=...`)
+  /// or an `Authorization: Bearer ...` header. It must be the
+  /// first message sent, any other request before a successful
+  /// Auth closes the connection
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"Auth":{"token":"c2VjcmV0LWtleQ"}}
+  /// ```
+  Auth { token: String },
   /// Open a port for reading
   ///
   /// Opening the same port more than once is
   /// okay and has no ill effects
   ///
+  /// Settings are optional, if not given the port is
+  /// opened with the defaults (115200 8N1, no flow control),
+  /// or the settings of the already open port are kept.
+  /// Any missing settings values are filled in with the defaults.
+  ///
+  /// If the port is already open with different settings,
+  /// a SerialResponse::Error is returned
+  ///
+  /// If sticky is true and the port disappears, for example when
+  /// a USB cable is unplugged, the server keeps the subscription
+  /// and any write lock, and tries to reopen the port. When it
+  /// comes back, a SerialResponse::Reconnected message is sent
+  ///
   ///``` json
   /// JSON:
   /// {"Open":{"port":"/dev/ttyUSB"}}
+  ///
+  /// {"Open":{"port":"/dev/ttyUSB",
+  ///          "settings":{"baud_rate":9600,
+  ///                      "data_bits":8,
+  ///                      "parity":"None",
+  ///                      "stop_bits":1,
+  ///                      "flow_control":"Hardware"}
+  ///         }}
+  ///
+  /// {"Open":{"port":"/dev/ttyUSB","sticky":true}}
+  ///
+  /// {"Open":{"port":"/dev/ttyUSB","encoding":"Hex"}}
+  ///```
+  ///
+  /// The encoding controls how data read from the port
+  /// is sent in SerialResponse::Read messages, see
+  /// [Encoding](enum.Encoding.html). Defaults to Auto.
+  ///
+  /// If framing is given, data read from the port is buffered
+  /// and only sent as complete frames, see [Framing](struct.Framing.html)
+  ///
+  ///``` json
+  /// JSON:
+  /// {"Open":{"port":"/dev/ttyUSB",
+  ///          "framing":{"delimiter":"Newline","flush_timeout_ms":500}
+  ///         }}
+  ///```
+  ///
+  /// If echo is true, the client is sent a SerialResponse::Sent
+  /// whenever another client writes to the port
+  ///
+  ///``` json
+  /// JSON:
+  /// {"Open":{"port":"/dev/ttyUSB","echo":true}}
   ///```
-  Open { port: String },
+  ///
+  /// The write_policy decides who may write to the port, see
+  /// [WritePolicy](enum.WritePolicy.html). It is set by the client
+  /// that opens the port first, others opening it with a different
+  /// policy get an error. Defaults to Exclusive. The policy is
+  /// forgotten when the last client closes the port or the port
+  /// is lost.
+  ///
+  ///``` json
+  /// JSON:
+  /// {"Open":{"port":"/dev/ttyUSB","write_policy":"Shared"}}
+  ///```
+  Open {
+    port: String,
+    settings: Option<PortSettings>,
+    sticky: Option<bool>,
+    encoding: Option<Encoding>,
+    framing: Option<Framing>,
+    echo: Option<bool>,
+    write_policy: Option<WritePolicy>,
+  },
   /// Take control of a port for writing
   ///
+  /// If lease_ms is given, the lock is released after that
+  /// many milliseconds unless the client renews it by sending
+  /// another WriteLock, and subscribers are sent a
+  /// SerialResponse::WriteLockExpired. Without a lease the
+  /// lock is held till released
+  ///
+  /// If another client holds the lock and wait is true, the
+  /// client is put in line for the lock and told its place with
+  /// a SerialResponse::WriteLockQueued. Once the lock is handed
+  /// to it, it is sent SerialResponse::WriteLocked. Sending
+  /// ReleaseWriteLock for the port leaves the line
+  ///
   /// ``` json
   /// JSON:
   /// {"WriteLock":{"port":"/dev/ttyUSB"}}
+  ///
+  /// {"WriteLock":{"port":"/dev/ttyUSB","lease_ms":30000}}
+  ///
+  /// {"WriteLock":{"port":"/dev/ttyUSB","wait":true}}
   /// ```
-  WriteLock { port: String },
+  WriteLock {
+    port: String,
+    lease_ms: Option<u64>,
+    wait: Option<bool>,
+  },
+  /// Take control of a port for writing, even if another
+  /// client holds the write lock. Only allowed for clients
+  /// listed as admin for the port in the access control rules
+  ///
+  /// The previous holder is sent a SerialResponse::WriteLockTaken
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"ForceWriteLock":{"port":"/dev/ttyUSB","lease_ms":30000}}
+  /// ```
+  ForceWriteLock { port: String, lease_ms: Option<u64> },
   /// Release control of a port for writing
   /// If no port is given, release all write locks
   /// held by the client for all ports
@@ -78,6 +332,59 @@ pub enum SerialRequest {
     data: String,
     base64: Option<bool>,
   },
+  /// Write raw bytes, sent by clients as binary write
+  /// frames rather than json, see
+  /// [binary_frame](../binary_frame/index.html)
+  #[serde(skip_deserializing)]
+  WriteBytes { port: String, data: Vec<u8> },
+  /// Write data and collect the reply, only works
+  /// if the client has a WriteLock active for the given port
+  ///
+  /// Data read from the port after the write is collected
+  /// until the terminator is seen, max_bytes have been read,
+  /// or timeout_ms (default 1000) passes, and is then sent
+  /// only to this client in a SerialResponse::TransactResult.
+  /// The reply is not broadcast as SerialResponse::Read messages.
+  ///
+  /// timeout_ms is cut to 60000, max_bytes must be between 1
+  /// and 65536, and a reply that reaches 65536 bytes without
+  /// ending is answered with an error instead.
+  ///
+  /// Only one transaction can be in progress per port
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"Transact":{"port":"/dev/ttyUSB",
+  ///              "data": "AT+CSQ\r",
+  ///              "terminator": "OK\r\n",
+  ///              "timeout_ms": 500
+  ///             }}
+  /// ```
+  Transact {
+    port: String,
+    data: String,
+    base64: Option<bool>,
+    terminator: Option<String>,
+    max_bytes: Option<usize>,
+    timeout_ms: Option<u64>,
+  },
+  /// Change the settings of an open port, only works
+  /// if the client has a WriteLock active for the given port
+  ///
+  /// The port stays open and all subscribers are
+  /// notified of the new settings with a
+  /// SerialResponse::Configured message
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"Configure":{"port":"/dev/ttyUSB",
+  ///               "settings":{"baud_rate":921600}
+  ///              }}
+  /// ```
+  Configure {
+    port: String,
+    settings: PortSettings,
+  },
   /// Close the port, which stops any read updates
   /// from the port from being sent to this subscription
   ///
@@ -96,11 +403,188 @@ pub enum SerialRequest {
   Close { port: Option<String> },
   /// List available serial ports
   ///
+  /// By default the response includes detailed port
+  /// information such as the USB vendor and product ids.
+  /// Set names_only to true to only get the port names.
+  ///
   /// ``` json
   /// JSON:
   /// {"List":{}}
+  ///
+  /// {"List":{"names_only":true}}
   /// ```
-  List {},
+  List { names_only: Option<bool> },
+  /// Get the state of open ports, who holds their write
+  /// locks and for how long
+  ///
+  /// Only ports the client is allowed to open are included.
+  /// If a port is given, only that port is included
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"Status":{}}
+  ///
+  /// {"Status":{"port":"/dev/ttyUSB"}}
+  /// ```
+  Status { port: Option<String> },
+}
+
+/// Encodings for data read from a port
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Encoding {
+  /// Utf8 if a chunk of data is valid utf8, otherwise base64.
+  ///
+  /// Multi byte characters split across reads cause
+  /// those chunks to be sent as base64
+  #[default]
+  Auto,
+  /// Utf8, characters split across reads are carried
+  /// over, invalid bytes are replaced with U+FFFD
+  Utf8,
+  /// ISO-8859-1, every byte maps to one character
+  Latin1,
+  /// Lowercase hex, two characters per byte
+  Hex,
+  /// Base64
+  Base64,
+}
+
+/// Who may write to a port
+///
+/// Each write is sent to the port whole, in the order
+/// requests arrive, so writes from different clients
+/// are never mixed up within a packet
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum WritePolicy {
+  /// Only the client holding the write lock may write
+  #[default]
+  Exclusive,
+  /// Any client allowed to write the port may Write and
+  /// Transact without a write lock, unless someone holds
+  /// it. Other subscribers are told who wrote each frame
+  /// with SerialResponse::Written, those that opened the
+  /// port with echo also get the frame itself right after,
+  /// as SerialResponse::Sent
+  Shared,
+  /// Like Shared, and a Transact is kept whole too. While
+  /// it waits for its reply, writes from other clients are
+  /// held back and sent in order once it finishes, so
+  /// request / reply exchanges on a bus are not interleaved
+  SharedAtomic,
+}
+
+/// How data read from a port is split into frames
+///
+/// A frame ends at the delimiter, which is included in
+/// the frame, or when max_length bytes have been buffered.
+/// max_length must be greater than 0, and frames are never
+/// longer than MAX_FRAME_LENGTH, 64 KiB, whether or not it
+/// is given. If flush_timeout_ms is given, a partial frame is sent
+/// after no data has been received for that long.
+///
+/// ``` json
+/// JSON:
+/// {"delimiter":"CrLf"}
+///
+/// {"delimiter":{"Byte":59},"max_length":256,"flush_timeout_ms":1000}
+///
+/// {"delimiter":"None","max_length":64}
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Framing {
+  /// Delimiter ending a frame
+  pub delimiter: FrameDelimiter,
+  /// Maximum frame length in bytes, at least 1
+  pub max_length: Option<usize>,
+  /// Send partial frames after this many milliseconds without data
+  pub flush_timeout_ms: Option<u64>,
+}
+
+impl Default for Framing {
+  fn default() -> Framing {
+    Framing {
+      delimiter: FrameDelimiter::Newline,
+      max_length: None,
+      flush_timeout_ms: None,
+    }
+  }
+}
+
+/// Delimiters ending a frame
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FrameDelimiter {
+  /// No delimiter, frames are split by max_length only
+  None,
+  /// Line feed, `\n`
+  Newline,
+  /// Carriage return and line feed, `\r\n`
+  CrLf,
+  /// A custom byte
+  Byte(u8),
+}
+
+/// Parity checking modes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Parity {
+  /// No parity bit
+  None,
+  /// Parity bit set for odd parity
+  Odd,
+  /// Parity bit set for even parity
+  Even,
+}
+
+/// Flow control modes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FlowControl {
+  /// No flow control
+  None,
+  /// XON/XOFF software flow control
+  Software,
+  /// RTS/CTS hardware flow control
+  Hardware,
+}
+
+/// Serial port settings
+///
+/// Missing values are filled in with the defaults
+/// of 115200 baud, 8 data bits, no parity, 1 stop bit
+/// and no flow control
+///
+/// ``` json
+/// JSON:
+/// {"baud_rate":9600,
+///  "data_bits":8,
+///  "parity":"Even",
+///  "stop_bits":1,
+///  "flow_control":"None"}
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PortSettings {
+  /// Baud rate
+  pub baud_rate: u32,
+  /// Number of data bits, 5 to 8
+  pub data_bits: u8,
+  /// Parity checking mode
+  pub parity: Parity,
+  /// Number of stop bits, 1 or 2
+  pub stop_bits: u8,
+  /// Flow control mode
+  pub flow_control: FlowControl,
+}
+
+impl Default for PortSettings {
+  fn default() -> PortSettings {
+    PortSettings {
+      baud_rate: 115200,
+      data_bits: 8,
+      parity: Parity::None,
+      stop_bits: 1,
+      flow_control: FlowControl::None,
+    }
+  }
 }
 
 impl fmt::Display for SerialRequest {
@@ -130,26 +614,61 @@ pub enum SerialResponse {
   },
   /// Data that was read from port
   ///
-  /// If the data could not be parsed into a utf8 string
-  /// then it is base64 encoded and the base64
-  /// property is set to true.
+  /// The data is encoded using the encoding chosen when
+  /// the port was opened, which is given in the encoding
+  /// property. If the data is base64 encoded, the base64
+  /// property is also set to true.
+  ///
+  /// Data from several reads of a busy port may be merged
+  /// into one message, unless the port was opened with framing,
+  /// in which case each message holds one frame.
   ///
   /// ``` json
   /// JSON:
   /// {"Read":{"port":"/dev/ttyUSB",
-  ///           "data": "Hello World"
+  ///           "data": "Hello World",
+  ///           "base64": false,
+  ///           "encoding": "Utf8"
   ///          }}
   ///
   /// {"Read":{"port":"/dev/ttyUSB",
   ///           "data": "SGVsbG8gV29ybGQ=",
-  ///           "base64": true
+  ///           "base64": true,
+  ///           "encoding": "Base64"
   ///          }}
   /// ```
   Read {
     port: String,
     data: String,
     base64: Option<bool>,
+    encoding: Option<Encoding>,
   },
+  /// Data read from a port was dropped
+  ///
+  /// Sent when a client doesn't keep up with the data
+  /// read from a port and its queue overflows. Bytes is
+  /// how much data was lost. See the queue_length and
+  /// overflow config values
+  ///
+  ///``` json
+  /// JSON:
+  /// {"DataDropped":{"port":"/dev/ttyUSB","bytes":4096}}
+  ///```
+  DataDropped { port: String, bytes: usize },
+  /// Notices were dropped because the client's queue
+  /// was full
+  ///
+  /// Notices such as SerialResponse::WriteLockChanged or
+  /// SerialResponse::PortAdded count toward the queue
+  /// length like port data. Replies to requests are never
+  /// dropped. Counts of notices dropped in a row are added
+  /// up in one message
+  ///
+  ///``` json
+  /// JSON:
+  /// {"NoticesDropped":{"count":3}}
+  ///```
+  NoticesDropped { count: usize },
   /// Port was closed
   ///
   /// Sent in response to SerialRequest::Close
@@ -166,11 +685,92 @@ pub enum SerialResponse {
   ///
   /// Sent in response to SerialRequest::Open
   ///
+  /// Contains the settings the port is actually using
+  ///
+  ///``` json
+  /// JSON:
+  /// {"Opened":{"port":"/dev/ttyUSB",
+  ///            "settings":{"baud_rate":115200,
+  ///                        "data_bits":8,
+  ///                        "parity":"None",
+  ///                        "stop_bits":1,
+  ///                        "flow_control":"None"}
+  ///           }}
+  ///```
+  Opened {
+    port: String,
+    settings: PortSettings,
+  },
+  /// A lost sticky port was reopened
+  ///
+  /// Sent to subscribers that opened the port with sticky set,
+  /// after the port disappeared and came back. Their subscription
+  /// and write lock, if they held one, have been restored
+  ///
   ///``` json
   /// JSON:
-  /// {"Opened":{"port":"/dev/ttyUSB"}}
+  /// {"Reconnected":{"port":"/dev/ttyUSB",
+  ///                 "settings":{"baud_rate":115200,
+  ///                             "data_bits":8,
+  ///                             "parity":"None",
+  ///                             "stop_bits":1,
+  ///                             "flow_control":"None"}
+  ///                }}
   ///```
-  Opened { port: String },
+  Reconnected {
+    port: String,
+    settings: PortSettings,
+  },
+  /// Port settings were changed
+  ///
+  /// Sent to all subscribers of a port in response
+  /// to SerialRequest::Configure
+  ///
+  ///``` json
+  /// JSON:
+  /// {"Configured":{"port":"/dev/ttyUSB",
+  ///                "settings":{"baud_rate":921600,
+  ///                            "data_bits":8,
+  ///                            "parity":"None",
+  ///                            "stop_bits":1,
+  ///                            "flow_control":"None"}
+  ///               }}
+  ///```
+  Configured {
+    port: String,
+    settings: PortSettings,
+  },
+  /// A serial port became available
+  ///
+  /// Broadcast to all clients when a new serial port,
+  /// such as a USB serial adapter, is detected
+  ///
+  ///``` json
+  /// JSON:
+  /// {"PortAdded":{"port":"/dev/ttyUSB0",
+  ///               "info":{"port_name":"/dev/ttyUSB0","port_type":"Unknown"}
+  ///              }}
+  ///```
+  PortAdded { port: String, info: PortInfo },
+  /// A serial port is no longer available
+  ///
+  /// Broadcast to all clients when a serial port disappears
+  ///
+  ///``` json
+  /// JSON:
+  /// {"PortRemoved":{"port":"/dev/ttyUSB0"}}
+  ///```
+  PortRemoved { port: String },
+  /// Client authenticated
+  ///
+  /// Sent in response to SerialRequest::Auth, with
+  /// the name of the key that was matched
+  ///
+  ///``` json
+  /// JSON:
+  /// {"Authenticated":{"name":"print-service"}}
+  ///```
+  Authenticated { name: String },
   /// Command successful
   Ok { msg: String },
   /// Wrote data
@@ -188,6 +788,28 @@ pub enum SerialResponse {
   ///
   /// TODO: Return hash of data written?
   Wrote { port: String },
+  /// Result of a transaction
+  ///
+  /// Sent in response to SerialRequest::Transact
+  ///
+  /// The data is utf8, or base64 encoded if it is not valid utf8.
+  /// timed_out is true if the reply ended because of the timeout
+  /// rather than the terminator or byte count
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"TransactResult":{"port":"/dev/ttyUSB",
+  ///                    "data": "+CSQ: 20,99\r\nOK\r\n",
+  ///                    "base64": false,
+  ///                    "timed_out": false
+  ///                   }}
+  /// ```
+  TransactResult {
+    port: String,
+    data: String,
+    base64: Option<bool>,
+    timed_out: bool,
+  },
   /// Port successfully writelocked
   ///
   /// Sent in response to SerialReques::WriteLock
@@ -218,59 +840,264 @@ pub enum SerialResponse {
   /// {"WriteLockReleased":{}}
   /// ```
   WriteLockReleased { port: Option<String> },
+  /// The lease on a write lock ran out without being
+  /// renewed, and the lock was released
+  ///
+  /// Sent to the subscribers of the port and the
+  /// client that held the lock
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"WriteLockExpired":{"port":"/dev/ttyUSB"}}
+  /// ```
+  WriteLockExpired { port: String },
+  /// Another client took the write lock with
+  /// SerialRequest::ForceWriteLock
+  ///
+  /// Sent to the client that held the lock
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"WriteLockTaken":{"port":"/dev/ttyUSB"}}
+  /// ```
+  WriteLockTaken { port: String },
+  /// Waiting in line for a write lock
+  ///
+  /// Sent in response to SerialRequest::WriteLock with wait
+  /// set, and again whenever the client moves up in line.
+  /// Position 1 is next in line
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"WriteLockQueued":{"port":"/dev/ttyUSB","position":2}}
+  /// ```
+  WriteLockQueued { port: String, position: usize },
+  /// The write lock on a port changed hands
+  ///
+  /// Sent to the subscribers of the port whenever a client
+  /// takes, releases or loses the write lock. The holder is
+  /// the name of the client's api key if it authenticated,
+  /// otherwise its subscription id, and null if the port
+  /// is no longer locked
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"WriteLockChanged":{"port":"/dev/ttyUSB","holder":"print-service"}}
+  ///
+  /// {"WriteLockChanged":{"port":"/dev/ttyUSB","holder":null}}
+  /// ```
+  WriteLockChanged {
+    port: String,
+    holder: Option<String>,
+  },
+  /// A client wrote to a port with a shared write policy
+  ///
+  /// Sent to the other subscribers of the port after each
+  /// Write or Transact, in the order they were written. The
+  /// client is named as in SerialResponse::WriteLockChanged
+  ///
+  /// Only the length is given, subscribers that need the
+  /// data open the port with echo, and get it in a
+  /// SerialResponse::Sent following this message
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"Written":{"port":"/dev/ttyUSB","by":"print-service","bytes":12}}
+  /// ```
+  Written {
+    port: String,
+    by: String,
+    bytes: usize,
+  },
+  /// Data another client wrote to a port
+  ///
+  /// Sent after each successful Write or Transact to the
+  /// other subscribers of the port that opened it with echo
+  /// set. The data is encoded like SerialResponse::Read,
+  /// using the encoding each subscriber opened the port
+  /// with. The client is named as in
+  /// SerialResponse::WriteLockChanged
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"Sent":{"port":"/dev/ttyUSB",
+  ///          "data":"G28 X0\n",
+  ///          "base64":false,
+  ///          "encoding":"Utf8",
+  ///          "by":"print-service"
+  ///         }}
+  /// ```
+  Sent {
+    port: String,
+    data: String,
+    base64: Option<bool>,
+    encoding: Option<Encoding>,
+    by: String,
+  },
   /// List serial ports response
   ///
+  /// The info property contains detailed port information,
+  /// unless the request set names_only, in which case it is null
+  ///
+  /// ``` json
+  /// JSON:
+  /// {"List":{"ports":["/dev/ttyUSB0","/dev/ttyACM0"],
+  ///          "info":[{"port_name":"/dev/ttyUSB0","port_type":"Unknown"},
+  ///                  {"port_name":"/dev/ttyACM0",
+  ///                   "port_type":{"UsbPort":{"vid":9025,
+  ///                                           "pid":66,
+  ///                                           "serial_number":"85439303333351F0E1E1",
+  ///                                           "manufacturer":"Arduino (www.arduino.cc)",
+  ///                                           "product":"Arduino Mega 2560"}}}]
+  ///         }}
+  ///
+  /// {"List":{"ports":["/dev/ttyUSB0","/dev/ttyACM0"],"info":null}}
+  /// ```
+  List {
+    ports: Vec<String>,
+    info: Option<Vec<PortInfo>>,
+  },
+  /// State of open ports
+  ///
+  /// Sent in response to SerialRequest::Status
+  ///
   /// ``` json
   /// JSON:
-  /// {"List":{"ports":["/dev/ttyUSB0","/dev/ttyUSB1"]}}
+  /// {"Status":{"ports":[{"port":"/dev/ttyUSB0",
+  ///                      "settings":{"baud_rate":115200,
+  ///                                  "data_bits":8,
+  ///                                  "parity":"None",
+  ///                                  "stop_bits":1,
+  ///                                  "flow_control":"None"},
+  ///                      "write_policy":"Exclusive",
+  ///                      "subscribers":2,
+  ///                      "lock_holder":"print-service",
+  ///                      "locked_by_you":false,
+  ///                      "lock_age_ms":5230,
+  ///                      "lock_waiters":0}]
+  ///           }}
   /// ```
-  List { ports: Vec<String> },
-}
-
-/*
-// TODO: Uncomment when new serialport module version drops
-/// Needed for Serde Support as
-/// SerialPortInfo is in seperate module
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(remote = "SerialPortInfo")]
-pub struct SerialPortInfoDef {
-    /// The short name of the serial port
-    pub port_name: String,
-    /// The hardware device type that exposes this port
-    pub port_type: SerialPortType,
-
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "sp::SerialPortType")]
-/// The physical type of a `SerialPort`
-pub enum SerialPortTypeDef {
-    /// The serial port is connected via USB
-    UsbPort(UsbPortInfoDef),
-    /// The serial port is connected via PCI (permanent port)
-    PciPort,
-    /// The serial port is connected via Bluetooth
-    BluetoothPort,
-    /// It can't be determined how the serial port is connected
-    Unknown,
-}
-
-
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "UsbPortInfo")]
-pub struct UsbPortInfoDef {
-    /// Vender ID
-    pub vid: u16,
-    /// Product ID
-    pub pid: u16,
-    /// Serial number (arbitrary string)
-    pub serial_number: Option<String>,
-    /// Manufacturer (arbitrary string)
-    pub manufacturer: Option<String>,
-    /// Product name (arbitrary string)
-    pub product: Option<String>,
-}
-*/
+  Status { ports: Vec<PortStatus> },
+}
+
+/// State of an open port, sent in SerialResponse::Status
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PortStatus {
+  /// The port name
+  pub port: String,
+  /// Settings the port is open with
+  pub settings: PortSettings,
+  /// Who may write to the port
+  pub write_policy: WritePolicy,
+  /// Number of clients that have the port open
+  pub subscribers: usize,
+  /// Who holds the write lock, the name of its api key
+  /// if it authenticated, otherwise its subscription id
+  pub lock_holder: Option<String>,
+  /// Does the client asking hold the write lock
+  pub locked_by_you: bool,
+  /// How long the write lock has been held, in milliseconds
+  pub lock_age_ms: Option<u64>,
+  /// Number of clients waiting for the write lock
+  pub lock_waiters: usize,
+}
+
+/// Information about an available serial port
+///
+/// ``` json
+/// JSON:
+/// {"port_name":"/dev/ttyACM0",
+///  "port_type":{"UsbPort":{"vid":9025,
+///                          "pid":66,
+///                          "serial_number":"85439303333351F0E1E1",
+///                          "manufacturer":"Arduino (www.arduino.cc)",
+///                          "product":"Arduino Mega 2560"}}}
+///
+/// {"port_name":"/dev/ttyS0","port_type":"PciPort"}
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PortInfo {
+  /// The short name of the serial port
+  pub port_name: String,
+  /// The hardware device type that exposes this port
+  pub port_type: PortType,
+}
+
+/// The physical type of a serial port
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PortType {
+  /// The serial port is connected via USB
+  UsbPort(UsbInfo),
+  /// The serial port is connected via PCI (permanent port)
+  PciPort,
+  /// The serial port is connected via Bluetooth
+  BluetoothPort,
+  /// It can't be determined how the serial port is connected
+  Unknown,
+}
+
+/// Information about a USB serial port device
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UsbInfo {
+  /// Vendor ID
+  pub vid: u16,
+  /// Product ID
+  pub pid: u16,
+  /// Serial number (arbitrary string)
+  pub serial_number: Option<String>,
+  /// Manufacturer (arbitrary string)
+  pub manufacturer: Option<String>,
+  /// Product name (arbitrary string)
+  pub product: Option<String>,
+}
+
+impl From<SerialPortInfo> for PortInfo {
+  fn from(info: SerialPortInfo) -> PortInfo {
+    PortInfo {
+      port_name: info.port_name,
+      port_type: info.port_type.into(),
+    }
+  }
+}
+
+impl From<SerialPortType> for PortType {
+  fn from(port_type: SerialPortType) -> PortType {
+    match port_type {
+      SerialPortType::UsbPort(usb_info) => PortType::UsbPort(usb_info.into()),
+      SerialPortType::PciPort => PortType::PciPort,
+      SerialPortType::BluetoothPort => PortType::BluetoothPort,
+      SerialPortType::Unknown => PortType::Unknown,
+    }
+  }
+}
+
+impl From<UsbPortInfo> for UsbInfo {
+  fn from(usb_info: UsbPortInfo) -> UsbInfo {
+    UsbInfo {
+      vid: usb_info.vid,
+      pid: usb_info.pid,
+      serial_number: usb_info.serial_number,
+      manufacturer: usb_info.manufacturer,
+      product: usb_info.product,
+    }
+  }
+}
+
+impl fmt::Display for RequestEnvelope {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let json = serde_json::to_string(self)
+      .unwrap_or("Display RequestEnvelope: Serialization Failed!".to_string());
+    write!(f, "{}", json)
+  }
+}
+
+impl fmt::Display for ResponseEnvelope {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let json = serde_json::to_string(self)
+      .unwrap_or("Display ResponseEnvelope: Serialization Failed!".to_string());
+    write!(f, "{}", json)
+  }
+}
 
 impl fmt::Display for SerialResponse {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -279,3 +1106,56 @@ impl fmt::Display for SerialResponse {
     write!(f, "{}", json)
   }
 }
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_envelope_json() {
+    let req: RequestEnvelope =
+      serde_json::from_str(r#"{"id":"42","Close":{"port":"/dev/ttyUSB0"}}"#)
+        .expect("Parsing request with id failed");
+    assert_eq!(req.id, Some("42".to_string()), "Request id should be parsed");
+    assert_eq!(
+      req.request,
+      SerialRequest::Close {
+        port: Some("/dev/ttyUSB0".to_string())
+      },
+      "Request should be parsed"
+    );
+
+    let req: RequestEnvelope =
+      serde_json::from_str(r#"{"List":{}}"#).expect("Parsing request without id failed");
+    assert_eq!(req.id, None, "Request id should be None");
+
+    let req = r#"{"WriteBytes":{"port":"/dev/ttyUSB0","data":[72,105]}}"#;
+    assert!(
+      serde_json::from_str::<RequestEnvelope>(req).is_err(),
+      "Raw writes should only come from binary frames"
+    );
+
+    let resp = ResponseEnvelope {
+      id: Some("42".to_string()),
+      response: SerialResponse::Wrote {
+        port: "/dev/ttyUSB0".to_string(),
+      },
+    };
+    assert_eq!(
+      serde_json::to_string(&resp).unwrap(),
+      r#"{"id":"42","Wrote":{"port":"/dev/ttyUSB0"}}"#,
+      "Response id should be serialized"
+    );
+
+    let resp: ResponseEnvelope = SerialResponse::Wrote {
+      port: "/dev/ttyUSB0".to_string(),
+    }
+    .into();
+    assert_eq!(
+      serde_json::to_string(&resp).unwrap(),
+      r#"{"Wrote":{"port":"/dev/ttyUSB0"}}"#,
+      "Response without id should be unchanged"
+    );
+  }
+}
@@ -14,12 +14,25 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod acl;
+pub mod auth;
+pub mod binary_frame;
 pub mod cfg;
+pub mod coalesce;
 pub mod common;
-pub mod dynamic_sleep;
+pub mod encoding;
 pub mod errors;
+pub mod framing;
+pub mod listener;
 pub mod manager;
 pub mod messages;
+pub mod origin;
 pub mod port_manager;
+pub mod port_watcher;
+pub mod reconnect_manager;
 pub mod sub_manager;
+pub mod sub_queue;
+pub mod tls;
+pub mod transaction;
 pub mod writelock_manager;
+pub mod ws_connection;
@@ -0,0 +1,169 @@
+//! Checks the Origin header of websocket upgrade requests,
+//! so other websites open in a browser can't use the
+//! serial ports (cross-site WebSocket hijacking)
+//!
+//! The built in page is always allowed when it is loaded from
+//! one of the addresses the server binds to, or from a local
+//! host name. The Host header is not trusted, as DNS rebinding
+//! lets other websites choose it. Requests without an Origin
+//! header come from programs rather than browsers and are
+//! allowed unless configured otherwise.
+
+use std::net::IpAddr;
+
+use hyper::Url;
+
+/// Allows any origin
+pub const ANY_ORIGIN: &str = "*";
+
+/// Hosts the built in page is allowed from, besides
+/// the addresses the server binds to
+pub const LOCAL_HOSTS: &[&str] = &["localhost", "127.0.0.1", "[::1]"];
+
+/// Decides which origins may open websockets
+#[derive(Clone, Debug)]
+pub struct OriginPolicy {
+  /// Extra allowed origins, e.g. `https://lab.example.com`
+  allowed_origins: Vec<String>,
+  /// Hosts the built in page may be loaded from
+  page_hosts: Vec<String>,
+  /// Port the built in page is served on
+  http_port: u32,
+  /// Whether the built in page is served over https
+  tls: bool,
+  /// Allow requests without an Origin header
+  allow_no_origin: bool,
+}
+
+impl OriginPolicy {
+  /// Constructor
+  ///
+  /// Unspecified bind addresses, 0.0.0.0 and ::, are not
+  /// page hosts, pages loaded through them must be listed
+  /// in allowed_origins
+  pub fn new(
+    allowed_origins: Vec<String>,
+    bind_addresses: &[IpAddr],
+    http_port: u32,
+    tls: bool,
+    allow_no_origin: bool,
+  ) -> OriginPolicy {
+    let bound_hosts = bind_addresses
+      .iter()
+      .filter(|addr| !addr.is_unspecified())
+      .map(|addr| match *addr {
+        IpAddr::V4(ref ip) => ip.to_string(),
+        IpAddr::V6(ref ip) => format!("[{}]", ip),
+      });
+    OriginPolicy {
+      allowed_origins: allowed_origins
+        .iter()
+        .map(|o| normalize_origin(o))
+        .collect(),
+      page_hosts: LOCAL_HOSTS
+        .iter()
+        .map(|host| host.to_string())
+        .chain(bound_hosts)
+        .collect(),
+      http_port: http_port,
+      tls: tls,
+      allow_no_origin: allow_no_origin,
+    }
+  }
+
+  /// Check the Origin header of an upgrade request
+  pub fn is_allowed(&self, origin: Option<&str>) -> bool {
+    let origin = match origin {
+      None => return self.allow_no_origin,
+      Some(origin) => normalize_origin(origin),
+    };
+    self
+      .allowed_origins
+      .iter()
+      .any(|allowed| allowed == ANY_ORIGIN || *allowed == origin)
+      || self.is_builtin_page(&origin)
+  }
+
+  /// Is the origin the built in page, served from a
+  /// local host name or an address we bind to
+  fn is_builtin_page(&self, origin: &str) -> bool {
+    let scheme = if self.tls { "https" } else { "http" };
+    let origin = match Url::parse(origin) {
+      Ok(url) => url,
+      Err(_) => return false,
+    };
+    let host = match origin.host_str() {
+      Some(host) => host,
+      None => return false,
+    };
+    origin.scheme() == scheme
+      && origin.port_or_known_default() == Some(self.http_port as u16)
+      && self.page_hosts.iter().any(|page_host| page_host == host)
+  }
+}
+
+/// Lowercase and strip any trailing slash so
+/// origins can be compared as strings
+fn normalize_origin(origin: &str) -> String {
+  origin.trim().trim_end_matches('/').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::str::FromStr;
+
+  use super::*;
+
+  fn addrs(addrs: &[&str]) -> Vec<IpAddr> {
+    addrs.iter().map(|a| IpAddr::from_str(a).unwrap()).collect()
+  }
+
+  #[test]
+  fn test_builtin_page() {
+    let policy = OriginPolicy::new(Vec::new(), &addrs(&["127.0.0.1"]), 10080, false, true);
+    assert!(policy.is_allowed(Some("http://127.0.0.1:10080")));
+    assert!(policy.is_allowed(Some("http://LOCALHOST:10080/")));
+    assert!(policy.is_allowed(Some("http://[::1]:10080")));
+    assert!(policy.is_allowed(None));
+
+    assert!(!policy.is_allowed(Some("http://evil.example.com")));
+    assert!(!policy.is_allowed(Some("http://127.0.0.1:8080")));
+    assert!(!policy.is_allowed(Some("https://127.0.0.1:10080")));
+    assert!(!policy.is_allowed(Some("null")));
+    // A rebound name resolving to us is still a foreign origin
+    assert!(!policy.is_allowed(Some("http://rebind.example.com:10080")));
+
+    // Addresses we bind to, but not unspecified ones
+    let policy = OriginPolicy::new(
+      Vec::new(),
+      &addrs(&["10.1.100.12", "fe80::1", "0.0.0.0"]),
+      443,
+      true,
+      false,
+    );
+    assert!(policy.is_allowed(Some("https://10.1.100.12")));
+    assert!(policy.is_allowed(Some("https://[FE80::1]")));
+    assert!(policy.is_allowed(Some("https://localhost")));
+    assert!(!policy.is_allowed(Some("https://0.0.0.0")));
+    assert!(!policy.is_allowed(Some("https://10.1.100.13")));
+    assert!(!policy.is_allowed(None));
+  }
+
+  #[test]
+  fn test_allowed_origins() {
+    let bind = addrs(&["10.1.100.12"]);
+    let policy = OriginPolicy::new(
+      vec!["https://Lab.Example.com/".to_string()],
+      &bind,
+      10080,
+      false,
+      true,
+    );
+    assert!(policy.is_allowed(Some("https://lab.example.com")));
+    assert!(!policy.is_allowed(Some("http://lab.example.com")));
+
+    let policy = OriginPolicy::new(vec![ANY_ORIGIN.to_string()], &bind, 10080, false, true);
+    assert!(policy.is_allowed(Some("http://anything.example.com")));
+  }
+}
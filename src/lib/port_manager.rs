@@ -1,23 +1,80 @@
+//! Opens serial ports and reads / writes them
+//!
+//! Each open port has its own reader thread that blocks on
+//! the device and sends what it reads to the manager, so
+//! reads don't wait on the manager or other ports
+
 use std::collections::{HashMap, HashSet};
-use std::error::Error;
+use std::io;
 use std::iter::FromIterator;
+use std::sync::mpsc::Sender;
+use std::thread;
 use std::time::Duration;
 
 use serialport as sp;
+use thread_control::{make_pair, Control, Flag};
 
 use crate::errors::*;
+use crate::messages::{FlowControl, ManagerEvent, Parity, PortSettings};
+
+/// How long a reader blocks on the port before
+/// checking if it has been told to stop
+pub const READ_TIMEOUT_MS: u64 = 50;
+/// Size of the buffer each reader reads into
+pub const READ_BUFFER_SIZE: usize = 4096;
+/// How long a write may take on top of the time
+/// its bytes need at the port's baud rate, for the
+/// device or flow control holding it up
+pub const WRITE_TIMEOUT_MS: u64 = 1000;
+/// Most bits sent per byte, a start bit, 8 data
+/// bits, a parity bit and 2 stop bits
+const MAX_BITS_PER_BYTE: u64 = 12;
 
 /// Struct for containing Port information
 struct OpenPort {
   /// The opened serial port
   /// SerialPort is not Sized, so it makes hashmap mad
   /// and so we deal with these shennanigans
-  port: Box<sp::SerialPort>,
+  port: Box<dyn sp::SerialPort>,
+  /// The settings the port was opened with
+  settings: PortSettings,
+  /// Tells the reader thread to stop
+  reader_control: Control,
+  /// The reader thread
+  reader: Option<thread::JoinHandle<()>>,
 }
 
 impl OpenPort {
-  /// Write data to the serial port
+  /// Wrap an opened serial port, starting a reader
+  /// thread on a clone of it
+  fn new(
+    port_name: &String,
+    port: Box<dyn sp::SerialPort>,
+    settings: PortSettings,
+    events: Sender<ManagerEvent>,
+  ) -> Result<OpenPort> {
+    let mut reader_port = port.try_clone().map_err(ErrorKind::Serialport)?;
+    reader_port
+      .set_timeout(Duration::from_millis(READ_TIMEOUT_MS))
+      .map_err(ErrorKind::Serialport)?;
+    let (flag, control) = make_pair();
+    let name = port_name.to_string();
+    let reader = thread::spawn(move || read_port(name, reader_port, flag, events));
+    Ok(OpenPort {
+      port: port,
+      settings: settings,
+      reader_control: control,
+      reader: Some(reader),
+    })
+  }
+
+  /// Write data to the serial port, blocking till it is
+  /// all written or the write times out, see write_timeout
   pub fn write_port(&mut self, data: &[u8]) -> Result<()> {
+    self
+      .port
+      .set_timeout(write_timeout(&self.settings, data.len()))
+      .map_err(ErrorKind::Serialport)?;
     self
       .port
       .write_all(data)
@@ -25,26 +82,81 @@ impl OpenPort {
       .map_err(|err| ErrorKind::Io(err).into())
   }
 
-  /// Read data from the serial port
-  pub fn read_port(&mut self, buff: &mut [u8]) -> Result<usize> {
+  /// Apply new settings to the serial port
+  pub fn configure_port(&mut self, settings: &PortSettings) -> Result<()> {
     self
       .port
-      .read(buff)
-      .map_err(|err| ErrorKind::Io(err).into())
+      .set_all(&to_sp_settings(settings))
+      .map_err(ErrorKind::Serialport)?;
+    self.settings = settings.clone();
+    Ok(())
+  }
+}
+
+impl Drop for OpenPort {
+  /// Stop the reader and wait for it, so the port is
+  /// really closed and can be opened again right away
+  fn drop(&mut self) {
+    self.reader_control.stop();
+    if let Some(reader) = self.reader.take() {
+      if reader.join().is_err() {
+        warn!("Reader thread for port panicked");
+      }
+    }
   }
 }
 
+/// Read a port till told to stop or reading fails,
+/// sending the data read to the manager
+fn read_port(
+  port_name: String,
+  mut port: Box<dyn sp::SerialPort>,
+  flag: Flag,
+  events: Sender<ManagerEvent>,
+) {
+  let mut buffer = vec![0; READ_BUFFER_SIZE];
+  while flag.alive() {
+    let result = match port.read(buffer.as_mut_slice()) {
+      Ok(0) => {
+        info!("Received EOF reading from port {}", port_name);
+        Err(Box::new(ErrorKind::PortEOFError(port_name.clone()).into()))
+      }
+      Ok(bytes_read) => Ok(buffer[0..bytes_read].to_vec()),
+      Err(ref e)
+        if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::Interrupted =>
+      {
+        continue
+      }
+      Err(e) => Err(Box::new(ErrorKind::Io(e).into())),
+    };
+    let failed = result.is_err();
+    if events.send(ManagerEvent::PortData(port_name.clone(), result)).is_err() {
+      debug!("Manager went away, stopping reader for port {}", port_name);
+      break;
+    }
+    // The manager cleans up broken ports
+    if failed {
+      break;
+    }
+  }
+  debug!("Reader for port {} stopped", port_name);
+}
+
 /// Manages ports and reading / writing to them
 pub struct PortManager {
   /// Maintains list of ports
   open_ports: HashMap<String, OpenPort>,
+  /// Where port readers send what they read
+  events: Sender<ManagerEvent>,
 }
 
 impl PortManager {
-  /// Create a new PortManager instance
-  pub fn new() -> PortManager {
+  /// Create a new PortManager instance, port readers
+  /// send ManagerEvent::PortData to events
+  pub fn new(events: Sender<ManagerEvent>) -> PortManager {
     PortManager {
       open_ports: HashMap::new(),
+      events: events,
     }
   }
 
@@ -58,31 +170,72 @@ impl PortManager {
     sp::available_ports().map_err(|e| ErrorKind::Serialport(e).into())
   }
 
+  /// Get the settings of an open port
+  pub fn port_settings(&self, port_name: &String) -> Result<PortSettings> {
+    match self.open_ports.get(port_name) {
+      None => Err(ErrorKind::OpenPortNotFound(port_name.to_string()).into()),
+      Some(p) => Ok(p.settings.clone()),
+    }
+  }
+
   /// Open a port
-  pub fn open_port(&mut self, port_name: &String) -> Result<()> {
-    if self.is_port_open(port_name) {
-      Ok(())
-    } else {
-      let sp_settings = sp::SerialPortSettings {
-        baud_rate: sp::BaudRate::Baud115200,
-        data_bits: sp::DataBits::Eight,
-        flow_control: sp::FlowControl::None,
-        parity: sp::Parity::None,
-        stop_bits: sp::StopBits::One,
-        timeout: Duration::from_millis(1),
-      };
-
-      match sp::open_with_settings(&port_name, &sp_settings) {
-        Ok(serial_port) => {
-          let open_port = OpenPort { port: serial_port };
-          self.open_ports.insert(port_name.to_string(), open_port);
-          Ok(())
+  ///
+  /// If settings are None, the port is opened with default
+  /// settings, or if already open, the current settings are kept.
+  ///
+  /// If the port is already open with different settings
+  /// an error is returned.
+  ///
+  /// Returns the settings the port is using
+  pub fn open_port(
+    &mut self,
+    port_name: &String,
+    settings: Option<&PortSettings>,
+  ) -> Result<PortSettings> {
+    if let Some(s) = settings {
+      validate_settings(s)?;
+    }
+    match self.open_ports.get(port_name) {
+      Some(open_port) => match settings {
+        Some(s) if *s != open_port.settings => {
+          Err(ErrorKind::PortSettingsConflict(port_name.to_string()).into())
+        }
+        _ => Ok(open_port.settings.clone()),
+      },
+      None => {
+        let settings = settings.cloned().unwrap_or_default();
+        match sp::open_with_settings(&port_name, &to_sp_settings(&settings)) {
+          Ok(serial_port) => {
+            let open_port = OpenPort::new(
+              port_name,
+              serial_port,
+              settings.clone(),
+              self.events.clone(),
+            )?;
+            self.open_ports.insert(port_name.to_string(), open_port);
+            Ok(settings)
+          }
+          Err(e) => Err(ErrorKind::Serialport(e).into()),
         }
-        Err(e) => Err(ErrorKind::Serialport(e).into()),
       }
     }
   }
 
+  /// Change the settings of an open port
+  ///
+  /// Returns the settings the port is now using
+  pub fn configure_port(
+    &mut self,
+    port_name: &String,
+    settings: &PortSettings,
+  ) -> Result<PortSettings> {
+    validate_settings(settings)?;
+    match self.open_ports.get_mut(port_name) {
+      None => Err(ErrorKind::OpenPortNotFound(port_name.to_string()).into()),
+      Some(p) => p.configure_port(settings).map(|_| p.settings.clone()),
+    }
+  }
+
   /// Close a port
   pub fn close_port(&mut self, port_name: &String) {
     // This drops the underlying serial port and box
@@ -97,53 +250,73 @@ impl PortManager {
     }
   }
 
-  /// Read data from a port into the buffer buff
-  /// If successful, returns Ok(usize) which is the number of
-  /// bytes read
-  pub fn read_port(&mut self, port_name: &String, buff: &mut [u8]) -> Result<usize> {
-    match self.open_ports.get_mut(port_name) {
-      None => Err(ErrorKind::OpenPortNotFound(port_name.to_string()).into()),
-      Some(p) => p.read_port(buff),
-    }
+  /// Get a set of open ports
+  pub fn open_ports(&self) -> HashSet<String> {
+    HashSet::<String>::from_iter(self.open_ports.keys().cloned())
   }
+}
 
-  /// Read all currently open ports, return a hashmap of
-  /// ports to Result<Vec<u8>>
-  pub fn read_all_ports(&mut self) -> HashMap<String, Result<Vec<u8>>> {
-    let mut buffer = vec![0; 4096];
-    let mut map = HashMap::new();
-    for (port_name, open_port) in self.open_ports.iter_mut() {
-      match open_port.read_port(buffer.as_mut_slice()) {
-        Ok(bytes_read) => {
-          if bytes_read == 0 {
-            // EOF
-            info!("Received EOF reading from port {}", port_name);
-            map.insert(
-              port_name.to_string(),
-              Err(ErrorKind::PortEOFError(port_name.clone()).into()),
-            );
-          } else {
-            let bytes = buffer[0..bytes_read].to_vec();
-            map.insert(port_name.to_string(), Ok(bytes));
-          }
-        }
-        Err(e) => {
-          // debug!("Error {} reading from port {}", e, port_name);
-          match e.description() {
-            "Operation timed out" => {}
-            _ => {
-              map.insert(port_name.to_string(), Err(e.into()));
-            }
-          }
-        }
-      }
-    }
-    map
+/// Check that settings are valid
+fn validate_settings(settings: &PortSettings) -> Result<()> {
+  if settings.baud_rate == 0 {
+    return Err(
+      ErrorKind::InvalidPortSettings("baud_rate must be greater than 0".to_string()).into(),
+    );
+  }
+  if settings.data_bits < 5 || settings.data_bits > 8 {
+    return Err(
+      ErrorKind::InvalidPortSettings(format!(
+        "data_bits must be 5, 6, 7 or 8, got {}",
+        settings.data_bits
+      ))
+      .into(),
+    );
   }
+  if settings.stop_bits < 1 || settings.stop_bits > 2 {
+    return Err(
+      ErrorKind::InvalidPortSettings(format!(
+        "stop_bits must be 1 or 2, got {}",
+        settings.stop_bits
+      ))
+      .into(),
+    );
+  }
+  Ok(())
+}
 
-  /// Get a set of open ports
-  pub fn open_ports(&self) -> HashSet<String> {
-    HashSet::<String>::from_iter(self.open_ports.keys().map(|k| k.clone()))
+/// How long writing len bytes to a port may take, the time
+/// they need at the port's baud rate plus WRITE_TIMEOUT_MS
+fn write_timeout(settings: &PortSettings, len: usize) -> Duration {
+  let bits = len as u64 * MAX_BITS_PER_BYTE;
+  Duration::from_millis(WRITE_TIMEOUT_MS + bits * 1000 / settings.baud_rate as u64)
+}
+
+/// Convert settings to serialport settings
+/// Settings must have been validated first
+fn to_sp_settings(settings: &PortSettings) -> sp::SerialPortSettings {
+  sp::SerialPortSettings {
+    baud_rate: sp::BaudRate::from(settings.baud_rate),
+    data_bits: match settings.data_bits {
+      5 => sp::DataBits::Five,
+      6 => sp::DataBits::Six,
+      7 => sp::DataBits::Seven,
+      _ => sp::DataBits::Eight,
+    },
+    flow_control: match settings.flow_control {
+      FlowControl::None => sp::FlowControl::None,
+      FlowControl::Software => sp::FlowControl::Software,
+      FlowControl::Hardware => sp::FlowControl::Hardware,
+    },
+    parity: match settings.parity {
+      Parity::None => sp::Parity::None,
+      Parity::Odd => sp::Parity::Odd,
+      Parity::Even => sp::Parity::Even,
+    },
+    stop_bits: match settings.stop_bits {
+      2 => sp::StopBits::Two,
+      _ => sp::StopBits::One,
+    },
+    timeout: Duration::from_millis(WRITE_TIMEOUT_MS),
   }
 }
 
@@ -152,6 +325,7 @@ mod tests {
 
   use std::io::Read;
   use std::io::Write;
+  use std::sync::mpsc::channel;
 
   use serialport::posix::TTYPort;
   use serialport::SerialPort;
@@ -170,34 +344,30 @@ mod tests {
     let serial_msg = "abcdefg";
 
     if let Some(s_name) = slave.port_name() {
-      let mut port_manager = PortManager::new();
+      let (events_tx, events_rx) = channel::<ManagerEvent>();
+      let mut port_manager = PortManager::new(events_tx);
 
       port_manager
-        .open_port(&s_name)
-        .expect(&format!("Failed to open slave port {}", s_name));
+        .open_port(&s_name, None)
+        .unwrap_or_else(|_| panic!("Failed to open slave port {}", s_name));
 
-      // Write to master, read from slave via port manager
+      // Write to master, the port reader sends what it read from slave
       {
         master
-          .write(serial_msg.as_bytes())
+          .write_all(serial_msg.as_bytes())
           .expect("Write to master failed!");
 
-        let res = port_manager.read_all_ports();
-
-        assert_eq!(res.len(), 1, "Should have read one port.");
-
-        for (port_name, value) in res {
-          match value {
-            Ok(bytes) => {
-              let read_msg = String::from_utf8_lossy(&bytes);
-              assert_eq!(
-                serial_msg, read_msg,
-                "Messages should be same '{}' '{}'",
-                serial_msg, read_msg
-              );
-            }
-            Err(e) => panic!("Got error reading port {}", e),
+        match events_rx.recv_timeout(Duration::from_secs(1)) {
+          Ok(ManagerEvent::PortData(port_name, Ok(bytes))) => {
+            assert_eq!(port_name, s_name, "Data should be from the slave port");
+            let read_msg = String::from_utf8_lossy(&bytes);
+            assert_eq!(
+              serial_msg, read_msg,
+              "Messages should be same '{}' '{}'",
+              serial_msg, read_msg
+            );
           }
+          other => panic!("Expected data read from port, got {:?}", other),
         }
       }
 
@@ -219,8 +389,133 @@ mod tests {
           panic!("Reading master failed!");
         }
       }
+
+      // Closing the port stops its reader
+      {
+        port_manager.close_port(&s_name);
+        master
+          .write_all(serial_msg.as_bytes())
+          .expect("Write to master failed!");
+        assert!(
+          events_rx.recv_timeout(Duration::from_millis(200)).is_err(),
+          "Closed port should not be read"
+        );
+      }
     } else {
       panic!("Failed to get slave pty name");
     }
   }
+
+  #[test]
+  #[cfg(unix)]
+  fn test_slow_writes() {
+    let (mut master, mut slave) = TTYPort::pair().expect("Failed to create pseudoterminal pair!");
+    slave
+      .set_exclusive(false)
+      .expect("Failed to set exclusive false");
+    let s_name = slave.port_name().expect("Failed to get slave pty name");
+    let (events_tx, _events_rx) = channel::<ManagerEvent>();
+    let mut port_manager = PortManager::new(events_tx);
+    port_manager
+      .open_port(&s_name, None)
+      .expect("Opening port failed");
+
+    // More than the pty buffers, so writes have to wait
+    // for the other end to catch up
+    let data = vec![b'a'; 1024];
+    let len = 256 * data.len();
+    let reader = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(100));
+      let mut read = 0;
+      let mut buffer = vec![0; 4096];
+      while read < len {
+        read += master.read(&mut buffer).expect("Reading master failed");
+      }
+      // Closing the master would fail the last flush
+      (read, master)
+    });
+    for _ in 0..256 {
+      port_manager
+        .write_port(&s_name, &data)
+        .expect("Slow write should not time out");
+    }
+    assert_eq!(reader.join().unwrap().0, len);
+
+    // Slow ports get longer
+    let slow = PortSettings {
+      baud_rate: 9600,
+      ..PortSettings::default()
+    };
+    assert_eq!(
+      write_timeout(&slow, 0),
+      Duration::from_millis(WRITE_TIMEOUT_MS)
+    );
+    assert_eq!(
+      write_timeout(&slow, 960),
+      Duration::from_millis(WRITE_TIMEOUT_MS + 1200)
+    );
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn test_port_settings() {
+    let (_master, mut slave) = TTYPort::pair().expect("Failed to create pseudoterminal pair!");
+
+    slave
+      .set_exclusive(false)
+      .expect("Failed to set exclusive false");
+
+    let s_name = slave.port_name().expect("Failed to get slave pty name");
+    let (events_tx, _events_rx) = channel::<ManagerEvent>();
+    let mut port_manager = PortManager::new(events_tx);
+
+    let settings = PortSettings {
+      baud_rate: 9600,
+      ..PortSettings::default()
+    };
+
+    // Invalid settings should be rejected
+    let bad_settings = PortSettings {
+      data_bits: 9,
+      ..PortSettings::default()
+    };
+    assert!(
+      port_manager.open_port(&s_name, Some(&bad_settings)).is_err(),
+      "Opening with 9 data bits should fail"
+    );
+    assert!(!port_manager.is_port_open(&s_name), "Port should not be open");
+
+    // Opening with settings should echo them back
+    let opened = port_manager
+      .open_port(&s_name, Some(&settings))
+      .expect("Opening port with settings failed");
+    assert_eq!(opened, settings, "Opened settings should match requested");
+
+    // Opening again without settings keeps existing ones
+    let reopened = port_manager
+      .open_port(&s_name, None)
+      .expect("Reopening port without settings failed");
+    assert_eq!(reopened, settings, "Reopened settings should be unchanged");
+
+    // Opening again with different settings is a conflict
+    match port_manager.open_port(&s_name, Some(&PortSettings::default())) {
+      Err(Error(ErrorKind::PortSettingsConflict(_), _)) => {}
+      other => panic!("Expected settings conflict, got {:?}", other),
+    }
+
+    // Reconfiguring changes the settings of the open port
+    let fast_settings = PortSettings {
+      baud_rate: 921600,
+      ..PortSettings::default()
+    };
+    let configured = port_manager
+      .configure_port(&s_name, &fast_settings)
+      .expect("Reconfiguring port failed");
+    assert_eq!(configured, fast_settings, "Configured settings should match");
+    assert_eq!(
+      port_manager.port_settings(&s_name).unwrap(),
+      fast_settings,
+      "Port settings should be updated"
+    );
+  }
 }
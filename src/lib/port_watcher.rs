@@ -0,0 +1,148 @@
+//! Watches the list of available serial ports
+//! and detects ports being added or removed
+//! so clients can be notified of hotplug events
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::messages::*;
+
+/// How often the available ports are rescanned
+pub const PORT_SCAN_INTERVAL_MS: u64 = 1000;
+
+/// Tracks the available serial ports between scans
+pub struct PortWatcher {
+  /// Ports seen on the last scan, None until
+  /// the first scan has completed
+  known_ports: Option<HashMap<String, PortInfo>>,
+  /// Time between scans
+  interval: Duration,
+  /// When the last scan happened
+  last_scan: Option<Instant>,
+}
+
+impl PortWatcher {
+  /// Create a new PortWatcher with the default scan interval
+  pub fn new() -> PortWatcher {
+    PortWatcher::with_interval(Duration::from_millis(PORT_SCAN_INTERVAL_MS))
+  }
+
+  /// Create a new PortWatcher with a custom scan interval
+  pub fn with_interval(interval: Duration) -> PortWatcher {
+    PortWatcher {
+      known_ports: None,
+      interval: interval,
+      last_scan: None,
+    }
+  }
+
+  /// Is it time to scan the ports again
+  pub fn is_scan_due(&self) -> bool {
+    match self.last_scan {
+      None => true,
+      Some(last) => last.elapsed() >= self.interval,
+    }
+  }
+
+  /// How long until the next scan is due
+  pub fn time_until_scan(&self) -> Duration {
+    match self.last_scan {
+      None => Duration::from_millis(0),
+      Some(last) => {
+        let elapsed = last.elapsed();
+        match elapsed < self.interval {
+          true => self.interval - elapsed,
+          false => Duration::from_millis(0),
+        }
+      }
+    }
+  }
+
+  /// Update the known ports with the results of a scan,
+  /// returning PortAdded and PortRemoved responses for
+  /// any changes since the previous scan
+  ///
+  /// The first scan only records the ports and
+  /// returns no responses
+  pub fn update(&mut self, ports: Vec<PortInfo>) -> Vec<SerialResponse> {
+    self.last_scan = Some(Instant::now());
+    let current: HashMap<String, PortInfo> = ports
+      .into_iter()
+      .map(|p| (p.port_name.clone(), p))
+      .collect();
+    let mut responses = Vec::new();
+    if let Some(ref known) = self.known_ports {
+      for (port_name, info) in current.iter() {
+        if !known.contains_key(port_name) {
+          responses.push(SerialResponse::PortAdded {
+            port: port_name.clone(),
+            info: info.clone(),
+          });
+        }
+      }
+      for port_name in known.keys() {
+        if !current.contains_key(port_name) {
+          responses.push(SerialResponse::PortRemoved {
+            port: port_name.clone(),
+          });
+        }
+      }
+    }
+    self.known_ports = Some(current);
+    responses
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  fn port(name: &str) -> PortInfo {
+    PortInfo {
+      port_name: name.to_string(),
+      port_type: PortType::Unknown,
+    }
+  }
+
+  #[test]
+  fn test_time_until_scan() {
+    let mut watcher = PortWatcher::with_interval(Duration::from_secs(60));
+    assert_eq!(watcher.time_until_scan(), Duration::from_millis(0));
+    watcher.update(Vec::new());
+    assert!(!watcher.is_scan_due(), "Scan should not be due");
+    assert!(watcher.time_until_scan() > Duration::from_secs(59));
+  }
+
+  #[test]
+  fn test_port_changes() {
+    let mut watcher = PortWatcher::with_interval(Duration::from_millis(0));
+    assert!(watcher.is_scan_due(), "First scan should be due");
+
+    // First scan only records ports
+    let resps = watcher.update(vec![port("/dev/ttyUSB0"), port("/dev/ttyUSB1")]);
+    assert_eq!(resps.len(), 0, "First scan should not report changes");
+    assert_eq!(watcher.time_until_scan(), Duration::from_millis(0));
+
+    // No changes
+    let resps = watcher.update(vec![port("/dev/ttyUSB0"), port("/dev/ttyUSB1")]);
+    assert_eq!(resps.len(), 0, "Unchanged ports should not report changes");
+
+    // One added, one removed
+    let resps = watcher.update(vec![port("/dev/ttyUSB0"), port("/dev/ttyACM0")]);
+    assert_eq!(resps.len(), 2, "Should report one added and one removed port");
+    assert!(
+      resps.contains(&SerialResponse::PortAdded {
+        port: "/dev/ttyACM0".to_string(),
+        info: port("/dev/ttyACM0"),
+      }),
+      "/dev/ttyACM0 should be added"
+    );
+    assert!(
+      resps.contains(&SerialResponse::PortRemoved {
+        port: "/dev/ttyUSB1".to_string(),
+      }),
+      "/dev/ttyUSB1 should be removed"
+    );
+  }
+}
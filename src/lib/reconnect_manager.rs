@@ -0,0 +1,213 @@
+//! Keeps track of sticky ports that were lost
+//! so they can be reopened when they come back
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::messages::{PortSettings, WritePolicy};
+use crate::sub_manager::PortOptions;
+
+/// Delay before the first reconnect attempt
+pub const RECONNECT_INITIAL_BACKOFF_MS: u64 = 500;
+/// Longest delay between reconnect attempts
+pub const RECONNECT_MAX_BACKOFF_MS: u64 = 30_000;
+
+/// State remembered for a lost port
+#[derive(Clone, Debug)]
+pub struct LostPort {
+  /// Settings the port was open with
+  pub settings: PortSettings,
+  /// Subscriptions to restore
+  pub subscribers: Vec<(String, PortOptions)>,
+  /// Sub id holding the write lock when the port was lost
+  pub lock_holder: Option<String>,
+  /// Lease of the write lock, restarted when it is restored
+  pub lock_lease: Option<Duration>,
+  /// Write policy the port was opened with
+  pub write_policy: WritePolicy,
+  /// Current delay between attempts
+  backoff: Duration,
+  /// When to try reopening next
+  next_attempt: Instant,
+}
+
+/// Tracks lost sticky ports and when to retry them
+pub struct ReconnectManager {
+  /// Map of port name to lost port state
+  lost_ports: HashMap<String, LostPort>,
+}
+
+impl ReconnectManager {
+  /// Create a new ReconnectManager instance
+  pub fn new() -> ReconnectManager {
+    ReconnectManager {
+      lost_ports: HashMap::new(),
+    }
+  }
+
+  /// Remember a lost port, its sticky subscribers and lock holder
+  ///
+  /// Nothing is remembered if there are no subscribers
+  pub fn add_lost_port(
+    &mut self,
+    port_name: &String,
+    settings: PortSettings,
+    subscribers: Vec<(String, PortOptions)>,
+    lock_holder: Option<String>,
+    lock_lease: Option<Duration>,
+    write_policy: WritePolicy,
+  ) {
+    if subscribers.is_empty() {
+      return;
+    }
+    let backoff = Duration::from_millis(RECONNECT_INITIAL_BACKOFF_MS);
+    self.lost_ports.insert(
+      port_name.to_string(),
+      LostPort {
+        settings: settings,
+        subscribers: subscribers,
+        lock_holder: lock_holder,
+        lock_lease: lock_lease,
+        write_policy: write_policy,
+        backoff: backoff,
+        next_attempt: Instant::now() + backoff,
+      },
+    );
+  }
+
+  /// Is the port waiting to be reconnected
+  pub fn is_port_lost(&self, port_name: &String) -> bool {
+    self.lost_ports.contains_key(port_name)
+  }
+
+  /// Ports whose next reconnect attempt is due
+  pub fn ports_due(&self) -> Vec<String> {
+    let now = Instant::now();
+    self
+      .lost_ports
+      .iter()
+      .filter(|&(_, lp)| lp.next_attempt <= now)
+      .map(|(port_name, _)| port_name.to_string())
+      .collect()
+  }
+
+  /// How long until the next reconnect attempt is due,
+  /// None if no ports are lost
+  pub fn time_until_due(&self) -> Option<Duration> {
+    let now = Instant::now();
+    self
+      .lost_ports
+      .values()
+      .map(|lp| match lp.next_attempt > now {
+        true => lp.next_attempt - now,
+        false => Duration::from_millis(0),
+      })
+      .min()
+  }
+
+  /// Get the settings a lost port should be reopened with
+  pub fn settings(&self, port_name: &String) -> Option<PortSettings> {
+    self.lost_ports.get(port_name).map(|lp| lp.settings.clone())
+  }
+
+  /// A reconnect attempt failed, back off before the next one
+  pub fn attempt_failed(&mut self, port_name: &String) {
+    if let Some(lp) = self.lost_ports.get_mut(port_name) {
+      lp.backoff = cmp::min(
+        lp.backoff * 2,
+        Duration::from_millis(RECONNECT_MAX_BACKOFF_MS),
+      );
+      lp.next_attempt = Instant::now() + lp.backoff;
+    }
+  }
+
+  /// The port was reopened, stop tracking it and return
+  /// the state to restore
+  pub fn reconnected(&mut self, port_name: &String) -> Option<LostPort> {
+    self.lost_ports.remove(port_name)
+  }
+
+  /// Forget a subscriber for a single lost port, the port is
+  /// no longer tracked if no subscribers remain
+  pub fn remove_sub(&mut self, port_name: &String, sub_id: &String) {
+    let empty = match self.lost_ports.get_mut(port_name) {
+      None => false,
+      Some(lp) => {
+        lp.subscribers.retain(|&(ref sid, _)| sid != sub_id);
+        if lp.lock_holder.as_ref() == Some(sub_id) {
+          lp.lock_holder = None;
+        }
+        lp.subscribers.is_empty()
+      }
+    };
+    if empty {
+      self.lost_ports.remove(port_name);
+    }
+  }
+
+  /// Forget a subscriber for all lost ports
+  pub fn remove_sub_from_all(&mut self, sub_id: &String) {
+    let port_names: Vec<String> = self.lost_ports.keys().cloned().collect();
+    for port_name in port_names.iter() {
+      self.remove_sub(port_name, sub_id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_lost_ports() {
+    let mut reconnect_manager = ReconnectManager::new();
+    let port = "/dev/ttyUSB0".to_string();
+    let sub1 = "SUB1".to_string();
+    let sub2 = "SUB2".to_string();
+    let sticky = PortOptions {
+      sticky: true,
+      ..PortOptions::default()
+    };
+
+    // Ports without subscribers are not tracked
+    reconnect_manager.add_lost_port(
+      &port,
+      PortSettings::default(),
+      Vec::new(),
+      None,
+      None,
+      WritePolicy::Exclusive,
+    );
+    assert!(!reconnect_manager.is_port_lost(&port), "Port should not be tracked");
+    assert_eq!(reconnect_manager.time_until_due(), None);
+
+    reconnect_manager.add_lost_port(
+      &port,
+      PortSettings::default(),
+      vec![(sub1.clone(), sticky.clone()), (sub2.clone(), sticky.clone())],
+      Some(sub1.clone()),
+      None,
+      WritePolicy::Shared,
+    );
+    assert!(reconnect_manager.is_port_lost(&port), "Port should be tracked");
+    assert_eq!(
+      reconnect_manager.ports_due().len(),
+      0,
+      "No attempt should be due right away"
+    );
+    assert!(
+      reconnect_manager.time_until_due().unwrap() > Duration::from_millis(0),
+      "First attempt should be in the future"
+    );
+
+    // Removing the lock holder also forgets the lock
+    reconnect_manager.remove_sub(&port, &sub1);
+    assert!(reconnect_manager.is_port_lost(&port), "Port should be tracked");
+
+    // Removing the last subscriber stops tracking the port
+    reconnect_manager.remove_sub_from_all(&sub2);
+    assert!(!reconnect_manager.is_port_lost(&port), "Port should not be tracked");
+  }
+}
@@ -1,37 +1,168 @@
 use std::collections::{HashMap, HashSet};
-use std::sync::mpsc::Sender;
 
+use crate::auth::Identity;
+use crate::encoding::ReadEncoder;
 use crate::errors::*;
+use crate::framing::Framer;
 use crate::messages::*;
+use crate::sub_queue::QueueSender;
+
+/// Per port options for a subscription,
+/// given when the port is opened
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PortOptions {
+  /// Keep the subscription and write lock if the port
+  /// disappears, and restore them when it comes back
+  pub sticky: bool,
+  /// Encoding for data read from the port
+  pub encoding: Encoding,
+  /// How to split data read from the port into frames,
+  /// if None data is sent as it is read
+  pub framing: Option<Framing>,
+  /// Receive what other clients write to the port
+  pub echo: bool,
+}
+
+/// A port a subscription is registered for
+struct SubscribedPort {
+  /// Options the port was opened with
+  options: PortOptions,
+  /// Encodes data read from the port
+  encoder: ReadEncoder,
+  /// Splits data read from the port into frames
+  framer: Option<Framer>,
+}
+
+impl SubscribedPort {
+  /// Create a new SubscribedPort
+  fn new(options: PortOptions) -> SubscribedPort {
+    SubscribedPort {
+      encoder: ReadEncoder::new(options.encoding),
+      framer: options.framing.clone().map(Framer::new),
+      options: options,
+    }
+  }
+}
 
 /// Subscription
 struct Subscription {
   /// Subscription
-  subscriber: Sender<SerialResponse>,
+  subscriber: QueueSender,
+  /// Send port data as raw bytes
+  binary: bool,
+  /// Who the client authenticated as
+  identity: Option<Identity>,
   /// The ports it is subscribed to
-  ports: Vec<String>,
+  ports: HashMap<String, SubscribedPort>,
 }
 
 impl Subscription {
-  /// Send a message to a subscriber
-  fn send_message(&self, msg: SerialResponse) -> Result<()> {
+  /// Send a notice to a subscriber, which may be
+  /// dropped if its queue is full
+  fn send_message(&self, msg: ResponseEnvelope) -> Result<()> {
+    self.subscriber.send(SubscriberMessage::Response(msg))
+  }
+
+  /// Send a reply to a subscriber, which is never dropped
+  fn send_reply(&self, msg: ResponseEnvelope) -> Result<()> {
+    self.subscriber.send_reply(SubscriberMessage::Response(msg))
+  }
+
+  /// Send data read from a port to a subscriber
+  ///
+  /// If the port was opened with framing, the data is buffered
+  /// and only complete frames are sent.
+  fn send_data(&mut self, port_name: &String, data: &[u8]) -> Result<()> {
+    let chunks = match self.ports.get_mut(port_name) {
+      None => return Ok(()),
+      Some(sub_port) => match sub_port.framer {
+        Some(ref mut framer) => framer.push(data),
+        None => vec![data.to_vec()],
+      },
+    };
+    for chunk in chunks {
+      self.send_chunk(port_name, chunk)?;
+    }
+    Ok(())
+  }
+
+  /// Send any partial frames whose flush timeout has passed
+  fn flush_partial_frames(&mut self) -> Result<()> {
+    let mut flushed = Vec::new();
+    for (port_name, sub_port) in self.ports.iter_mut() {
+      if let Some(chunk) = sub_port.framer.as_mut().and_then(|f| f.flush_if_due()) {
+        flushed.push((port_name.to_string(), chunk));
+      }
+    }
+    for (port_name, chunk) in flushed {
+      self.send_chunk(&port_name, chunk)?;
+    }
+    Ok(())
+  }
+
+  /// Are partial frames waiting to be flushed
+  fn has_pending_flush(&self) -> bool {
     self
-      .subscriber
-      .send(msg)
-      .map_err(|e| ErrorKind::SendResponse(e).into())
+      .ports
+      .values()
+      .any(|sub_port| sub_port.framer.as_ref().is_some_and(|f| f.has_pending_flush()))
+  }
+
+  /// Send a chunk of port data to a subscriber
+  ///
+  /// Binary subscribers get the raw bytes, others get
+  /// a SerialResponse::Read with the data encoded using
+  /// the encoding the port was opened with
+  fn send_chunk(&mut self, port_name: &String, chunk: Vec<u8>) -> Result<()> {
+    let framed = match self.ports.get(port_name) {
+      None => return Ok(()),
+      Some(sub_port) => sub_port.framer.is_some(),
+    };
+    let msg = match (self.binary, self.ports.get_mut(port_name)) {
+      (_, None) => return Ok(()),
+      (true, Some(_)) => SubscriberMessage::Data {
+        port: port_name.to_string(),
+        data: chunk,
+      },
+      (false, Some(sub_port)) => {
+        let (encoded, encoding) = sub_port.encoder.encode(&chunk);
+        // Utf8 decoding may hold back an incomplete character
+        if encoded.is_empty() {
+          return Ok(());
+        }
+        let response = SerialResponse::Read {
+          port: port_name.to_string(),
+          data: encoded,
+          base64: Some(encoding == Encoding::Base64),
+          encoding: Some(encoding),
+        };
+        SubscriberMessage::Response(response.into())
+      }
+    };
+    let msg = match framed {
+      true => SubscriberMessage::Frame(Box::new(msg)),
+      false => msg,
+    };
+    self.subscriber.send(msg)
   }
 
   /// Register interest in a port
-  fn add_port(&mut self, port_name: &String) {
-    match self.ports.iter().position(|p| p == port_name) {
-      None => self.ports.push(port_name.to_string()),
-      Some(_) => debug!("Port already subscribed to"),
+  /// If already subscribed with different options, the options
+  /// are updated
+  fn add_port(&mut self, port_name: &String, options: PortOptions) {
+    match self.ports.get(port_name) {
+      Some(sub_port) if sub_port.options == options => debug!("Port already subscribed to"),
+      _ => {
+        self
+          .ports
+          .insert(port_name.to_string(), SubscribedPort::new(options));
+      }
     }
   }
 
   /// Remove interest in a port
   fn remove_port(&mut self, port_name: &String) {
-    self.ports.retain(|p| *p != *port_name);
+    self.ports.remove(port_name);
   }
 
   /// Remove all ports from this subscription
@@ -58,10 +189,15 @@ impl SubscriptionManager {
   }
 
   /// Add a port to a subscription
-  pub fn add_port(&mut self, sub_id: &String, port_name: &String) -> Result<()> {
+  pub fn add_port(
+    &mut self,
+    sub_id: &String,
+    port_name: &String,
+    options: PortOptions,
+  ) -> Result<()> {
     match self.subscriptions.get_mut(sub_id) {
       Some(sub) => {
-        sub.add_port(port_name);
+        sub.add_port(port_name, options);
         Ok(())
       }
       None => Err(ErrorKind::SubscriptionNotFound(sub_id.to_string()).into()),
@@ -72,7 +208,7 @@ impl SubscriptionManager {
   pub fn remove_port(&mut self, sub_id: &String, port_name: &String) -> Result<()> {
     match self.subscriptions.get_mut(sub_id) {
       Some(sub) => {
-        sub.ports.retain(|p| p != port_name);
+        sub.remove_port(port_name);
         Ok(())
       }
       None => Err(ErrorKind::SubscriptionNotFound(sub_id.to_string()).into()),
@@ -82,19 +218,42 @@ impl SubscriptionManager {
   /// Remove a port from all subscriptions
   pub fn remove_port_from_all(&mut self, port_name: &String) {
     for (_, sub) in self.subscriptions.iter_mut() {
-      sub.ports.retain(|p| p != port_name);
+      sub.remove_port(port_name);
     }
   }
 
+  /// Get the options a subscription opened a port with,
+  /// None if the subscription is not subscribed to the port
+  pub fn port_options(&self, sub_id: &String, port_name: &String) -> Option<PortOptions> {
+    self
+      .subscriptions
+      .get(sub_id)
+      .and_then(|sub| sub.ports.get(port_name).map(|p| p.options.clone()))
+  }
+
+  /// Get the sub ids and options of all subscriptions
+  /// registered for a given port
+  pub fn subscribers_for_port(&self, port_name: &String) -> Vec<(String, PortOptions)> {
+    self
+      .subscriptions
+      .iter()
+      .filter_map(|(sub_id, sub)| {
+        sub
+          .ports
+          .get(port_name)
+          .map(|p| (sub_id.to_string(), p.options.clone()))
+      })
+      .collect()
+  }
+
   /// Remove all ports from a single subscription or all subscriptions
   pub fn clear_ports(&mut self, sub_id: Option<&String>) {
     match sub_id {
-      Some(sid) => match self.subscriptions.get_mut(sid) {
-        Some(sub) => {
+      Some(sid) => {
+        if let Some(sub) = self.subscriptions.get_mut(sid) {
           sub.ports.clear();
         }
-        None => {}
-      },
+      }
       None => {
         for (_, sub) in self.subscriptions.iter_mut() {
           sub.ports.clear();
@@ -110,7 +269,9 @@ impl SubscriptionManager {
       .entry(sub.sub_id)
       .or_insert(Subscription {
         subscriber: sub.subscriber,
-        ports: Vec::new(),
+        binary: sub.binary,
+        identity: sub.identity,
+        ports: HashMap::new(),
       });
   }
 
@@ -122,24 +283,53 @@ impl SubscriptionManager {
     }
   }
 
+  /// Get who a subscription authenticated as
+  pub fn identity(&self, sub_id: &String) -> Result<Option<&Identity>> {
+    self
+      .subscriptions
+      .get(sub_id)
+      .map(|sub| sub.identity.as_ref())
+      .ok_or_else(|| ErrorKind::SubscriptionNotFound(sub_id.to_string()).into())
+  }
+
   /// End a subscription
   pub fn end_subscription(&mut self, sub_id: &String) {
     self.subscriptions.remove(sub_id);
   }
 
-  /// Send a message to the given subscription
+  /// Send a notice to the given subscription, which
+  /// may be dropped if the subscriber's queue is full
   pub fn send_message(&self, sub_id: &String, msg: SerialResponse) -> Result<()> {
+    self.send_to(sub_id, |sub| sub.send_message(msg.into()))
+  }
+
+  /// Send a reply to a request with the given id to the given subscription
+  pub fn send_reply(
+    &self,
+    sub_id: &String,
+    id: Option<String>,
+    msg: SerialResponse,
+  ) -> Result<()> {
+    let envelope = ResponseEnvelope {
+      id: id,
+      response: msg,
+    };
+    self.send_to(sub_id, |sub| sub.send_reply(envelope))
+  }
+
+  /// Send to the given subscription
+  fn send_to<F>(&self, sub_id: &String, send: F) -> Result<()>
+  where
+    F: FnOnce(&Subscription) -> Result<()>,
+  {
     match self.subscriptions.get(sub_id) {
       None => Err(ErrorKind::SubscriptionNotFound(sub_id.to_string()).into()),
-      Some(sub) => sub
-        .subscriber
-        .send(msg)
-        .map_err(|e| ErrorKind::SendResponse(e).into()),
+      Some(sub) => send(sub).chain_err(|| ErrorKind::SubscriberSendError(sub_id.to_string())),
     }
   }
 
   /// Broadcast a messages to all subscribers, returning
-  /// a vec of (sub_id,ErrorKind::SendResponse) failures if some sends fail
+  /// a vec of ErrorKind::SubscriberSendError failures if some sends fail
   pub fn broadcast_message(&self, msg: SerialResponse) -> Vec<Error> {
     debug!("Broadcasting '{}' to all subscribers", &msg);
     let mut res = Vec::new();
@@ -154,14 +344,69 @@ impl SubscriptionManager {
 
   /// Broadcast message to all subscribers registered for a given port
   pub fn broadcast_message_for_port(&self, port_name: &String, msg: SerialResponse) -> Vec<Error> {
+    self.broadcast_message_for_port_except(port_name, None, msg)
+  }
+
+  /// Broadcast message to all subscribers registered for a given port,
+  /// optionally skipping one subscriber
+  pub fn broadcast_message_for_port_except(
+    &self,
+    port_name: &String,
+    except_sub_id: Option<&String>,
+    msg: SerialResponse,
+  ) -> Vec<Error> {
     debug!(
       "Broadcasting '{}' to all subscribers registered on port {}",
       &msg, port_name
     );
+    self.broadcast_for_port_with(port_name, except_sub_id, |_| Some(msg.clone()))
+  }
+
+  /// Echo data written to a port by the given client to the
+  /// subscribers that opened the port with echo set, encoded
+  /// with the encoding each of them chose
+  pub fn broadcast_echo_for_port(
+    &self,
+    port_name: &String,
+    writer_sub_id: &String,
+    by: &String,
+    data: &[u8],
+  ) -> Vec<Error> {
+    self.broadcast_for_port_with(port_name, Some(writer_sub_id), |options| {
+      if !options.echo {
+        return None;
+      }
+      let (encoded, encoding) = ReadEncoder::encode_whole(options.encoding, data);
+      Some(SerialResponse::Sent {
+        port: port_name.to_string(),
+        data: encoded,
+        base64: Some(encoding == Encoding::Base64),
+        encoding: Some(encoding),
+        by: by.to_string(),
+      })
+    })
+  }
+
+  /// Broadcast the message built from each subscriber's options to
+  /// the subscribers registered for a given port, skipping those
+  /// it builds no message for, and optionally one more subscriber
+  fn broadcast_for_port_with<F>(
+    &self,
+    port_name: &String,
+    except_sub_id: Option<&String>,
+    build: F,
+  ) -> Vec<Error>
+  where
+    F: Fn(&PortOptions) -> Option<SerialResponse>,
+  {
     let mut res = Vec::new();
     for (sub_id, sub) in self.subscriptions.iter() {
-      if sub.ports.iter().position(|p| *p == *port_name).is_some() {
-        match self.send_message(sub_id, msg.clone()) {
+      if Some(sub_id) == except_sub_id {
+        continue;
+      }
+      let msg = sub.ports.get(port_name).and_then(|port| build(&port.options));
+      if let Some(msg) = msg {
+        match self.send_message(sub_id, msg) {
           Err(e) => res.push(e),
           _ => debug!("  Broadcast to '{}' was successful", sub_id),
         }
@@ -170,11 +415,48 @@ impl SubscriptionManager {
     res
   }
 
+  /// Send data read from a port to all subscribers registered for it
+  pub fn broadcast_data_for_port(&mut self, port_name: &String, data: &[u8]) -> Vec<Error> {
+    let mut res = Vec::new();
+    for (sub_id, sub) in self.subscriptions.iter_mut() {
+      if let Err(e) = sub.send_data(port_name, data) {
+        res.push(Error::with_chain(
+          e,
+          ErrorKind::SubscriberSendError(sub_id.to_string()),
+        ));
+      }
+    }
+    res
+  }
+
+  /// Send partial frames that timed out to all subscribers
+  pub fn flush_partial_frames(&mut self) -> Vec<Error> {
+    let mut res = Vec::new();
+    for (sub_id, sub) in self.subscriptions.iter_mut() {
+      if let Err(e) = sub.flush_partial_frames() {
+        res.push(Error::with_chain(
+          e,
+          ErrorKind::SubscriberSendError(sub_id.to_string()),
+        ));
+      }
+    }
+    res
+  }
+
+  /// Are partial frames waiting to be flushed
+  /// for any subscriber
+  pub fn has_pending_flush(&self) -> bool {
+    self
+      .subscriptions
+      .values()
+      .any(|sub| sub.has_pending_flush())
+  }
+
   /// Get a list of ports that currently have subscriptions
   pub fn subscribed_ports(&mut self) -> HashSet<String> {
     let mut subscribed_ports = HashSet::<String>::new();
     for subs in self.subscriptions.values() {
-      subscribed_ports.extend(subs.ports.clone());
+      subscribed_ports.extend(subs.ports.keys().cloned());
     }
     subscribed_ports
   }
@@ -185,20 +467,20 @@ mod tests {
 
   use std::collections::HashSet;
   use std::iter::FromIterator;
-  use std::sync::mpsc::{channel, Receiver};
 
   use super::*;
+  use crate::sub_queue::*;
 
   #[test]
   fn test_subscriptions() {
     fn should_get_msg(
-      rcvr: &Receiver<SerialResponse>,
+      rcvr: &QueueReceiver,
       serial_resp: &SerialResponse,
       fail_tag: &str,
     ) {
-      if let Ok(resp) = rcvr.try_recv() {
+      if let Ok(SubscriberMessage::Response(resp)) = rcvr.try_recv() {
         assert_eq!(
-          resp,
+          resp.response,
           serial_resp.clone(),
           "{} messages '{:?}' '{:?}' should be equal",
           fail_tag,
@@ -210,7 +492,7 @@ mod tests {
       }
     }
 
-    fn should_not_get_a_msg(rcvr: &Receiver<SerialResponse>, fail_tag: &str) {
+    fn should_not_get_a_msg(rcvr: &QueueReceiver, fail_tag: &str) {
       if let Ok(resp) = rcvr.try_recv() {
         panic!(
           "{} should not have recieved anything, got {:?}",
@@ -226,23 +508,27 @@ mod tests {
     let ports_set = HashSet::from_iter(ports.clone().into_iter().map(|p| p.to_string()));
     // subscriber1
     let sub1_id = "SUB1";
-    let sub1_channel = channel::<SerialResponse>();
+    let sub1_channel = sub_queue(DEFAULT_QUEUE_LENGTH, OverflowPolicy::default());
     let sub1_req = SubscriptionRequest {
       sub_id: sub1_id.to_string(),
       subscriber: sub1_channel.0,
+      binary: false,
+      identity: None,
     };
     // subscriber 2
     let sub2_id = "SUB2";
-    let sub2_channel = channel::<SerialResponse>();
+    let sub2_channel = sub_queue(DEFAULT_QUEUE_LENGTH, OverflowPolicy::default());
     let sub2_req = SubscriptionRequest {
       sub_id: sub2_id.to_string(),
       subscriber: sub2_channel.0,
+      binary: true,
+      identity: None,
     };
     // Add subscriber 1
     sub_manager.add_subscription(sub1_req);
     for port in ports.iter() {
       sub_manager
-        .add_port(&sub1_id.to_string(), &port.to_string())
+        .add_port(&sub1_id.to_string(), &port.to_string(), PortOptions::default())
         .unwrap();
     }
     // Sub1 should be subscribed to all ports
@@ -258,7 +544,7 @@ mod tests {
       msg: "Broadcast all!".to_string(),
     };
     let mut all_res = sub_manager.broadcast_message(all_subscribers_msg.clone());
-    assert!(all_res.len() == 0, "There should be no errors");
+    assert!(all_res.is_empty(), "There should be no errors");
     should_get_msg(&sub1_channel.1, &all_subscribers_msg, "Subscriber 1");
     should_get_msg(&sub2_channel.1, &all_subscribers_msg, "Subscriber 2");
     // Send message to one subscriber
@@ -270,9 +556,19 @@ mod tests {
       .expect("Send to subscriber 1 should work!");
     should_get_msg(&sub1_channel.1, &sub1_msg, "Subscriber 1");
     should_not_get_a_msg(&sub2_channel.1, "Subscriber 2");
+    // Replies carry the request id
+    sub_manager
+      .send_reply(&sub1_id.to_string(), Some("42".to_string()), sub1_msg.clone())
+      .expect("Reply to subscriber 1 should work!");
+    match sub1_channel.1.try_recv() {
+      Ok(SubscriberMessage::Response(resp)) => {
+        assert_eq!(resp.id, Some("42".to_string()), "Reply id should match")
+      }
+      _ => panic!("Subscriber 1 should have received a reply"),
+    }
     // Send message to subscribers of a given port
     all_res = sub_manager.broadcast_message_for_port(&"/dev/ttyUSB2".to_string(), sub1_msg.clone());
-    assert!(all_res.len() == 0, "There should be no errors");
+    assert!(all_res.is_empty(), "There should be no errors");
     should_get_msg(&sub1_channel.1, &sub1_msg, "Subscriber 1");
     should_not_get_a_msg(&sub2_channel.1, "Subscriber 2");
     // unsubscribe sub1 from /dev/ttyUSB2
@@ -281,9 +577,62 @@ mod tests {
       .expect("remvoving sub1 from port should not fail.");
     // No one should get message on ttyUSB2 now
     all_res = sub_manager.broadcast_message_for_port(&"/dev/ttyUSB2".to_string(), sub1_msg.clone());
-    assert!(all_res.len() == 0, "There should be no errors");
+    assert!(all_res.is_empty(), "There should be no errors");
     should_not_get_a_msg(&sub1_channel.1, "Subscriber 1");
     should_not_get_a_msg(&sub2_channel.1, "Subscriber 2");
+    // Port data is sent as json to sub1 and raw bytes to binary sub2
+    let port = "/dev/ttyUSB0".to_string();
+    sub_manager
+      .add_port(&sub2_id.to_string(), &port, PortOptions::default())
+      .unwrap();
+    all_res = sub_manager.broadcast_data_for_port(&port, b"Hello");
+    assert!(all_res.is_empty(), "There should be no errors");
+    should_get_msg(
+      &sub1_channel.1,
+      &SerialResponse::Read {
+        port: port.clone(),
+        data: "Hello".to_string(),
+        base64: Some(false),
+        encoding: Some(Encoding::Utf8),
+      },
+      "Subscriber 1",
+    );
+    match sub2_channel.1.try_recv() {
+      Ok(SubscriberMessage::Data { port: p, data }) => {
+        assert_eq!(p, port, "Data port should match");
+        assert_eq!(data, b"Hello".to_vec(), "Data should be raw bytes");
+      }
+      other => panic!("Subscriber 2 should have received data, got {:?}", other),
+    }
+    // Writes are only echoed to subscribers that asked
+    // in the encoding they opened the port with
+    let echo = PortOptions {
+      echo: true,
+      encoding: Encoding::Hex,
+      ..PortOptions::default()
+    };
+    sub_manager
+      .add_port(&sub2_id.to_string(), &port, echo)
+      .unwrap();
+    let by = "writer".to_string();
+    all_res = sub_manager.broadcast_echo_for_port(&port, &sub1_id.to_string(), &by, b"Hi");
+    assert!(all_res.is_empty(), "There should be no errors");
+    should_not_get_a_msg(&sub1_channel.1, "Subscriber 1");
+    let sent = SerialResponse::Sent {
+      port: port.clone(),
+      data: "4869".to_string(),
+      base64: Some(false),
+      encoding: Some(Encoding::Hex),
+      by: by,
+    };
+    should_get_msg(&sub2_channel.1, &sent, "Subscriber 2");
+    // Sends to a client that went away name the subscription
+    drop(sub2_channel.1);
+    all_res = sub_manager.broadcast_message(sub1_msg.clone());
+    match all_res.pop() {
+      Some(Error(ErrorKind::SubscriberSendError(sub_id), _)) => assert_eq!(sub_id, sub2_id),
+      other => panic!("Expected send error for subscriber 2, got {:?}", other),
+    }
+    assert!(all_res.is_empty(), "Only subscriber 2 should fail");
   }
-
 }
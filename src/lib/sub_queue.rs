@@ -0,0 +1,580 @@
+//! Bounded queues for messages from the manager
+//! to websocket clients
+//!
+//! When a client can't keep up and its queue fills, port
+//! data and notices are dropped according to the queue's
+//! overflow policy, and the client is sent a
+//! SerialResponse::DataDropped or NoticesDropped notice.
+//! Replies to requests are never dropped.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::common::query_param;
+use crate::errors::*;
+use crate::messages::*;
+
+/// Default max number of messages queued for a client
+pub const DEFAULT_QUEUE_LENGTH: usize = 1000;
+/// Query string parameter for the queue length
+pub const QUEUE_LENGTH_QUERY_PARAM: &str = "queue_length";
+/// Query string parameter for the overflow policy
+pub const OVERFLOW_QUERY_PARAM: &str = "overflow";
+
+/// What to do with data read from a port when
+/// a client's queue is full
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum OverflowPolicy {
+  /// Drop the oldest queued data to make room
+  #[default]
+  DropOldest,
+  /// Drop the new data
+  DropNewest,
+  /// Disconnect the client
+  Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+  type Err = Error;
+
+  /// Parse a policy name, ignoring case
+  fn from_str(s: &str) -> Result<OverflowPolicy> {
+    match s.trim().to_lowercase().as_str() {
+      "dropoldest" => Ok(OverflowPolicy::DropOldest),
+      "dropnewest" => Ok(OverflowPolicy::DropNewest),
+      "disconnect" => Ok(OverflowPolicy::Disconnect),
+      _ => Err(ErrorKind::InvalidOverflowPolicy(s.to_string()).into()),
+    }
+  }
+}
+
+/// Length and overflow policy of a client's queue
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueOptions {
+  /// Max number of messages queued
+  pub length: usize,
+  /// What to do with port data when the queue is full
+  pub policy: OverflowPolicy,
+}
+
+impl QueueOptions {
+  /// Apply options a client gave in the query string of
+  /// its websocket url, for example `/?overflow=DropNewest`.
+  ///
+  /// Clients can only ask for shorter queues. Bad values
+  /// are ignored
+  pub fn with_query(self, path: &str) -> QueueOptions {
+    QueueOptions {
+      length: query_param(path, QUEUE_LENGTH_QUERY_PARAM)
+        .and_then(|v| v.parse::<usize>().ok())
+        .map(|length| length.max(1).min(self.length))
+        .unwrap_or(self.length),
+      policy: query_param(path, OVERFLOW_QUERY_PARAM)
+        .and_then(|v| v.parse::<OverflowPolicy>().ok())
+        .unwrap_or(self.policy),
+    }
+  }
+}
+
+/// Create a queue holding up to length messages
+pub fn sub_queue(length: usize, policy: OverflowPolicy) -> (QueueSender, QueueReceiver) {
+  let shared = Arc::new(Shared {
+    state: Mutex::new(State {
+      messages: VecDeque::new(),
+      senders: 1,
+      receiver_alive: true,
+      waker: None,
+    }),
+    ready: Condvar::new(),
+  });
+  let sender = QueueSender {
+    shared: shared.clone(),
+    length: length,
+    policy: policy,
+  };
+  (sender, QueueReceiver { shared: shared })
+}
+
+/// State shared by both ends of a queue
+struct Shared {
+  /// The queue
+  state: Mutex<State>,
+  /// Signalled when a message is queued
+  /// or the last sender goes away
+  ready: Condvar,
+}
+
+impl Shared {
+  /// Lock the state, a panic while holding the
+  /// lock doesn't leave it inconsistent
+  fn lock(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+/// A queued message
+struct Queued {
+  /// The message
+  msg: SubscriberMessage,
+  /// May it be dropped when the queue is full,
+  /// replies and drop notices may not
+  droppable: bool,
+}
+
+/// Messages and the ends still connected
+struct State {
+  /// Queued messages
+  messages: VecDeque<Queued>,
+  /// Number of live senders
+  senders: usize,
+  /// Is the receiver still around
+  receiver_alive: bool,
+  /// Called along with the condvar, so a receiver
+  /// can wait on other things too
+  waker: Option<Box<dyn Fn() + Send>>,
+}
+
+impl State {
+  /// Wake a receiver waiting through its waker
+  fn wake(&self) {
+    if let Some(ref waker) = self.waker {
+      waker();
+    }
+  }
+
+  /// Record a dropped message, as dropped port
+  /// data or as a dropped notice
+  fn record_dropped(&mut self, msg: &SubscriberMessage) {
+    match msg.port_data_len() {
+      Some((port_name, bytes)) => self.add_dropped(port_name, bytes),
+      None => self.add_dropped_notice(),
+    }
+  }
+
+  /// Record dropped port data, adding to a queued
+  /// notice for the port if there is one
+  fn add_dropped(&mut self, port_name: String, dropped: usize) {
+    for queued in self.messages.iter_mut().rev() {
+      if let SubscriberMessage::Response(ResponseEnvelope {
+        id: None,
+        response: SerialResponse::DataDropped {
+          ref port,
+          ref mut bytes,
+        },
+      }) = queued.msg
+      {
+        if *port == port_name {
+          *bytes += dropped;
+          return;
+        }
+      }
+    }
+    self.push_notice(SerialResponse::DataDropped {
+      port: port_name,
+      bytes: dropped,
+    });
+  }
+
+  /// Record a dropped notice, adding to a
+  /// queued count if there is one
+  fn add_dropped_notice(&mut self) {
+    for queued in self.messages.iter_mut().rev() {
+      if let SubscriberMessage::Response(ResponseEnvelope {
+        id: None,
+        response: SerialResponse::NoticesDropped { ref mut count },
+      }) = queued.msg
+      {
+        *count += 1;
+        return;
+      }
+    }
+    self.push_notice(SerialResponse::NoticesDropped { count: 1 });
+  }
+
+  /// Queue a notice about dropped messages, which
+  /// is not dropped itself
+  fn push_notice(&mut self, notice: SerialResponse) {
+    self.messages.push_back(Queued {
+      msg: SubscriberMessage::Response(notice.into()),
+      droppable: false,
+    });
+  }
+}
+
+/// Sending end of a queue, used by the manager
+pub struct QueueSender {
+  /// The queue
+  shared: Arc<Shared>,
+  /// Max messages queued
+  length: usize,
+  /// What to do when the queue is full
+  policy: OverflowPolicy,
+}
+
+impl QueueSender {
+  /// Queue port data or a notice
+  ///
+  /// Fails if the receiver went away, or if the queue
+  /// is full and the policy is OverflowPolicy::Disconnect
+  pub fn send(&self, msg: SubscriberMessage) -> Result<()> {
+    self.push(msg, true)
+  }
+
+  /// Queue a reply to a request, which is queued
+  /// even if the queue is full
+  ///
+  /// Fails if the receiver went away
+  pub fn send_reply(&self, msg: SubscriberMessage) -> Result<()> {
+    self.push(msg, false)
+  }
+
+  /// Queue a message, making room for it if it is droppable
+  /// and the queue is full, according to the policy
+  fn push(&self, msg: SubscriberMessage, droppable: bool) -> Result<()> {
+    let mut state = self.shared.lock();
+    if !state.receiver_alive {
+      return Err(ErrorKind::SubscriberGone.into());
+    }
+    if droppable && state.messages.len() >= self.length {
+      let oldest = match self.policy {
+        OverflowPolicy::Disconnect => return Err(ErrorKind::SubscriberQueueFull.into()),
+        OverflowPolicy::DropNewest => None,
+        OverflowPolicy::DropOldest => state
+          .messages
+          .iter()
+          .position(|queued| queued.droppable)
+          .and_then(|pos| state.messages.remove(pos)),
+      };
+      match oldest {
+        Some(oldest) => state.record_dropped(&oldest.msg),
+        // Nothing older to drop, so drop the new message
+        None => {
+          state.record_dropped(&msg);
+          state.wake();
+          self.shared.ready.notify_one();
+          return Ok(());
+        }
+      }
+    }
+    state.messages.push_back(Queued {
+      msg: msg,
+      droppable: droppable,
+    });
+    state.wake();
+    self.shared.ready.notify_one();
+    Ok(())
+  }
+}
+
+impl Clone for QueueSender {
+  fn clone(&self) -> QueueSender {
+    self.shared.lock().senders += 1;
+    QueueSender {
+      shared: self.shared.clone(),
+      length: self.length,
+      policy: self.policy,
+    }
+  }
+}
+
+impl Drop for QueueSender {
+  /// Wake the receiver if this was the last sender
+  fn drop(&mut self) {
+    let mut state = self.shared.lock();
+    state.senders -= 1;
+    if state.senders == 0 {
+      state.wake();
+    }
+    self.shared.ready.notify_all();
+  }
+}
+
+impl fmt::Debug for QueueSender {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "QueueSender {{ length: {}, policy: {:?} }}",
+      self.length, self.policy
+    )
+  }
+}
+
+/// Receiving end of a queue, used by the websocket handler
+pub struct QueueReceiver {
+  /// The queue
+  shared: Arc<Shared>,
+}
+
+impl QueueReceiver {
+  /// Call waker whenever a message is queued or the last
+  /// sender goes away, as well as right away if either
+  /// already happened
+  pub fn set_waker<F>(&self, waker: F)
+  where
+    F: Fn() + Send + 'static,
+  {
+    let mut state = self.shared.lock();
+    if !state.messages.is_empty() || state.senders == 0 {
+      waker();
+    }
+    state.waker = Some(Box::new(waker));
+  }
+
+  /// Wait up to timeout for a message
+  pub fn recv_timeout(
+    &self,
+    timeout: Duration,
+  ) -> ::std::result::Result<SubscriberMessage, RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.shared.lock();
+    loop {
+      if let Some(queued) = state.messages.pop_front() {
+        return Ok(queued.msg);
+      }
+      if state.senders == 0 {
+        return Err(RecvTimeoutError::Disconnected);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(RecvTimeoutError::Timeout);
+      }
+      state = self
+        .shared
+        .ready
+        .wait_timeout(state, deadline - now)
+        .unwrap_or_else(|e| e.into_inner())
+        .0;
+    }
+  }
+
+  /// Take a message if one is queued
+  pub fn try_recv(&self) -> ::std::result::Result<SubscriberMessage, TryRecvError> {
+    let mut state = self.shared.lock();
+    match state.messages.pop_front() {
+      Some(queued) => Ok(queued.msg),
+      None if state.senders == 0 => Err(TryRecvError::Disconnected),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+
+  /// Take up to max queued messages without waiting
+  pub fn drain(&self, max: usize) -> Vec<SubscriberMessage> {
+    let mut state = self.shared.lock();
+    let count = max.min(state.messages.len());
+    state.messages.drain(..count).map(|queued| queued.msg).collect()
+  }
+}
+
+impl Drop for QueueReceiver {
+  /// Let senders know nobody is listening anymore
+  fn drop(&mut self) {
+    let mut state = self.shared.lock();
+    state.receiver_alive = false;
+    state.messages.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  fn data(port: &str, len: usize) -> SubscriberMessage {
+    SubscriberMessage::Data {
+      port: port.to_string(),
+      data: vec![0; len],
+    }
+  }
+
+  fn reply(msg: &str) -> SubscriberMessage {
+    SubscriberMessage::Response(
+      SerialResponse::Ok {
+        msg: msg.to_string(),
+      }
+      .into(),
+    )
+  }
+
+  fn notice(port: &str) -> SubscriberMessage {
+    SubscriberMessage::Response(
+      SerialResponse::WriteLockChanged {
+        port: port.to_string(),
+        holder: None,
+      }
+      .into(),
+    )
+  }
+
+  fn dropped(port: &str, bytes: usize) -> SubscriberMessage {
+    SubscriberMessage::Response(
+      SerialResponse::DataDropped {
+        port: port.to_string(),
+        bytes: bytes,
+      }
+      .into(),
+    )
+  }
+
+  #[test]
+  fn test_drop_oldest() {
+    let (tx, rx) = sub_queue(2, OverflowPolicy::DropOldest);
+    tx.send(data("/dev/ttyUSB0", 1)).unwrap();
+    tx.send_reply(reply("a")).unwrap();
+    tx.send(data("/dev/ttyUSB0", 3)).unwrap();
+    tx.send(data("/dev/ttyUSB0", 5)).unwrap();
+    // Replies are kept, dropped data adds up in one notice
+    assert_eq!(
+      rx.drain(10),
+      vec![
+        reply("a"),
+        dropped("/dev/ttyUSB0", 4),
+        data("/dev/ttyUSB0", 5)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_drop_newest() {
+    let (tx, rx) = sub_queue(2, OverflowPolicy::DropNewest);
+    tx.send(data("/dev/ttyUSB0", 1)).unwrap();
+    tx.send(data("/dev/ttyUSB1", 2)).unwrap();
+    tx.send(data("/dev/ttyUSB0", 3)).unwrap();
+    tx.send(data("/dev/ttyUSB0", 4)).unwrap();
+    tx.send_reply(reply("a")).unwrap();
+    assert_eq!(
+      rx.drain(10),
+      vec![
+        data("/dev/ttyUSB0", 1),
+        data("/dev/ttyUSB1", 2),
+        dropped("/dev/ttyUSB0", 7),
+        reply("a")
+      ]
+    );
+  }
+
+  #[test]
+  fn test_notices_are_bounded() {
+    let (tx, rx) = sub_queue(2, OverflowPolicy::DropOldest);
+    tx.send_reply(reply("a")).unwrap();
+    tx.send(notice("/dev/ttyUSB0")).unwrap();
+    tx.send(notice("/dev/ttyUSB1")).unwrap();
+    tx.send(notice("/dev/ttyUSB2")).unwrap();
+    // Only replies may go past the limit
+    tx.send_reply(reply("b")).unwrap();
+    let notices_dropped = SubscriberMessage::Response(
+      SerialResponse::NoticesDropped { count: 2 }.into(),
+    );
+    assert_eq!(
+      rx.drain(10),
+      vec![
+        reply("a"),
+        notices_dropped,
+        notice("/dev/ttyUSB2"),
+        reply("b")
+      ]
+    );
+
+    // Echoed writes are port data
+    let (tx, rx) = sub_queue(1, OverflowPolicy::DropNewest);
+    let sent = SerialResponse::Sent {
+      port: "/dev/ttyUSB0".to_string(),
+      data: "abc".to_string(),
+      base64: Some(false),
+      encoding: Some(Encoding::Utf8),
+      by: "SUB1".to_string(),
+    };
+    tx.send(notice("/dev/ttyUSB0")).unwrap();
+    tx.send(SubscriberMessage::Response(sent.into())).unwrap();
+    assert_eq!(
+      rx.drain(10),
+      vec![notice("/dev/ttyUSB0"), dropped("/dev/ttyUSB0", 3)]
+    );
+  }
+
+  #[test]
+  fn test_disconnect() {
+    let (tx, rx) = sub_queue(1, OverflowPolicy::Disconnect);
+    tx.send(data("/dev/ttyUSB0", 1)).unwrap();
+    match tx.send(data("/dev/ttyUSB0", 1)) {
+      Err(Error(ErrorKind::SubscriberQueueFull, _)) => {}
+      other => panic!("Expected queue full, got {:?}", other),
+    }
+    // Replies are queued anyway
+    tx.send_reply(reply("a")).unwrap();
+    drop(rx);
+    match tx.send_reply(reply("a")) {
+      Err(Error(ErrorKind::SubscriberGone, _)) => {}
+      other => panic!("Expected subscriber gone, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_receive() {
+    let (tx, rx) = sub_queue(DEFAULT_QUEUE_LENGTH, OverflowPolicy::default());
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(
+      rx.recv_timeout(Duration::from_millis(1)),
+      Err(RecvTimeoutError::Timeout)
+    );
+    let tx2 = tx.clone();
+    tx2.send(reply("a")).unwrap();
+    drop(tx2);
+    assert_eq!(rx.recv_timeout(Duration::from_millis(1)), Ok(reply("a")));
+    drop(tx);
+    assert_eq!(
+      rx.recv_timeout(Duration::from_millis(1)),
+      Err(RecvTimeoutError::Disconnected)
+    );
+
+    assert_eq!(
+      "dropNewest".parse::<OverflowPolicy>().unwrap(),
+      OverflowPolicy::DropNewest
+    );
+    assert!("drop".parse::<OverflowPolicy>().is_err());
+  }
+
+  #[test]
+  fn test_waker() {
+    let (tx, rx) = sub_queue(1, OverflowPolicy::DropNewest);
+    let wakes = Arc::new(AtomicUsize::new(0));
+    tx.send(reply("a")).unwrap();
+    let counter = wakes.clone();
+    rx.set_waker(move || {
+      counter.fetch_add(1, Ordering::SeqCst);
+    });
+    // Already queued messages wake right away
+    assert_eq!(wakes.load(Ordering::SeqCst), 1);
+    // So do dropped messages, for the drop notice
+    tx.send(reply("b")).unwrap();
+    assert_eq!(wakes.load(Ordering::SeqCst), 2);
+    let tx2 = tx.clone();
+    drop(tx);
+    assert_eq!(wakes.load(Ordering::SeqCst), 2);
+    drop(tx2);
+    assert_eq!(wakes.load(Ordering::SeqCst), 3);
+  }
+
+  #[test]
+  fn test_queue_options() {
+    let options = QueueOptions {
+      length: 100,
+      policy: OverflowPolicy::DropOldest,
+    };
+    assert_eq!(options.with_query("/"), options);
+    assert_eq!(
+      options.with_query("/ws?token=abc&queue_length=10&overflow=Disconnect"),
+      QueueOptions {
+        length: 10,
+        policy: OverflowPolicy::Disconnect,
+      }
+    );
+    assert_eq!(options.with_query("/?queue_length=5000").length, 100);
+    assert_eq!(options.with_query("/?queue_length=0").length, 1);
+    assert_eq!(options.with_query("/?overflow=bogus"), options);
+  }
+}
@@ -0,0 +1,156 @@
+//! Loads TLS certificates and keys for serving
+//! the HTTP page and websockets over https:// and wss://
+
+use std::fs::File;
+use std::io::prelude::*;
+
+use native_tls::{Identity, TlsAcceptor};
+use openssl::pkcs12::Pkcs12 as OpensslPkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+
+use crate::errors::*;
+
+/// Password protecting the in memory PKCS#12 bundle
+const PKCS12_PASSWORD: &str = "wsss";
+
+/// Create a TLS acceptor from a PEM certificate chain
+/// file and a PEM private key file
+pub fn load_tls_acceptor(cert_file: &str, key_file: &str) -> Result<TlsAcceptor> {
+  let cert_pem = read_file(cert_file)?;
+  let key_pem = read_file(key_file)?;
+
+  // native-tls only accepts PKCS#12 bundles, so build one in memory
+  let mut certs = X509::stack_from_pem(&cert_pem)?;
+  if certs.is_empty() {
+    return Err(ErrorKind::TlsConfig(format!("no certificate found in '{}'", cert_file)).into());
+  }
+  let cert = certs.remove(0);
+  let key = PKey::private_key_from_pem(&key_pem)?;
+  let mut builder = OpensslPkcs12::builder();
+  builder.name("wsss").pkey(&key).cert(&cert);
+  // Any remaining certificates are the intermediate chain
+  if !certs.is_empty() {
+    let mut chain = Stack::new()?;
+    for c in certs {
+      chain.push(c)?;
+    }
+    builder.ca(chain);
+  }
+  let der = builder.build2(PKCS12_PASSWORD)?.to_der()?;
+
+  let identity = Identity::from_pkcs12(&der, PKCS12_PASSWORD)?;
+  let acceptor = TlsAcceptor::new(identity)?;
+  Ok(acceptor)
+}
+
+/// Read a whole file
+fn read_file(file_name: &str) -> Result<Vec<u8>> {
+  let mut file = File::open(file_name)
+    .map_err(|e| ErrorKind::TlsConfig(format!("could not open '{}', {}", file_name, e)))?;
+  let mut contents = Vec::new();
+  file.read_to_end(&mut contents)?;
+  Ok(contents)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+  extern crate tempfile;
+
+  use std::io::{Read, Write};
+  use std::net::{TcpListener, TcpStream};
+  use std::thread;
+
+  use native_tls::{Certificate, TlsConnector};
+  use openssl::asn1::Asn1Time;
+  use openssl::bn::BigNum;
+  use openssl::hash::MessageDigest;
+  use openssl::rsa::Rsa;
+  use openssl::x509::{X509Builder, X509NameBuilder};
+
+  use self::tempfile::NamedTempFile;
+  use super::*;
+
+  /// Generate a self signed certificate for localhost,
+  /// returning the certificate and key as PEM
+  pub(crate) fn self_signed_cert() -> (Vec<u8>, Vec<u8>) {
+    let rsa = Rsa::generate(2048).unwrap();
+    let key = PKey::from_rsa(rsa).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+      .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+      .unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+      .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+      .unwrap();
+    builder
+      .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+      .unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert = builder.build();
+    (cert.to_pem().unwrap(), key.private_key_to_pem_pkcs8().unwrap())
+  }
+
+  /// Load an acceptor for a new self signed certificate,
+  /// returning it with a connector that trusts the certificate
+  pub(crate) fn self_signed_acceptor() -> (TlsAcceptor, TlsConnector) {
+    let (cert_pem, key_pem) = self_signed_cert();
+    let mut cert_file = NamedTempFile::new().expect("Creating temp file failed");
+    cert_file.write_all(&cert_pem).unwrap();
+    let mut key_file = NamedTempFile::new().expect("Creating temp file failed");
+    key_file.write_all(&key_pem).unwrap();
+
+    let acceptor = load_tls_acceptor(
+      &cert_file.path().to_string_lossy(),
+      &key_file.path().to_string_lossy(),
+    )
+    .expect("Loading TLS acceptor failed");
+
+    let root = Certificate::from_der(&X509::from_pem(&cert_pem).unwrap().to_der().unwrap())
+      .unwrap();
+    let connector = TlsConnector::builder()
+      .add_root_certificate(root)
+      .build()
+      .unwrap();
+    (acceptor, connector)
+  }
+
+  #[test]
+  fn test_tls_handshake() {
+    let (acceptor, connector) = self_signed_acceptor();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let (stream, _) = listener.accept().unwrap();
+      let mut tls_stream = acceptor.accept(stream).expect("Server handshake failed");
+      tls_stream.write_all(b"hello").unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut tls_stream = connector
+      .connect("localhost", stream)
+      .expect("Client handshake failed");
+    let mut msg = String::new();
+    tls_stream.read_to_string(&mut msg).unwrap();
+    assert_eq!(msg, "hello", "Should read message over TLS");
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn test_missing_files() {
+    assert!(
+      load_tls_acceptor("/nonexistent/cert.pem", "/nonexistent/key.pem").is_err(),
+      "Loading missing files should fail"
+    );
+  }
+}
@@ -0,0 +1,175 @@
+//! Tracks write and read transactions, where a client
+//! writes a command to a port and collects the reply
+
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::framing::MAX_FRAME_LENGTH;
+
+/// Default time to wait for a transaction reply
+pub const DEFAULT_TRANSACT_TIMEOUT_MS: u64 = 1000;
+/// Longer timeouts are cut to this, while a transaction
+/// waits the port's data is held back from other subscribers
+pub const MAX_TRANSACT_TIMEOUT_MS: u64 = 60 * 1000;
+/// Replies that reach this length without ending fail
+pub const MAX_TRANSACT_REPLY_LENGTH: usize = MAX_FRAME_LENGTH;
+
+/// Check transaction options sent by a client
+pub fn check_transaction(max_bytes: Option<usize>) -> Result<()> {
+  if max_bytes == Some(0) {
+    return Err(ErrorKind::InvalidTransaction("max_bytes must be greater than 0".to_string()).into());
+  }
+  if max_bytes.is_some_and(|max| max > MAX_TRANSACT_REPLY_LENGTH) {
+    let reason = format!("max_bytes must be at most {}", MAX_TRANSACT_REPLY_LENGTH);
+    return Err(ErrorKind::InvalidTransaction(reason).into());
+  }
+  Ok(())
+}
+
+/// A pending transaction on a port
+#[derive(Clone, Debug)]
+pub struct Transaction {
+  /// Sub id of the client that started the transaction
+  pub sub_id: String,
+  /// Id of the request that started the transaction
+  pub request_id: Option<String>,
+  /// Reply is complete once this is seen
+  terminator: Option<Vec<u8>>,
+  /// Reply is complete once this many bytes are read
+  max_bytes: Option<usize>,
+  /// Reply is complete when this passes
+  deadline: Instant,
+  /// Reply data collected so far
+  buffer: Vec<u8>,
+  /// Did the reply reach MAX_TRANSACT_REPLY_LENGTH
+  /// without ending
+  too_long: bool,
+}
+
+impl Transaction {
+  /// Create a new transaction, timeout_ms is
+  /// cut to MAX_TRANSACT_TIMEOUT_MS
+  pub fn new(
+    sub_id: &String,
+    request_id: Option<String>,
+    terminator: Option<Vec<u8>>,
+    max_bytes: Option<usize>,
+    timeout_ms: Option<u64>,
+  ) -> Transaction {
+    let timeout_ms = timeout_ms
+      .unwrap_or(DEFAULT_TRANSACT_TIMEOUT_MS)
+      .min(MAX_TRANSACT_TIMEOUT_MS);
+    let timeout = Duration::from_millis(timeout_ms);
+    Transaction {
+      sub_id: sub_id.to_string(),
+      request_id: request_id,
+      terminator: terminator.filter(|t| !t.is_empty()),
+      max_bytes: max_bytes,
+      deadline: Instant::now() + timeout,
+      buffer: Vec::new(),
+      too_long: false,
+    }
+  }
+
+  /// Add data read from the port.
+  ///
+  /// Returns None if the reply is not complete yet, otherwise
+  /// the data read after the end of the reply, which does not
+  /// belong to the transaction. A reply that reaches
+  /// MAX_TRANSACT_REPLY_LENGTH is complete, but too long
+  pub fn push(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+    let start = self.buffer.len();
+    self.buffer.extend_from_slice(data);
+    let end = match self.reply_end(start) {
+      Some(end) => end,
+      None if self.buffer.len() >= MAX_TRANSACT_REPLY_LENGTH => {
+        self.too_long = true;
+        MAX_TRANSACT_REPLY_LENGTH
+      }
+      None => return None,
+    };
+    Some(self.buffer.split_off(end))
+  }
+
+  /// Did the reply reach MAX_TRANSACT_REPLY_LENGTH without ending
+  pub fn is_too_long(&self) -> bool {
+    self.too_long
+  }
+
+  /// Has the transaction timed out
+  pub fn is_expired(&self) -> bool {
+    Instant::now() >= self.deadline
+  }
+
+  /// Take the collected reply data
+  pub fn into_reply(self) -> Vec<u8> {
+    self.buffer
+  }
+
+  /// Find the end of the reply in the buffer, only searching
+  /// for the terminator in data added since start
+  fn reply_end(&self, start: usize) -> Option<usize> {
+    let terminated = self.terminator.as_ref().and_then(|t| {
+      // The terminator may straddle the previous data
+      let from = start.saturating_sub(t.len() - 1);
+      self.buffer[from..]
+        .windows(t.len())
+        .position(|w| w == &t[..])
+        .map(|i| from + i + t.len())
+    });
+    let limited = self.max_bytes.filter(|&max| self.buffer.len() >= max);
+    match (terminated, limited) {
+      (Some(t), Some(m)) => Some(t.min(m)),
+      (t, m) => t.or(m),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_transaction_end() {
+    let sub_id = "SUB1".to_string();
+
+    // Terminator split across reads, extra data is left over
+    let mut trans = Transaction::new(&sub_id, None, Some(b"OK\r\n".to_vec()), None, None);
+    assert_eq!(trans.push(b"+CSQ: 20\r\nO"), None);
+    assert_eq!(trans.push(b"K\r\nRING"), Some(b"RING".to_vec()));
+    assert_eq!(trans.into_reply(), b"+CSQ: 20\r\nOK\r\n".to_vec());
+
+    // Byte count
+    let mut trans = Transaction::new(&sub_id, None, None, Some(4), None);
+    assert_eq!(trans.push(b"ab"), None);
+    assert_eq!(trans.push(b"cdef"), Some(b"ef".to_vec()));
+    assert_eq!(trans.into_reply(), b"abcd".to_vec());
+
+    // Timeout only
+    let trans = Transaction::new(&sub_id, None, None, None, Some(0));
+    assert!(trans.is_expired(), "Transaction should have timed out");
+  }
+
+  #[test]
+  fn test_transaction_limits() {
+    let sub_id = "SUB1".to_string();
+    assert!(check_transaction(None).is_ok());
+    assert!(check_transaction(Some(MAX_TRANSACT_REPLY_LENGTH)).is_ok());
+    assert!(check_transaction(Some(0)).is_err());
+    assert!(check_transaction(Some(MAX_TRANSACT_REPLY_LENGTH + 1)).is_err());
+
+    // Timeouts are cut to the max
+    let trans = Transaction::new(&sub_id, None, None, None, Some(u64::MAX));
+    assert!(trans.deadline <= Instant::now() + Duration::from_millis(MAX_TRANSACT_TIMEOUT_MS));
+
+    // A reply without an end stops at the max length
+    let mut trans = Transaction::new(&sub_id, None, Some(b"\n".to_vec()), None, None);
+    let chunk = vec![b'a'; MAX_TRANSACT_REPLY_LENGTH / 2 + 1];
+    assert_eq!(trans.push(&chunk), None);
+    assert!(!trans.is_too_long());
+    assert_eq!(trans.push(&chunk), Some(vec![b'a'; 2]));
+    assert!(trans.is_too_long());
+    assert_eq!(trans.into_reply().len(), MAX_TRANSACT_REPLY_LENGTH);
+  }
+}
@@ -1,11 +1,74 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
 use crate::errors::*;
+use crate::messages::WritePolicy;
+
+/// A write lock held by a subscription
+#[derive(Clone, Debug)]
+struct WriteLock {
+  /// Sub id holding the lock
+  sub_id: String,
+  /// How long the lock lasts without being renewed,
+  /// None if it lasts till released
+  lease: Option<Duration>,
+  /// When the lease runs out
+  expires: Option<Instant>,
+  /// When the holder got the lock
+  since: Instant,
+}
+
+impl WriteLock {
+  /// Create a lock, starting its lease at the given time
+  fn new(sub_id: &String, lease: Option<Duration>, now: Instant) -> WriteLock {
+    WriteLock {
+      sub_id: sub_id.to_string(),
+      lease: lease,
+      expires: lease.map(|lease| now + lease),
+      since: now,
+    }
+  }
+
+  /// Has the lease run out
+  fn is_expired(&self, now: Instant) -> bool {
+    self.expires.map(|expires| expires <= now).unwrap_or(false)
+  }
+}
+
+/// A client waiting for a write lock
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockWaiter {
+  /// Sub id waiting
+  pub sub_id: String,
+  /// Lease to give the lock once granted
+  pub lease: Option<Duration>,
+  /// Id of the WriteLock request, attached
+  /// to the reply once the lock is granted
+  pub request_id: Option<String>,
+}
 
 /// Manages tracking of write locks
+///
+/// Locks can be given a lease, in which case they are
+/// released unless the holder renews them in time, so
+/// a client that stops responding can't hold a port forever
+///
+/// Clients can wait for a locked port, they are granted
+/// the lock in the order they asked for it
+///
+/// Ports with a shared write policy can also be written
+/// by clients without the lock, as long as nobody holds it
 pub struct WriteLockManager {
-  /// Map of port to subscription ids
-  write_locks: HashMap<String, String>,
+  /// Map of port to write locks
+  write_locks: HashMap<String, WriteLock>,
+  /// Map of port to clients waiting for its lock
+  waiters: HashMap<String, VecDeque<LockWaiter>>,
+  /// Ports whose lock changed hands since
+  /// changes were last taken
+  changed: HashSet<String>,
+  /// Map of port to write policy, ports
+  /// not in the map are exclusive
+  policies: HashMap<String, WritePolicy>,
 }
 
 impl WriteLockManager {
@@ -13,37 +76,117 @@ impl WriteLockManager {
   pub fn new() -> WriteLockManager {
     WriteLockManager {
       write_locks: HashMap::new(),
+      waiters: HashMap::new(),
+      changed: HashSet::new(),
+      policies: HashMap::new(),
+    }
+  }
+
+  /// Get the write policy of a port
+  pub fn policy(&self, port_name: &String) -> WritePolicy {
+    self.policies.get(port_name).cloned().unwrap_or_default()
+  }
+
+  /// Set the write policy of a port
+  pub fn set_policy(&mut self, port_name: &String, policy: WritePolicy) {
+    match policy {
+      WritePolicy::Exclusive => self.policies.remove(port_name),
+      _ => self.policies.insert(port_name.to_string(), policy),
+    };
+  }
+
+  /// Check if sub id may write to this port, either because
+  /// it holds the write lock, or because the port is shared
+  /// and nobody holds the lock
+  ///
+  /// A lock whose lease ran out counts as released, even
+  /// before it is expired
+  pub fn check_can_write(&self, port_name: &String, sub_id: &String) -> Result<()> {
+    self.check_can_write_at(port_name, sub_id, Instant::now())
+  }
+
+  /// Check if sub id may write to this port at the given time
+  fn check_can_write_at(&self, port_name: &String, sub_id: &String, now: Instant) -> Result<()> {
+    let shared = self.policy(port_name) != WritePolicy::Exclusive;
+    match shared && self.live_lock_at(port_name, now).is_none() {
+      true => Ok(()),
+      false => self.check_owns_write_lock_at(port_name, sub_id, now),
     }
   }
 
-  /// Is the port write locked by the given sub_id
+  /// Is the port write locked by the given sub_id,
+  /// with a lease that hasn't run out
   pub fn is_port_write_locked_by(&self, port_name: &String, sub_id: &String) -> bool {
-    match self.write_locks.get(port_name) {
+    match self.live_lock_at(port_name, Instant::now()) {
       None => false,
-      Some(sid) => sid == sub_id,
+      Some(lock) => lock.sub_id == *sub_id,
     }
   }
 
-  /// Is the port write locked at all
+  /// Is the port write locked at all, by a lock
+  /// with a lease that hasn't run out
   pub fn is_port_write_locked(&self, port_name: &String) -> bool {
-    self.write_locks.get(port_name).is_some()
+    self.live_lock_at(port_name, Instant::now()).is_some()
+  }
+
+  /// Get the lock on a port, unless its lease
+  /// ran out by the given time
+  fn live_lock_at(&self, port_name: &String, now: Instant) -> Option<&WriteLock> {
+    self
+      .write_locks
+      .get(port_name)
+      .filter(|lock| !lock.is_expired(now))
+  }
+
+  /// Get the sub id holding the write lock on a port, if any
+  pub fn lock_holder(&self, port_name: &String) -> Option<String> {
+    self
+      .write_locks
+      .get(port_name)
+      .map(|lock| lock.sub_id.clone())
+  }
+
+  /// How long the holder has had the lock on a port,
+  /// renewing doesn't reset it
+  pub fn lock_age(&self, port_name: &String) -> Option<Duration> {
+    self
+      .write_locks
+      .get(port_name)
+      .map(|lock| lock.since.elapsed())
+  }
+
+  /// Get the lease of the write lock on a port,
+  /// None if it isn't locked or the lock has no lease
+  pub fn lease(&self, port_name: &String) -> Option<Duration> {
+    self.write_locks.get(port_name).and_then(|lock| lock.lease)
   }
 
   /// Is the port locked by someone else
   pub fn is_port_locked_by_someone_else(&self, port_name: &String, sub_id: &String) -> bool {
     match self.write_locks.get(port_name) {
       None => false,
-      Some(sid) => sid != sub_id,
+      Some(lock) => lock.sub_id != *sub_id,
     }
   }
 
   /// Check if sub id has write lock on this port, if it doesn't,
-  /// return error
+  /// or its lease ran out, return error
   pub fn check_owns_write_lock(&self, port_name: &String, sub_id: &String) -> Result<()> {
-    match self.write_locks.get(port_name) {
+    self.check_owns_write_lock_at(port_name, sub_id, Instant::now())
+  }
+
+  /// Check if sub id has a live write lock on this port
+  /// at the given time
+  fn check_owns_write_lock_at(
+    &self,
+    port_name: &String,
+    sub_id: &String,
+    now: Instant,
+  ) -> Result<()> {
+    match self.live_lock_at(port_name, now) {
       None => Err(ErrorKind::NeedWriteLock(port_name.to_string()).into()),
-      Some(sid) => {
-        if sid != sub_id {
+      Some(lock) => {
+        if lock.sub_id != *sub_id {
           Err(ErrorKind::AlreadyWriteLocked(port_name.to_string()).into())
         } else {
           Ok(())
@@ -52,16 +195,22 @@ impl WriteLockManager {
     }
   }
 
-  /// Clear a write lock, without checking subscriber id
+  /// Clear a write lock, without checking subscriber id,
+  /// along with anyone waiting for it
   pub fn clear_lock(&mut self, port_name: &String) {
-    self.write_locks.remove(port_name);
+    self.remove_lock(port_name);
+    self.waiters.remove(port_name);
   }
 
-  /// Release the write lock for the given port and sub id
+  /// Release the write lock for the given port and sub id,
+  /// or stop waiting for it if the sub is waiting
   pub fn unlock_port(&mut self, port_name: &String, sub_id: &String) -> Result<()> {
+    if self.stop_waiting(port_name, sub_id) {
+      return Ok(());
+    }
     match self.is_port_locked_by_someone_else(port_name, sub_id) {
       false => {
-        self.write_locks.remove(port_name);
+        self.remove_lock(port_name);
         Ok(())
       }
       true => Err(ErrorKind::AlreadyWriteLocked(port_name.to_string()).into()),
@@ -72,40 +221,218 @@ impl WriteLockManager {
   pub fn unlock_port_if_locked_by(&mut self, port_name: &String, sub_id: &String) {
     if self.is_port_write_locked_by(port_name, sub_id) {
       // Should not panic since we are the one who locked it
-      self.unlock_port(&port_name, sub_id).unwrap();
+      self.unlock_port(port_name, sub_id).unwrap();
     }
   }
 
-  /// Release all write locks held by this sub_id
+  /// Release all write locks held by this sub_id,
+  /// and stop it waiting for any
   pub fn unlock_all_ports_for_sub(&mut self, sub_id: &String) {
-    let mut to_delete = Vec::<String>::new();
-    for port_name in self.write_locks.keys() {
-      if self
-        .write_locks
-        .get(port_name)
-        .map(|sid| sid == sub_id)
-        .unwrap_or(false)
-      {
-        to_delete.push(port_name.to_string());
-      }
+    let locked: Vec<String> = self
+      .write_locks
+      .iter()
+      .filter(|&(_, lock)| lock.sub_id == *sub_id)
+      .map(|(port_name, _)| port_name.to_string())
+      .collect();
+    for port_name in locked.iter() {
+      self.remove_lock(port_name);
     }
-    for delete_port in to_delete.iter() {
-      self.write_locks.remove(delete_port);
+    for queue in self.waiters.values_mut() {
+      queue.retain(|waiter| waiter.sub_id != *sub_id);
     }
+    self.waiters.retain(|_, queue| !queue.is_empty());
   }
 
   /// Try and lock the port
-  pub fn lock_port(&mut self, port_name: &String, sub_id: &String) -> Result<()> {
+  ///
+  /// If the sub already holds the lock, it is renewed
+  /// with the given lease
+  pub fn lock_port(
+    &mut self,
+    port_name: &String,
+    sub_id: &String,
+    lease: Option<Duration>,
+  ) -> Result<()> {
+    self.lock_port_at(port_name, sub_id, lease, Instant::now())
+  }
+
+  /// Try and lock the port, starting the lease at the given time
+  fn lock_port_at(
+    &mut self,
+    port_name: &String,
+    sub_id: &String,
+    lease: Option<Duration>,
+    now: Instant,
+  ) -> Result<()> {
     match self.is_port_locked_by_someone_else(port_name, sub_id) {
       false => {
-        self
-          .write_locks
-          .insert(port_name.to_string(), sub_id.to_string());
+        self.insert_lock(port_name, WriteLock::new(sub_id, lease, now));
         Ok(())
       }
       true => Err(ErrorKind::AlreadyWriteLocked(port_name.to_string()).into()),
     }
   }
+
+  /// Lock the port, even if someone else holds the lock
+  ///
+  /// Returns the sub id of the previous holder, if
+  /// the lock was taken from someone else
+  pub fn force_lock_port(
+    &mut self,
+    port_name: &String,
+    sub_id: &String,
+    lease: Option<Duration>,
+  ) -> Option<String> {
+    self
+      .insert_lock(port_name, WriteLock::new(sub_id, lease, Instant::now()))
+      .map(|previous| previous.sub_id)
+      .filter(|previous| previous != sub_id)
+  }
+
+  /// Wait for the lock on a port, returning the position
+  /// in the queue, starting at 1
+  ///
+  /// A sub already waiting keeps its place
+  pub fn wait_for_lock(&mut self, port_name: &String, waiter: LockWaiter) -> usize {
+    let queue = self
+      .waiters
+      .entry(port_name.to_string())
+      .or_default();
+    match queue.iter().position(|w| w.sub_id == waiter.sub_id) {
+      Some(pos) => {
+        queue[pos] = waiter;
+        pos + 1
+      }
+      None => {
+        queue.push_back(waiter);
+        queue.len()
+      }
+    }
+  }
+
+  /// Stop waiting for the lock on a port,
+  /// returning false if the sub wasn't waiting
+  pub fn stop_waiting(&mut self, port_name: &String, sub_id: &String) -> bool {
+    let (removed, empty) = match self.waiters.get_mut(port_name) {
+      None => return false,
+      Some(queue) => {
+        let before = queue.len();
+        queue.retain(|waiter| waiter.sub_id != *sub_id);
+        (queue.len() < before, queue.is_empty())
+      }
+    };
+    if empty {
+      self.waiters.remove(port_name);
+    }
+    removed
+  }
+
+  /// Sub ids waiting for the lock on a port, in order
+  pub fn waiters(&self, port_name: &String) -> Vec<String> {
+    self
+      .waiters
+      .get(port_name)
+      .map(|queue| queue.iter().map(|w| w.sub_id.clone()).collect())
+      .unwrap_or_default()
+  }
+
+  /// Give unlocked ports to the first client waiting
+  /// for them, returning the port names and new holders
+  pub fn grant_waiting_locks(&mut self) -> Vec<(String, LockWaiter)> {
+    let unlocked: Vec<String> = self
+      .waiters
+      .keys()
+      .filter(|port_name| !self.write_locks.contains_key(*port_name))
+      .cloned()
+      .collect();
+    let mut granted = Vec::new();
+    for port_name in unlocked {
+      let next = self
+        .waiters
+        .get_mut(&port_name)
+        .and_then(|queue| queue.pop_front());
+      if let Some(waiter) = next {
+        self.insert_lock(
+          &port_name,
+          WriteLock::new(&waiter.sub_id, waiter.lease, Instant::now()),
+        );
+        granted.push((port_name, waiter));
+      }
+    }
+    self.waiters.retain(|_, queue| !queue.is_empty());
+    granted
+  }
+
+  /// Release locks whose lease ran out, returning
+  /// the port names and sub ids that held them
+  pub fn expire_locks(&mut self) -> Vec<(String, String)> {
+    self.expire_locks_at(Instant::now())
+  }
+
+  /// Release locks whose lease ran out by the given time
+  fn expire_locks_at(&mut self, now: Instant) -> Vec<(String, String)> {
+    let expired: Vec<(String, String)> = self
+      .write_locks
+      .iter()
+      .filter(|&(_, lock)| lock.is_expired(now))
+      .map(|(port_name, lock)| (port_name.to_string(), lock.sub_id.clone()))
+      .collect();
+    for &(ref port_name, _) in expired.iter() {
+      self.remove_lock(port_name);
+    }
+    expired
+  }
+
+  /// Take the ports whose lock changed hands since
+  /// the last call, along with the sub id now holding
+  /// the lock, if any
+  pub fn take_changes(&mut self) -> Vec<(String, Option<String>)> {
+    let mut changed: Vec<String> = self.changed.drain().collect();
+    changed.sort();
+    changed
+      .into_iter()
+      .map(|port_name| {
+        let holder = self.lock_holder(&port_name);
+        (port_name, holder)
+      })
+      .collect()
+  }
+
+  /// Set the lock on a port, noting if it changed hands.
+  /// A holder renewing its lock keeps the time it got it
+  fn insert_lock(&mut self, port_name: &String, mut lock: WriteLock) -> Option<WriteLock> {
+    match self.write_locks.get(port_name) {
+      Some(previous) if previous.sub_id == lock.sub_id => lock.since = previous.since,
+      _ => {
+        self.changed.insert(port_name.to_string());
+      }
+    }
+    self.write_locks.insert(port_name.to_string(), lock)
+  }
+
+  /// Remove the lock on a port, noting the change
+  fn remove_lock(&mut self, port_name: &String) -> Option<WriteLock> {
+    let removed = self.write_locks.remove(port_name);
+    if removed.is_some() {
+      self.changed.insert(port_name.to_string());
+    }
+    removed
+  }
+
+  /// How long until the next lease runs out,
+  /// None if no locks have a lease
+  pub fn time_until_expiry(&self) -> Option<Duration> {
+    let now = Instant::now();
+    self
+      .write_locks
+      .values()
+      .filter_map(|lock| lock.expires)
+      .map(|expires| match expires > now {
+        true => expires - now,
+        false => Duration::from_millis(0),
+      })
+      .min()
+  }
 }
 
 #[cfg(test)]
@@ -121,16 +448,14 @@ mod tests {
       sub_ids: Vec<&String>,
     ) {
       for sub_id in sub_ids.iter() {
-        assert_eq!(
-          false,
-          wl_manager.is_port_write_locked_by(port, sub_id),
+        assert!(
+          !wl_manager.is_port_write_locked_by(port, sub_id),
           "Port '{}' should not be locked by '{}'",
           port,
           sub_id
         );
-        assert_eq!(
-          false,
-          wl_manager.is_port_locked_by_someone_else(port, sub_id),
+        assert!(
+          !wl_manager.is_port_locked_by_someone_else(port, sub_id),
           "Port '{}' should not be locked by someone else",
           port
         );
@@ -144,16 +469,14 @@ mod tests {
       sub_locker: &String,
       sub_ids: Vec<&String>,
     ) {
-      assert_eq!(
-        true,
+      assert!(
         wl_manager.is_port_write_locked_by(port, sub_locker),
         "Port '{}' should not be locked by '{}'",
         port,
         sub_locker
       );
       for sub_id in sub_ids.iter() {
-        assert_eq!(
-          true,
+        assert!(
           wl_manager.is_port_locked_by_someone_else(port, sub_id),
           "Port '{}' should not be locked by someone else",
           port
@@ -167,12 +490,11 @@ mod tests {
     let sub_id2: String = "SUB_ID3".to_owned();
     let port: String = "/dev/TTY_USB".to_owned();
     // Ports should not be locked
-    check_not_locked_by_anyone(&wl_manager, &port, vec![&sub_id1, &sub_id2, &sub_id3]);
+    check_not_locked_by_anyone(wl_manager, &port, vec![&sub_id1, &sub_id2, &sub_id3]);
     // sub_id1 locking a port should work
-    assert_eq!(
-      true,
+    assert!(
       wl_manager
-        .lock_port(&port, &sub_id1)
+        .lock_port(&port, &sub_id1, None)
         .map(|_| true)
         .unwrap_or(false),
       "Sub_id '{}' locking port '{}' should succeed",
@@ -180,12 +502,11 @@ mod tests {
       port
     );
     //Port should now be locked by sub_id1
-    check_locked_by_sub(&wl_manager, &port, &sub_id1, vec![&sub_id2, &sub_id3]);
+    check_locked_by_sub(wl_manager, &port, &sub_id1, vec![&sub_id2, &sub_id3]);
     // sub_id2 should fail locking port already locked
-    assert_eq!(
-      true,
+    assert!(
       wl_manager
-        .lock_port(&port, &sub_id2)
+        .lock_port(&port, &sub_id2, None)
         .map(|_| false)
         .unwrap_or(true),
       "Sub_id '{}' locking port '{}' should fail",
@@ -193,8 +514,7 @@ mod tests {
       port
     );
     // sub_id2 should fail unlocking port locked by sub_id1
-    assert_eq!(
-      true,
+    assert!(
       wl_manager
         .unlock_port(&port, &sub_id2)
         .map(|_| false)
@@ -204,8 +524,7 @@ mod tests {
       port
     );
     // sub_id1 should be able to unlock it
-    assert_eq!(
-      true,
+    assert!(
       wl_manager
         .unlock_port(&port, &sub_id1)
         .map(|_| true)
@@ -215,8 +534,175 @@ mod tests {
       port
     );
     // Ports should not be locked
-    check_not_locked_by_anyone(&wl_manager, &port, vec![&sub_id1, &sub_id2, &sub_id3]);
+    check_not_locked_by_anyone(wl_manager, &port, vec![&sub_id1, &sub_id2, &sub_id3]);
     // TODO: Finish testing all other methods
   }
 
+  #[test]
+  fn test_leases() {
+    let wl_manager = &mut WriteLockManager::new();
+    let sub_id1 = "SUB_ID1".to_string();
+    let sub_id2 = "SUB_ID2".to_string();
+    let port = "/dev/TTY_USB".to_string();
+    let lease = Duration::from_secs(10);
+    let renewed = Duration::from_secs(1);
+
+    assert_eq!(wl_manager.time_until_expiry(), None);
+    let now = Instant::now();
+    wl_manager
+      .lock_port_at(&port, &sub_id1, Some(lease), now)
+      .expect("Locking should succeed");
+    assert_eq!(wl_manager.lease(&port), Some(lease));
+    assert!(wl_manager.time_until_expiry().unwrap() <= lease);
+    assert!(wl_manager.expire_locks_at(now).is_empty());
+
+    // Renewing restarts the lease
+    wl_manager
+      .lock_port_at(&port, &sub_id1, Some(lease), now + renewed)
+      .expect("Renewing should succeed");
+    assert!(wl_manager.expire_locks_at(now + lease).is_empty());
+    assert!(wl_manager.is_port_write_locked_by(&port, &sub_id1));
+
+    // A lapsed holder can't write, even before its lock is expired
+    let lapsed = now + renewed + lease;
+    assert!(wl_manager
+      .check_can_write_at(&port, &sub_id1, now + lease)
+      .is_ok());
+    assert!(wl_manager
+      .check_can_write_at(&port, &sub_id1, lapsed)
+      .is_err());
+    assert!(wl_manager
+      .check_owns_write_lock_at(&port, &sub_id1, lapsed)
+      .is_err());
+
+    assert_eq!(
+      wl_manager.expire_locks_at(lapsed),
+      vec![(port.clone(), sub_id1.clone())]
+    );
+    assert!(!wl_manager.is_port_write_locked(&port));
+
+    // Forcing takes the lock from the holder
+    wl_manager
+      .lock_port(&port, &sub_id1, None)
+      .expect("Locking should succeed");
+    assert_eq!(
+      wl_manager.force_lock_port(&port, &sub_id2, None),
+      Some(sub_id1.clone())
+    );
+    assert!(wl_manager.is_port_write_locked_by(&port, &sub_id2));
+    assert_eq!(wl_manager.force_lock_port(&port, &sub_id2, None), None);
+  }
+
+  #[test]
+  fn test_wait_queue() {
+    let wl_manager = &mut WriteLockManager::new();
+    let sub_ids: Vec<String> = (1..5).map(|i| format!("SUB_ID{}", i)).collect();
+    let port = "/dev/TTY_USB".to_string();
+    let waiter = |sub_id: &String| LockWaiter {
+      sub_id: sub_id.clone(),
+      lease: None,
+      request_id: None,
+    };
+
+    wl_manager
+      .lock_port(&port, &sub_ids[0], None)
+      .expect("Locking should succeed");
+    assert_eq!(wl_manager.wait_for_lock(&port, waiter(&sub_ids[1])), 1);
+    assert_eq!(wl_manager.wait_for_lock(&port, waiter(&sub_ids[2])), 2);
+    assert_eq!(wl_manager.wait_for_lock(&port, waiter(&sub_ids[3])), 3);
+    // Waiting again keeps the place in line
+    assert_eq!(wl_manager.wait_for_lock(&port, waiter(&sub_ids[1])), 1);
+    assert!(wl_manager.grant_waiting_locks().is_empty());
+
+    // Releasing hands the lock to the first in line
+    wl_manager
+      .unlock_port(&port, &sub_ids[0])
+      .expect("Unlocking should succeed");
+    assert_eq!(
+      wl_manager.grant_waiting_locks(),
+      vec![(port.clone(), waiter(&sub_ids[1]))]
+    );
+    assert!(wl_manager.is_port_write_locked_by(&port, &sub_ids[1]));
+    assert_eq!(
+      wl_manager.waiters(&port),
+      vec![sub_ids[2].clone(), sub_ids[3].clone()]
+    );
+
+    // Waiters that go away lose their place
+    wl_manager
+      .unlock_port(&port, &sub_ids[2])
+      .expect("Cancelling a wait should succeed");
+    wl_manager.unlock_all_ports_for_sub(&sub_ids[1]);
+    assert_eq!(
+      wl_manager.grant_waiting_locks(),
+      vec![(port.clone(), waiter(&sub_ids[3]))]
+    );
+    assert!(wl_manager.waiters(&port).is_empty());
+  }
+
+  #[test]
+  fn test_changes() {
+    let wl_manager = &mut WriteLockManager::new();
+    let sub_id1 = "SUB_ID1".to_string();
+    let sub_id2 = "SUB_ID2".to_string();
+    let port = "/dev/TTY_USB".to_string();
+    let now = Instant::now();
+
+    wl_manager
+      .lock_port_at(&port, &sub_id1, None, now)
+      .expect("Locking should succeed");
+    assert_eq!(
+      wl_manager.take_changes(),
+      vec![(port.clone(), Some(sub_id1.clone()))]
+    );
+    assert!(wl_manager.take_changes().is_empty());
+
+    // Renewing is not a change, and keeps the lock age
+    let later = now + Duration::from_secs(1);
+    wl_manager
+      .lock_port_at(&port, &sub_id1, Some(Duration::from_secs(10)), later)
+      .expect("Renewing should succeed");
+    assert!(wl_manager.take_changes().is_empty());
+    assert_eq!(wl_manager.write_locks[&port].since, now);
+
+    wl_manager.force_lock_port(&port, &sub_id2, None);
+    wl_manager.unlock_all_ports_for_sub(&sub_id2);
+    assert_eq!(wl_manager.take_changes(), vec![(port.clone(), None)]);
+    assert_eq!(wl_manager.lock_age(&port), None);
+  }
+
+  #[test]
+  fn test_shared_policy() {
+    let wl_manager = &mut WriteLockManager::new();
+    let sub_id1 = "SUB_ID1".to_string();
+    let sub_id2 = "SUB_ID2".to_string();
+    let port = "/dev/TTY_USB".to_string();
+
+    assert_eq!(wl_manager.policy(&port), WritePolicy::Exclusive);
+    assert!(wl_manager.check_can_write(&port, &sub_id1).is_err());
+
+    wl_manager.set_policy(&port, WritePolicy::Shared);
+    assert!(wl_manager.check_can_write(&port, &sub_id1).is_ok());
+    assert!(wl_manager.check_can_write(&port, &sub_id2).is_ok());
+
+    // A lock holder still gets the port to itself
+    wl_manager
+      .lock_port(&port, &sub_id1, None)
+      .expect("Locking should succeed");
+    assert!(wl_manager.check_can_write(&port, &sub_id1).is_ok());
+    assert!(wl_manager.check_can_write(&port, &sub_id2).is_err());
+
+    // Once the lease runs out the port is shared again
+    let lease = Duration::from_secs(10);
+    let now = Instant::now();
+    wl_manager
+      .lock_port_at(&port, &sub_id1, Some(lease), now)
+      .expect("Renewing should succeed");
+    assert!(wl_manager
+      .check_can_write_at(&port, &sub_id2, now + lease)
+      .is_ok());
+
+    wl_manager.set_policy(&port, WritePolicy::Exclusive);
+    assert_eq!(wl_manager.policy(&port), WritePolicy::Exclusive);
+  }
 }
@@ -0,0 +1,486 @@
+//! Waits on a websocket client and the messages queued for
+//! it at the same time, so a handler thread sleeps until the
+//! client sends something or the manager has something for
+//! the client, instead of polling both
+
+use std::io::{BufReader, Chain, Cursor, Read};
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::TryRecvError;
+use std::time::{Duration, Instant};
+
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use native_tls::TlsStream;
+use websocket::receiver::Receiver;
+use websocket::sender::Sender;
+use websocket::stream::sync::{AsTcpStream, Stream};
+use websocket::sync::Client;
+use websocket::ws::{Receiver as WsReceiver, Sender as WsSender};
+use websocket::{Message, OwnedMessage};
+
+use crate::binary_frame::{encode_frame, READ_FRAME};
+use crate::coalesce::try_recv_batch;
+use crate::errors::*;
+use crate::messages::*;
+use crate::sub_queue::QueueReceiver;
+
+/// Max time a client may stall partway through a message
+pub const MESSAGE_TIMEOUT_MS: u64 = 10000;
+
+/// Poll token for the client's socket
+const SOCKET: Token = Token(0);
+/// Poll token for the client's queue
+const QUEUE: Token = Token(1);
+
+/// Streams that may hold data already taken off the
+/// socket, which poll can't tell us about
+pub trait BufferedStream {
+  /// Number of bytes readable without touching the socket
+  fn buffered_len(&self) -> usize;
+}
+
+impl BufferedStream for TcpStream {
+  fn buffered_len(&self) -> usize {
+    0
+  }
+}
+
+impl BufferedStream for TlsStream<TcpStream> {
+  /// Decrypted data not read yet
+  fn buffered_len(&self) -> usize {
+    self.buffered_read_size().unwrap_or(0)
+  }
+}
+
+/// What WsConnection::wait woke up for
+#[derive(Debug)]
+pub enum WsEvent {
+  /// The client sent a message
+  Message(OwnedMessage),
+  /// Messages were queued for the client,
+  /// or the last queue sender went away
+  Queued,
+  /// Nothing happened before the timeout
+  Timeout,
+}
+
+/// An accepted websocket client
+pub struct WsConnection<S> {
+  /// The stream, after any bytes the client had buffered
+  reader: BufReader<Chain<Cursor<Vec<u8>>, S>>,
+  /// Decodes messages from the client
+  receiver: Receiver,
+  /// Encodes messages to the client
+  sender: Sender,
+  /// Waits on the socket and the queue
+  poll: Poll,
+  /// Events from the last poll
+  events: Events,
+  /// The queue's registration with poll
+  registration: Registration,
+  /// Marks the queue ready, waking poll
+  queue_ready: SetReadiness,
+}
+
+impl<S> WsConnection<S>
+where
+  S: Stream + AsTcpStream + BufferedStream,
+{
+  /// Take over the stream of a client that was just accepted
+  pub fn new(client: Client<S>) -> Result<WsConnection<S>> {
+    let (stream, buffer) = client.into_stream();
+    let buffered = match buffer {
+      Some((buf, pos, cap)) => buf[pos..cap].to_vec(),
+      None => Vec::new(),
+    };
+
+    // Reads only happen once poll says there is data,
+    // a client stalling partway through a message is dropped
+    stream.as_tcp().set_nonblocking(false)?;
+    stream
+      .as_tcp()
+      .set_read_timeout(Some(Duration::from_millis(MESSAGE_TIMEOUT_MS)))?;
+
+    let poll = Poll::new()?;
+    poll.register(
+      &EventedFd(&stream.as_tcp().as_raw_fd()),
+      SOCKET,
+      Ready::readable(),
+      PollOpt::level(),
+    )?;
+    let (registration, queue_ready) = Registration::new2();
+    poll.register(&registration, QUEUE, Ready::readable(), PollOpt::level())?;
+
+    Ok(WsConnection {
+      reader: BufReader::new(Cursor::new(buffered).chain(stream)),
+      receiver: Receiver::new(true),
+      sender: Sender::new(false),
+      poll: poll,
+      events: Events::with_capacity(2),
+      registration: registration,
+      queue_ready: queue_ready,
+    })
+  }
+
+  /// Wake up wait when messages are queued for the client
+  pub fn watch_queue(&self, queue: &QueueReceiver) {
+    let queue_ready = self.queue_ready.clone();
+    queue.set_waker(move || {
+      queue_ready
+        .set_readiness(Ready::readable())
+        .unwrap_or_else(|e| warn!("Waking websocket handler failed, cause '{}'", e))
+    });
+  }
+
+  /// Wait for the client to send a message or for messages to
+  /// be queued, for up to timeout, or forever if it is None.
+  /// The socket is checked at least once, even for a zero timeout
+  ///
+  /// Client messages are reported first, so a queue that is
+  /// never empty can't hold them up. An error means the
+  /// client hung up, stalled or broke the protocol
+  pub fn wait(&mut self, timeout: Option<Duration>) -> Result<WsEvent> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+      // Poll only knows about data still on the socket
+      if self.has_buffered() {
+        return self.recv();
+      }
+
+      let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+      self.poll.poll(&mut self.events, remaining)?;
+
+      if self.events.iter().any(|event| event.token() == SOCKET) {
+        return self.recv();
+      }
+      if self.events.iter().any(|event| event.token() == QUEUE) {
+        self.queue_ready.set_readiness(Ready::empty())?;
+        return Ok(WsEvent::Queued);
+      }
+      if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Ok(WsEvent::Timeout);
+      }
+    }
+  }
+
+  /// Send a message to the client
+  pub fn send_message(&mut self, message: &Message) -> Result<()> {
+    let stream = self.reader.get_mut().get_mut().1;
+    self.sender.send_message(stream, message)?;
+    Ok(())
+  }
+
+  /// Send a batch of queued messages, with reads merged,
+  /// see coalesce::try_recv_batch
+  ///
+  /// Returns why any messages could not be sent, or
+  /// TryRecvError if nothing was queued
+  pub fn send_batch(
+    &mut self,
+    sub_id: &String,
+    queue: &QueueReceiver,
+  ) -> ::std::result::Result<Vec<Error>, TryRecvError> {
+    let mut errors = Vec::new();
+    for msg in try_recv_batch(queue)? {
+      if let Some(message) = to_ws_message(sub_id, msg) {
+        if let Err(e) = self.send_message(&message) {
+          errors.push(e);
+        }
+      }
+    }
+    Ok(errors)
+  }
+
+  /// Read a message, waiting for the rest of it if needed
+  fn recv(&mut self) -> Result<WsEvent> {
+    let message = self.receiver.recv_message(&mut self.reader)?;
+    Ok(WsEvent::Message(message))
+  }
+
+  /// Is data from the client waiting in a buffer
+  fn has_buffered(&self) -> bool {
+    let (leftover, stream) = self.reader.get_ref().get_ref();
+    !self.reader.buffer().is_empty()
+      || (leftover.position() as usize) < leftover.get_ref().len()
+      || stream.buffered_len() > 0
+  }
+}
+
+/// Convert a message from the manager into a websocket message,
+/// json text for responses, and binary frames for raw port data
+pub fn to_ws_message(sub_id: &String, msg: SubscriberMessage) -> Option<Message<'static>> {
+  match msg {
+    SubscriberMessage::Response(resp) => match serde_json::to_string(&resp) {
+      Ok(json) => Some(Message::text(json)),
+      Err(err) => {
+        warn!("{}: Serializing response failed, cause '{}'", sub_id, err);
+        None
+      }
+    },
+    SubscriberMessage::Data { port, data } => match encode_frame(READ_FRAME, &port, &data) {
+      Ok(frame) => Some(Message::binary(frame)),
+      Err(err) => {
+        warn!("{}: Encoding binary frame failed, cause '{}'", sub_id, err);
+        None
+      }
+    },
+    SubscriberMessage::Frame(msg) => to_ws_message(sub_id, *msg),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use std::io::Write;
+  use std::sync::mpsc::channel;
+  use std::thread;
+
+  use serialport::posix::TTYPort;
+  use serialport::SerialPort;
+  use websocket::sync::Server;
+  use websocket::ClientBuilder;
+
+  use crate::acl::AccessControl;
+  use crate::manager::Manager;
+  use crate::sub_queue::*;
+
+  /// A connection and the client on the other end
+  fn connect() -> (WsConnection<TcpStream>, Client<TcpStream>) {
+    let mut server = Server::bind("127.0.0.1:0").expect("Failed to bind server");
+    let addr = server.local_addr().expect("Failed to get server address");
+    let client = thread::spawn(move || {
+      ClientBuilder::new(&format!("ws://{}", addr))
+        .expect("Failed to build client")
+        .connect_insecure()
+        .expect("Failed to connect")
+    });
+    let upgrade = match server.accept() {
+      Ok(upgrade) => upgrade,
+      Err(_) => panic!("Failed to accept connection"),
+    };
+    let accepted = match upgrade.accept() {
+      Ok(accepted) => accepted,
+      Err((_, e)) => panic!("Failed to accept upgrade, cause '{}'", e),
+    };
+    let connection = WsConnection::new(accepted).expect("Failed to create connection");
+    (connection, client.join().expect("Client panicked"))
+  }
+
+  fn wait(connection: &mut WsConnection<TcpStream>) -> Result<WsEvent> {
+    connection.wait(Some(Duration::from_millis(500)))
+  }
+
+  fn assert_text(event: Result<WsEvent>, expected: &str) {
+    match event {
+      Ok(WsEvent::Message(OwnedMessage::Text(ref text))) if text == expected => {}
+      other => panic!("Expected text '{}', got {:?}", expected, other),
+    }
+  }
+
+  fn assert_timeout(event: Result<WsEvent>) {
+    match event {
+      Ok(WsEvent::Timeout) => {}
+      other => panic!("Expected timeout, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_client_messages() {
+    let (mut connection, mut client) = connect();
+    assert_timeout(connection.wait(Some(Duration::from_millis(10))));
+
+    // Two messages in one write, the second is
+    // already buffered when the first is read
+    let mut frames = Vec::new();
+    let mut sender = Sender::new(true);
+    sender.send_message(&mut frames, &Message::text("a")).unwrap();
+    sender.send_message(&mut frames, &Message::text("b")).unwrap();
+    client.writer_mut().write_all(&frames).unwrap();
+    assert_text(wait(&mut connection), "a");
+    assert_text(wait(&mut connection), "b");
+    assert_timeout(connection.wait(Some(Duration::from_millis(10))));
+
+    connection.send_message(&Message::text("c")).unwrap();
+    match client.recv_message() {
+      Ok(OwnedMessage::Text(ref text)) if text == "c" => {}
+      other => panic!("Expected text 'c', got {:?}", other),
+    }
+
+    drop(client);
+    assert!(wait(&mut connection).is_err(), "Client should be gone");
+  }
+
+  #[test]
+  fn test_queue_wakes() {
+    let (mut connection, _client) = connect();
+    let (tx, rx) = sub_queue(DEFAULT_QUEUE_LENGTH, OverflowPolicy::default());
+    connection.watch_queue(&rx);
+    assert_timeout(connection.wait(Some(Duration::from_millis(10))));
+
+    let notice = || {
+      SubscriberMessage::Response(
+        SerialResponse::Closed {
+          port: "/dev/ttyUSB0".to_string(),
+        }
+        .into(),
+      )
+    };
+    let sender = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      tx.send(notice()).unwrap();
+      tx
+    });
+    match wait(&mut connection) {
+      Ok(WsEvent::Queued) => {}
+      other => panic!("Expected queued, got {:?}", other),
+    }
+    let tx = sender.join().expect("Sender panicked");
+    assert_eq!(rx.drain(10), vec![notice()]);
+    assert_timeout(connection.wait(Some(Duration::from_millis(10))));
+
+    // Ending the subscription wakes too
+    drop(tx);
+    match wait(&mut connection) {
+      Ok(WsEvent::Queued) => {}
+      other => panic!("Expected queued, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_client_messages_first() {
+    let (mut connection, mut client) = connect();
+    let (tx, rx) = sub_queue(DEFAULT_QUEUE_LENGTH, OverflowPolicy::default());
+    connection.watch_queue(&rx);
+    let zero = Some(Duration::from_millis(0));
+
+    // The queue stays ready while the client sends
+    tx.send(SubscriberMessage::Response(SerialResponse::Ok { msg: String::new() }.into()))
+      .unwrap();
+    client.send_message(&Message::text("a")).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_text(connection.wait(zero), "a");
+    match connection.wait(zero) {
+      Ok(WsEvent::Queued) => {}
+      other => panic!("Expected queued, got {:?}", other),
+    }
+    assert_eq!(rx.drain(10).len(), 1);
+    assert_timeout(connection.wait(zero));
+  }
+
+  #[test]
+  fn test_reads_arrive_promptly() {
+    let (mut master, mut slave) = TTYPort::pair().expect("Failed to create pseudoterminal pair!");
+    slave
+      .set_exclusive(false)
+      .expect("Failed to set exclusive false");
+    let port = slave.port_name().expect("Failed to get slave pty name");
+
+    let (manager_tx, manager_rx) = channel::<ManagerEvent>();
+    Manager::spawn(manager_rx, manager_tx.clone(), AccessControl::default());
+
+    let sub_id = "SUB1".to_string();
+    // Big enough that nothing is dropped
+    let (sub_tx, sub_rx) = sub_queue(100000, OverflowPolicy::DropNewest);
+    manager_tx
+      .send(ManagerEvent::Subscribe(SubscriptionRequest {
+        sub_id: sub_id.clone(),
+        subscriber: sub_tx,
+        binary: false,
+        identity: None,
+      }))
+      .unwrap();
+    let open = SerialRequest::Open {
+      port: port.clone(),
+      settings: None,
+      sticky: None,
+      encoding: Some(Encoding::Latin1),
+      framing: None,
+      echo: None,
+      write_policy: None,
+    };
+    manager_tx
+      .send(ManagerEvent::Request(sub_id.clone(), open.into()))
+      .unwrap();
+    // Skip any port change notices
+    loop {
+      match sub_rx.recv_timeout(Duration::from_secs(1)) {
+        Ok(SubscriberMessage::Response(ResponseEnvelope {
+          response: SerialResponse::Opened { .. },
+          ..
+        })) => break,
+        Ok(_) => {}
+        Err(e) => panic!("Expected port to open, got {:?}", e),
+      }
+    }
+
+    // Send queued messages to the client the way ws_handler does
+    let (mut connection, mut client) = connect();
+    connection.watch_queue(&sub_rx);
+    let handler = thread::spawn(move || {
+      // Until the client hangs up
+      while connection.wait(None).is_ok() {
+        while let Ok(errors) = connection.send_batch(&sub_id, &sub_rx) {
+          assert!(errors.is_empty(), "Sending failed, cause {:?}", errors);
+        }
+      }
+    });
+
+    // Thousands of small writes
+    let chunk = b"0123456789abcdef";
+    let chunk_count = 5000;
+    let expected = chunk.len() * chunk_count;
+    let writer = thread::spawn(move || {
+      for _ in 0..chunk_count {
+        master.write_all(chunk).expect("Write to master failed!");
+      }
+      master
+    });
+
+    client
+      .stream_ref()
+      .set_read_timeout(Some(Duration::from_millis(100)))
+      .unwrap();
+    let start = Instant::now();
+    let mut received = String::new();
+    let mut message_count = 0;
+    while received.len() < expected && start.elapsed() < Duration::from_secs(5) {
+      let text = match client.recv_message() {
+        Ok(OwnedMessage::Text(text)) => text,
+        _ => continue,
+      };
+      if let Ok(ResponseEnvelope {
+        response: SerialResponse::Read { data, .. },
+        ..
+      }) = serde_json::from_str(&text)
+      {
+        message_count += 1;
+        received.push_str(&data);
+      }
+    }
+    let elapsed = start.elapsed();
+    let _master = writer.join().expect("Writer panicked");
+    drop(client);
+    handler.join().expect("Handler panicked");
+
+    assert_eq!(received.len(), expected, "All data should arrive");
+    assert!(
+      received
+        .as_bytes()
+        .chunks(chunk.len())
+        .all(|c| c == &chunk[..]),
+      "Data should arrive in order"
+    );
+    assert!(
+      elapsed < Duration::from_secs(2),
+      "Data took {:?} to arrive",
+      elapsed
+    );
+    assert!(
+      message_count < chunk_count,
+      "Reads should be merged, got {} messages",
+      message_count
+    );
+  }
+}